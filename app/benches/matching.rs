@@ -0,0 +1,56 @@
+//! Exact matching over 10k and 100k templates: building the template matrix
+//! (once per storage generation) and scoring a query against it.
+//!
+//! cargo bench --bench matching
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use face_auth::matching::TemplateMatrix;
+use face_auth::storage::vector_storage::EmbeddingRecord;
+use std::collections::HashMap;
+use std::hint::black_box;
+
+const DIM: usize = 768;
+const GALLERY_SIZES: [usize; 2] = [10_000, 100_000];
+
+// Deterministic pseudo-random embeddings, no rand dependency needed
+fn random_records(count: usize) -> Vec<EmbeddingRecord> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+    };
+    (0..count)
+        .map(|i| EmbeddingRecord {
+            id: format!("record{i}"),
+            name: format!("user{}", i / 4),
+            embedding: (0..DIM).map(|_| next()).collect(),
+            created_at: chrono::Utc::now(),
+            metadata: HashMap::new(),
+            model: None,
+        })
+        .collect()
+}
+
+fn matching(c: &mut Criterion) {
+    let mut group = c.benchmark_group("matching");
+    group.sample_size(10);
+    for count in GALLERY_SIZES {
+        let records = random_records(count);
+        let query = records[count / 2].embedding.clone();
+
+        group.bench_with_input(BenchmarkId::new("build", count), &records, |b, records| {
+            b.iter(|| TemplateMatrix::build(black_box(records), DIM).expect("templates build"))
+        });
+
+        let matrix = TemplateMatrix::build(&records, DIM).expect("templates build");
+        assert_eq!(matrix.record_id(matrix.ranked(&query).expect("query scores")[0].0), records[count / 2].id);
+        group.bench_with_input(BenchmarkId::new("score_and_rank", count), &query, |b, query| {
+            b.iter(|| matrix.ranked(black_box(query)).expect("query scores"))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, matching);
+criterion_main!(benches);
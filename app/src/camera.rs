@@ -1 +1,3 @@
-pub mod camera_interactions;
\ No newline at end of file
+pub mod camera_interactions;
+pub mod frame_source;
+pub mod mjpeg;
\ No newline at end of file
@@ -3,107 +3,293 @@ use anyhow::Result;
 use std::time::{Duration, Instant};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use reqwest::blocking::Client;
 use crate::config::*;
 use crate::image_utils::imagenet::{self, image_with_std_mean};
+use crate::image_utils::challenge::{self, Challenge};
+use crate::image_utils::frame_hash::{self, FrameHash};
+use crate::image_utils::face_detection::FaceBox;
+use crate::image_utils::liveness::{self, LivenessReport};
+use crate::image_utils::quality::{self, FrameQuality, QualityIssue};
 use crate::embeddings::utils::compute_embeddings;
-use candle_nn::Func;
+use crate::embeddings::utils::EmbeddingModel;
 
 use minifb::{Window, WindowOptions, Key};
-use std::io::Read;
-use image::{DynamicImage, ImageFormat};
+use image::DynamicImage;
+use super::frame_source::{FrameSource, FrameSourceType};
+
+/// Why a sampled frame was not used for an embedding.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameRejection {
+    NoFace,
+    LowQuality(QualityIssue),
+}
+
+impl std::fmt::Display for FrameRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameRejection::NoFace => write!(f, "no face detected"),
+            FrameRejection::LowQuality(issue) => write!(f, "{issue}"),
+        }
+    }
+}
+
+// How often the challenge looks for a new frame
+const CHALLENGE_POLL_MILLIS: u64 = 30;
+
+/// Returned when not enough frames could be sampled before the capture deadline.
+#[derive(Debug)]
+pub struct CaptureTimeout {
+    pub timeout: Duration,
+    pub samples_collected: usize,
+    pub samples_required: usize,
+    pub rejected_frames: usize,
+    pub last_rejection: Option<FrameRejection>,
+}
+
+impl std::fmt::Display for CaptureTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Capture timed out after {:.1}s with {}/{} samples collected",
+               self.timeout.as_secs_f32(), self.samples_collected, self.samples_required)?;
+        if let Some(rejection) = &self.last_rejection {
+            write!(f, " ({} frame(s) rejected, last: {rejection})", self.rejected_frames)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CaptureTimeout {}
+
+/// Returned when the requested liveness action wasn't seen before its deadline,
+/// or wasn't performed by the face that was then sampled.
+#[derive(Debug)]
+pub struct ChallengeFailed {
+    pub challenge: Challenge,
+    pub reason: ChallengeFailure,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChallengeFailure {
+    /// The action wasn't seen in time.
+    Timeout { timeout: Duration, frames_tracked: usize },
+    /// The face disappeared or jumped between the challenge and the samples.
+    TrackLost,
+    /// The face that performed the action doesn't match the sampled one.
+    DifferentFace { similarity: f32, min: f32 },
+}
+
+impl std::fmt::Display for ChallengeFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.reason {
+            ChallengeFailure::Timeout { timeout, frames_tracked } => {
+                write!(f, "Liveness challenge '{}' not completed within {:.1}s ({frames_tracked} frame(s) tracked)",
+                       self.challenge, timeout.as_secs_f32())
+            }
+            ChallengeFailure::TrackLost => {
+                write!(f, "Lost track of the face that performed the liveness challenge '{}'", self.challenge)
+            }
+            ChallengeFailure::DifferentFace { similarity, min } => {
+                write!(f, "The face that performed the liveness challenge '{}' doesn't match the sampled face (similarity {similarity:.4}, minimum {min:.4})",
+                       self.challenge)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChallengeFailed {}
+
+/// Counters and timings of one capture.
+#[derive(Debug, Clone, Default)]
+pub struct CaptureStats {
+    pub samples: usize,
+    pub rejected_frames: usize,
+    pub last_rejection: Option<FrameRejection>,
+    pub duration: Duration,
+    pub inference: Duration,
+    /// Passive liveness cues of the accepted samples, when the check is enabled.
+    pub liveness: Option<LivenessReport>,
+    /// The liveness challenge the user completed before sampling, if one was asked.
+    pub challenge: Option<Challenge>,
+}
+
+impl From<&CaptureTimeout> for CaptureStats {
+    fn from(timeout: &CaptureTimeout) -> Self {
+        CaptureStats {
+            samples: timeout.samples_collected,
+            rejected_frames: timeout.rejected_frames,
+            last_rejection: timeout.last_rejection.clone(),
+            duration: timeout.timeout,
+            inference: Duration::ZERO,
+            liveness: None,
+            challenge: None,
+        }
+    }
+}
+
+/// The embedded frames of a capture.
+pub struct Capture {
+    pub samples: Vec<CapturedSample>,
+    pub stats: CaptureStats,
+}
+
+/// One embedded frame of a capture.
+#[derive(Debug, Clone)]
+pub struct CapturedSample {
+    pub embedding: Vec<f32>,
+    pub captured_at: chrono::DateTime<chrono::Utc>,
+    pub quality: FrameQuality,
+    /// Hash of the face region of the decoded frame, when the replay guard is enabled.
+    pub frame_hash: Option<FrameHash>,
+}
+
+// The aligned faces that performed a challenge (where tracking started and
+// where the action was seen) and where the face was last seen
+struct ChallengeTrack {
+    challenge: Challenge,
+    faces: Vec<DynamicImage>,
+    last_face: FaceBox,
+}
+
+// Exponential reconnect delay, doubling from the initial value up to the maximum
+struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    fn new(initial: Duration, max: Duration) -> Self {
+        Backoff { initial, max, next: initial }
+    }
 
-pub fn capture_and_compute_average_embedding(model: &Func) -> Result<Vec<f32>> {
-    println!("[*] Starting camera capture for embedding computation from: {{get_stream_url()}}");
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+/// Captures `stream.num_images` frames and returns the embedding of each one.
+/// With a `challenge`, the user must first perform it in front of the camera.
+pub fn capture_samples(model: &EmbeddingModel, challenge: Option<Challenge>) -> Result<Capture> {
+    let source_type = get_frame_source_config()?;
+    println!("[*] Starting camera capture for embedding computation from: {source_type}");
 
     // Shared latest frame for display and sampling
     let latest_frame = Arc::new(Mutex::new(None::<Arc<DynamicImage>>));
     let latest_frame_clone_stream = Arc::clone(&latest_frame);
-    let latest_frame_clone_display = Arc::clone(&latest_frame);
 
     // Channels to signal when threads should shutdown
     let (shutdown_tx_stream, shutdown_rx_stream) = mpsc::channel::<()>();
-    let (shutdown_tx_display, shutdown_rx_display) = mpsc::channel::<()>();
 
-    // Stream reader thread - just updates the latest frame
+    // Stream reader thread - connects, then just updates the latest frame. A camera
+    // that is slow to come up is retried until the capture deadline shuts it down.
     let stream_handle = thread::spawn(move || {
-        if let Err(e) = stream_reader(latest_frame_clone_stream, shutdown_rx_stream) {
+        if let Err(e) = stream_reader(source_type, latest_frame_clone_stream, shutdown_rx_stream) {
             eprintln!("Stream reader error: {e}");
         }
     });
 
-    // Display thread - shows the latest frame and listens for shutdown signal
-    let display_handle = thread::spawn(move || {
-        if let Err(e) = display_processor(latest_frame_clone_display, shutdown_rx_display) {
-            eprintln!("Display processor error: {e}");
-        }
+    // Instructions for the user, shown as the preview window title
+    let prompt = Arc::new(Mutex::new(None::<String>));
+
+    // Display thread - shows the latest frame and listens for shutdown signal.
+    // Skipped entirely in headless mode, where there is no display to open.
+    let (shutdown_tx_display, display_handle) = if is_preview_enabled() {
+        let latest_frame_clone_display = Arc::clone(&latest_frame);
+        let prompt_clone_display = Arc::clone(&prompt);
+        let (shutdown_tx_display, shutdown_rx_display) = mpsc::channel::<()>();
+        let display_handle = thread::spawn(move || {
+            if let Err(e) = display_processor(latest_frame_clone_display, prompt_clone_display, shutdown_rx_display) {
+                eprintln!("Display processor error: {e}");
+            }
+        });
+        (Some(shutdown_tx_display), Some(display_handle))
+    } else {
+        println!("[*] Headless mode, preview window disabled");
+        (None, None)
+    };
+
+    // Main thread - runs the challenge, then samples frames for embedding computation
+    let embedding_result = challenge
+        .map(|challenge| run_challenge(model, challenge, &latest_frame, &prompt))
+        .transpose()
+        .and_then(|track| embedding_sampler_and_computer(model, latest_frame, track, &shutdown_tx_stream, shutdown_tx_display.as_ref()))
+    .map(|mut capture| {
+        capture.stats.challenge = challenge;
+        capture
     });
 
-    // Main thread - samples frames for embedding computation
-    let embedding_result = embedding_sampler_and_computer(model, latest_frame, shutdown_tx_stream, shutdown_tx_display);
+    // The sampler signals shutdown once sampling is done; signal again in case it
+    // returned early so the threads never outlive a failed capture
+    let _ = shutdown_tx_stream.send(());
+    if let Some(shutdown_tx_display) = &shutdown_tx_display {
+        let _ = shutdown_tx_display.send(());
+    }
 
     // Wait for threads to complete
     let _ = stream_handle.join();
-    let _ = display_handle.join();
+    if let Some(display_handle) = display_handle {
+        let _ = display_handle.join();
+    }
 
     embedding_result
 }
 
 fn stream_reader(
+    source_type: FrameSourceType,
     latest_frame: Arc<Mutex<Option<Arc<DynamicImage>>>>,
     shutdown_rx: mpsc::Receiver<()>
 ) -> Result<()> {
-    let client = Client::builder()
-        .timeout(Duration::from_secs(30))
-        .build()?;
-
-    let mut response = client.get(get_stream_url()).send()?;
-    let mut buffer = Vec::with_capacity(300_000);
-    let chunk_size = get_chunk_size();
-    let mut chunk_buffer = vec![0u8; chunk_size];
     let mut frame_count = 0;
+    let mut backoff = Backoff::new(
+        Duration::from_millis(get_reconnect_initial_millis()),
+        Duration::from_millis(get_reconnect_max_millis()),
+    );
 
     println!("Stream reader started...");
 
+    let Some(mut source) = connect(&source_type, get_chunk_size(), &mut backoff, &shutdown_rx) else {
+        println!("Stream reader finished");
+        return Ok(());
+    };
+    backoff.reset();
+
     loop {
         // Check for shutdown signal without blocking
         if shutdown_rx.try_recv().is_ok() {
             break;
         }
 
-        match response.read(&mut chunk_buffer) {
-            Ok(0) => {
-                println!("Stream ended");
-                break;
-            }
-            Ok(n) => {
-                buffer.extend_from_slice(&chunk_buffer[..n]);
-
-                // Process all available JPEG frames in buffer
-                while let Some(jpeg_data) = extract_next_jpeg(&mut buffer) {
-                    if let Ok(image) = decode_jpeg(&jpeg_data) {
-                        // Update the shared latest frame
-                        if let Ok(mut frame) = latest_frame.try_lock() {
-                            *frame = Some(Arc::new(image));
-                            frame_count += 1;
-
-                            if frame_count % 100 == 0 {
-                                println!("Processed {frame_count} frames");
-                            }
-                        }
-                        // If mutex is locked, just skip this frame - no big deal
+        match source.next_frame() {
+            Ok(Some(image)) => {
+                backoff.reset();
+
+                // Update the shared latest frame
+                if let Ok(mut frame) = latest_frame.try_lock() {
+                    *frame = Some(Arc::new(image));
+                    frame_count += 1;
+
+                    if frame_count % 100 == 0 {
+                        println!("Processed {frame_count} frames");
                     }
                 }
-
-                // Keep buffer size reasonable
-                if buffer.len() > 200_000 {
-                    // Avoid shrink/expand thrash; just clear and keep capacity
-                    buffer.clear();
+                // If mutex is locked, just skip this frame - no big deal
+            }
+            Ok(None) => {
+                println!("Stream ended");
+                if !reconnect(source.as_mut(), &mut backoff, &shutdown_rx) {
+                    break;
                 }
             }
             Err(e) => {
-                eprintln!("Stream read error: {e}");
-                thread::sleep(Duration::from_millis(100));
+                eprintln!("Stream error: {e:#}");
+                if !reconnect(source.as_mut(), &mut backoff, &shutdown_rx) {
+                    break;
+                }
             }
         }
     }
@@ -112,14 +298,142 @@ fn stream_reader(
     Ok(())
 }
 
+// Opens the source, retrying with exponential backoff until it succeeds. Returns
+// None if shutdown was requested while waiting.
+fn connect(
+    source_type: &FrameSourceType,
+    chunk_size: usize,
+    backoff: &mut Backoff,
+    shutdown_rx: &mpsc::Receiver<()>
+) -> Option<Box<dyn FrameSource>> {
+    loop {
+        match source_type.create_source(chunk_size) {
+            Ok(source) => return Some(source),
+            Err(e) => eprintln!("Connect failed: {e:#}"),
+        }
+
+        let delay = backoff.next_delay();
+        println!("Connecting again in {}ms...", delay.as_millis());
+        if !wait_for_retry(delay, shutdown_rx) {
+            return None;
+        }
+    }
+}
+
+// Retries the source's connection with exponential backoff. Returns false if the
+// source can't reconnect or shutdown was requested while waiting.
+fn reconnect(source: &mut dyn FrameSource, backoff: &mut Backoff, shutdown_rx: &mpsc::Receiver<()>) -> bool {
+    loop {
+        let delay = backoff.next_delay();
+        println!("Reconnecting in {}ms...", delay.as_millis());
+        if !wait_for_retry(delay, shutdown_rx) {
+            return false;
+        }
+
+        match source.reconnect() {
+            Ok(true) => {
+                println!("Stream reconnected");
+                return true;
+            }
+            Ok(false) => return false,
+            Err(e) => eprintln!("Reconnect failed: {e:#}"),
+        }
+    }
+}
+
+// Waits for the delay, waking up early on shutdown. Returns false on shutdown.
+fn wait_for_retry(delay: Duration, shutdown_rx: &mpsc::Receiver<()>) -> bool {
+    matches!(shutdown_rx.recv_timeout(delay), Err(mpsc::RecvTimeoutError::Timeout))
+}
+
+// Tracks the landmarks of every new frame until they show the challenge or it
+// times out. The action must be performed by one face tracked throughout; if it
+// is lost or jumps, the challenge starts over with the face now in view.
+fn run_challenge(
+    model: &EmbeddingModel,
+    challenge: Challenge,
+    latest_frame: &Mutex<Option<Arc<DynamicImage>>>,
+    prompt: &Mutex<Option<String>>,
+) -> Result<ChallengeTrack> {
+    let (Some(detector), Some(aligner)) = (model.face_detector(), model.face_aligner()) else {
+        anyhow::bail!("The liveness challenge needs face detection and alignment enabled");
+    };
+    let timeout = Duration::from_secs(get_challenge_timeout_secs());
+
+    println!("[?] Liveness challenge: {challenge}");
+    if let Ok(mut prompt) = prompt.lock() {
+        *prompt = Some(format!("Please {challenge}"));
+    }
+
+    let start_time = Instant::now();
+    let mut observations = Vec::new();
+    let mut first_face = None;
+    let mut tracked: Option<FaceBox> = None;
+    let mut last_frame: Option<Arc<DynamicImage>> = None;
+    let result = loop {
+        if start_time.elapsed() > timeout {
+            let reason = ChallengeFailure::Timeout { timeout, frames_tracked: observations.len() };
+            break Err(ChallengeFailed { challenge, reason }.into());
+        }
+        thread::sleep(Duration::from_millis(CHALLENGE_POLL_MILLIS));
+
+        // Only frames the stream hasn't shown us yet are tracked
+        let frame = match latest_frame.lock() {
+            Ok(frame_guard) => frame_guard.as_ref().map(Arc::clone),
+            Err(_) => None,
+        };
+        let Some(frame) = frame.filter(|frame| !last_frame.as_ref().is_some_and(|last| Arc::ptr_eq(last, frame))) else {
+            continue;
+        };
+        last_frame = Some(Arc::clone(&frame));
+
+        let face = detector.largest_face(&frame)?;
+        let continues = match (&tracked, &face) {
+            (Some(previous), Some(face)) => challenge::same_track(previous, face),
+            _ => false,
+        };
+        if !continues && !observations.is_empty() {
+            println!("[!] Lost track of the face, the challenge starts over");
+            observations.clear();
+        }
+        let Some(face) = face else {
+            tracked = None;
+            continue;
+        };
+        let landmarks = aligner.detect_landmarks(&frame, &face)?;
+        if observations.is_empty() {
+            first_face = Some(aligner.align(&frame, &landmarks)?);
+        }
+        observations.push(challenge::observe(&frame.to_luma8(), &landmarks));
+
+        if challenge::completed(challenge, &observations) {
+            println!("[+] Liveness challenge completed in {:.2}s ({} frame(s) tracked)",
+                     start_time.elapsed().as_secs_f32(), observations.len());
+            let faces = first_face.take().into_iter().chain([aligner.align(&frame, &landmarks)?]).collect();
+            break Ok(ChallengeTrack { challenge, faces, last_face: face });
+        }
+        tracked = Some(face);
+    };
+
+    if let Ok(mut prompt) = prompt.lock() {
+        *prompt = None;
+    }
+    result
+}
+
 fn embedding_sampler_and_computer(
-    model: &Func,
+    model: &EmbeddingModel,
     latest_frame: Arc<Mutex<Option<Arc<DynamicImage>>>>,
-    shutdown_tx_stream: mpsc::Sender<()>,
-    shutdown_tx_display: mpsc::Sender<()>
-) -> Result<Vec<f32>> {
+    challenge_track: Option<ChallengeTrack>,
+    shutdown_tx_stream: &mpsc::Sender<()>,
+    shutdown_tx_display: Option<&mpsc::Sender<()>>
+) -> Result<Capture> {
     let mut sample_count = 0;
     let start_time = Instant::now();
+    let capture_timeout = Duration::from_secs(get_capture_timeout_secs());
+    let quality_thresholds = get_quality_thresholds();
+    let check_liveness = get_liveness_threshold().is_some();
+    let hash_frames = is_replay_guard_enabled();
     let mut processing_time_total = Duration::default();
 
     println!("Embedding sampler started - will process {} samples with {}ms intervals",
@@ -127,9 +441,29 @@ fn embedding_sampler_and_computer(
 
     let mut collected_frames: Vec<Arc<DynamicImage>> = Vec::new();
     let mut processed_frames = Vec::new();
+    let mut frame_quality = Vec::new();
+    let mut captured_at = Vec::new();
+    let mut liveness_regions = Vec::new();
+    let mut frame_hashes = Vec::new();
+    let mut last_sampled: Option<Arc<DynamicImage>> = None;
+    // The face that performed the challenge must stay in view until sampled
+    let mut tracked = challenge_track.as_ref().map(|track| track.last_face.clone());
+    let track_lost = |track: &ChallengeTrack| ChallengeFailed { challenge: track.challenge, reason: ChallengeFailure::TrackLost };
+    let mut rejected_frames = 0;
+    let mut last_rejection = None;
 
     // Collect all frames first
     while sample_count < get_num_images() {
+        if start_time.elapsed() > capture_timeout {
+            return Err(CaptureTimeout {
+                timeout: capture_timeout,
+                samples_collected: sample_count,
+                samples_required: get_num_images(),
+                rejected_frames,
+                last_rejection,
+            }.into());
+        }
+
         // Wait for the sampling interval
         thread::sleep(Duration::from_millis(get_interval_millis()));
 
@@ -151,17 +485,73 @@ fn embedding_sampler_and_computer(
                 }
             }
         };
+        // Wait for the stream to deliver a new frame rather than sampling one twice
+        if last_sampled.as_ref().is_some_and(|last| Arc::ptr_eq(last, &frame_to_process)) {
+            continue;
+        }
+        last_sampled = Some(Arc::clone(&frame_to_process));
 
         let processing_start = Instant::now();
+        let sampled_at = chrono::Utc::now();
 
         println!("[*] Collecting sample {} (elapsed: {:.2}s)",
                  sample_count + 1, start_time.elapsed().as_secs_f32());
 
+        // Only the largest face is embedded when a detector is configured
+        let face = match model.face_detector() {
+            Some(detector) => match detector.largest_face(&frame_to_process)? {
+                Some(face) => {
+                    println!("[*] Face detected at ({:.0}, {:.0}) {:.0}x{:.0}, score {:.2}",
+                             face.x1, face.y1, face.width(), face.height(), face.score);
+                    Some(face)
+                }
+                None => {
+                    if let Some(track) = &challenge_track {
+                        return Err(track_lost(track).into());
+                    }
+                    reject_frame(FrameRejection::NoFace, &mut rejected_frames, &mut last_rejection);
+                    continue;
+                }
+            },
+            None => None,
+        };
+        if let (Some(previous), Some(face), Some(track)) = (&tracked, &face, &challenge_track) {
+            if !challenge::same_track(previous, face) {
+                return Err(track_lost(track).into());
+            }
+            tracked = Some(face.clone());
+        }
+
+        let quality = quality::assess(&frame_to_process, face.as_ref());
+        if let Some(thresholds) = &quality_thresholds
+            && let Some(issue) = thresholds.check(&quality)
+        {
+            reject_frame(FrameRejection::LowQuality(issue), &mut rejected_frames, &mut last_rejection);
+            continue;
+        }
+        // Liveness looks at the raw face region at the camera's resolution, as
+        // any resampling blurs away the screen patterns it looks for
+        if check_liveness {
+            liveness_regions.push(quality::face_region(&frame_to_process, face.as_ref()));
+        }
+        frame_hashes.push(hash_frames.then(|| frame_hash::hash_face(&frame_to_process, face.as_ref())));
+
+        let frame_to_process = match (model.face_detector(), &face) {
+            (Some(detector), Some(face)) => match model.face_aligner() {
+                Some(aligner) => {
+                    let (_, aligned) = aligner.align_face(&frame_to_process, face)?;
+                    Arc::new(aligned)
+                }
+                None => Arc::new(detector.crop(&frame_to_process, face)),
+            },
+            _ => frame_to_process,
+        };
+
         // Process frame for embedding computation
         
         let processed_frame =image_with_std_mean(
             &frame_to_process,
-            224,
+            imagenet::IMAGE_SIZE,
             &imagenet::IMAGENET_MEAN,
             &imagenet::IMAGENET_STD
         )?;
@@ -169,6 +559,8 @@ fn embedding_sampler_and_computer(
         // Store the processed frame and original frame
         processed_frames.push(processed_frame);
         collected_frames.push(frame_to_process);
+        frame_quality.push(quality);
+        captured_at.push(sampled_at);
         
         let processing_time = processing_start.elapsed();
         processing_time_total += processing_time;
@@ -181,7 +573,16 @@ fn embedding_sampler_and_computer(
 
     // Signal both threads to shutdown after sampling is complete
     let _ = shutdown_tx_stream.send(());
-    let _ = shutdown_tx_display.send(());
+    if let Some(shutdown_tx_display) = shutdown_tx_display {
+        let _ = shutdown_tx_display.send(());
+    }
+
+    // The challenge faces go into the same batch, after the samples
+    if let Some(track) = &challenge_track {
+        for face in &track.faces {
+            processed_frames.push(image_with_std_mean(face, imagenet::IMAGE_SIZE, &imagenet::IMAGENET_MEAN, &imagenet::IMAGENET_STD)?);
+        }
+    }
 
     // Now run inference once for all collected frames
     println!("[*] Running batch inference for {} samples (elapsed: {:.2}s)",
@@ -210,44 +611,108 @@ fn embedding_sampler_and_computer(
              inference_time.as_secs_f32() / embeddings.len() as f32);
 
 
-    // Compute and return the average embedding
-    if !embeddings.is_empty() {
-        println!("[*] Computing average embedding from {{embeddings.len()}} samples");
-        
-        let embedding_length = embeddings[0].len();
-        let mut avg_embedding = vec![0.0f32; embedding_length];
-        
-        // Sum all embeddings
-        for embedding in &embeddings {
-            for (i, &value) in embedding.iter().enumerate() {
-                avg_embedding[i] += value;
-            }
+    let challenge_embeddings = embeddings.split_off(sample_count.min(embeddings.len()));
+    if embeddings.is_empty() {
+        anyhow::bail!("No embeddings were generated");
+    }
+    if let Some(track) = &challenge_track {
+        check_challenge_faces(track.challenge, &challenge_embeddings, &embeddings, get_challenge_min_similarity())?;
+    }
+
+    let total_time = start_time.elapsed();
+    let avg_processing_time = processing_time_total.as_secs_f32() / sample_count as f32;
+    println!("Embedding sampler completed {} samples in {:.2}s (avg processing: {:.3}s per sample, inference: {:.3}s)",
+            sample_count, total_time.as_secs_f32(), avg_processing_time, inference_time.as_secs_f32());
+
+    let samples = embeddings
+        .into_iter()
+        .zip(captured_at)
+        .zip(frame_quality)
+        .zip(frame_hashes)
+        .map(|(((embedding, captured_at), quality), frame_hash)| CapturedSample { embedding, captured_at, quality, frame_hash })
+        .collect();
+    let stats = CaptureStats {
+        samples: sample_count,
+        rejected_frames,
+        last_rejection,
+        duration: total_time,
+        inference: inference_time,
+        liveness: check_liveness.then(|| liveness::assess(&liveness_regions)),
+        challenge: None,
+    };
+    Ok(Capture { samples, stats })
+}
+
+/// Element-wise mean of the sample embeddings.
+pub fn average_embedding(samples: &[CapturedSample]) -> Result<Vec<f32>> {
+    let Some(first) = samples.first() else {
+        anyhow::bail!("No embeddings were generated");
+    };
+
+    let mut avg_embedding = vec![0.0f32; first.embedding.len()];
+
+    // Sum all embeddings
+    for sample in samples {
+        for (i, &value) in sample.embedding.iter().enumerate() {
+            avg_embedding[i] += value;
         }
-        
-        // Divide by number of embeddings to get average
-        for value in &mut avg_embedding {
-            *value /= embeddings.len() as f32;
+    }
+
+    // Divide by number of embeddings to get average
+    for value in &mut avg_embedding {
+        *value /= samples.len() as f32;
+    }
+
+    Ok(avg_embedding)
+}
+
+// Fails unless every face that performed the challenge matches the average of
+// the sampled ones, so the challenge can't be passed by one person and the
+// samples taken of another, e.g. a photo swapped in afterwards
+fn check_challenge_faces(challenge: Challenge, challenge_embeddings: &[Vec<f32>], sample_embeddings: &[Vec<f32>], min: f32) -> Result<()> {
+    let dim = sample_embeddings.first().map_or(0, Vec::len);
+    let mut sampled = vec![0f32; dim];
+    for embedding in sample_embeddings {
+        for (sum, value) in sampled.iter_mut().zip(embedding) {
+            *sum += value;
         }
-        
-        let total_time = start_time.elapsed();
-        let avg_processing_time = processing_time_total.as_secs_f32() / sample_count as f32;
-        println!("Embedding sampler completed {} samples in {:.2}s (avg processing: {:.3}s per sample, inference: {:.3}s)",
-                sample_count, total_time.as_secs_f32(), avg_processing_time, inference_time.as_secs_f32());
+    }
 
-        Ok(avg_embedding)
-    } else {
-        Err(anyhow::anyhow!("No embeddings were generated"))
+    let similarity = challenge_embeddings
+        .iter()
+        .map(|embedding| cosine_similarity(embedding, &sampled))
+        .fold(f32::INFINITY, f32::min);
+    if challenge_embeddings.is_empty() || similarity < min {
+        let similarity = if similarity.is_finite() { similarity } else { 0. };
+        return Err(ChallengeFailed { challenge, reason: ChallengeFailure::DifferentFace { similarity, min } }.into());
     }
+    println!("[*] Challenge face matches the samples with similarity: {similarity:.4}");
+    Ok(())
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    dot / (norm(a) * norm(b)).max(f32::EPSILON)
+}
+
+fn reject_frame(rejection: FrameRejection, rejected_frames: &mut usize, last_rejection: &mut Option<FrameRejection>) {
+    println!("[!] Frame rejected: {rejection}");
+    *rejected_frames += 1;
+    *last_rejection = Some(rejection);
 }
 
 fn display_processor(
     latest_frame: Arc<Mutex<Option<Arc<DynamicImage>>>>,
+    prompt: Arc<Mutex<Option<String>>>,
     shutdown_rx: mpsc::Receiver<()>
 ) -> Result<()> {
     const WIDTH: usize = 640;
     const HEIGHT: usize = 480;
+    const TITLE: &str = "Live Stream";
 
-    let mut window = Window::new("Live Stream", WIDTH, HEIGHT, WindowOptions::default())?;
+    let mut window = Window::new(TITLE, WIDTH, HEIGHT, WindowOptions::default())?;
+    let mut shown_prompt: Option<String> = None;
     window.set_target_fps(30);
 
     println!("Display window opened. Press ESC to exit or wait for processing to complete.");
@@ -261,6 +726,14 @@ fn display_processor(
             break;
         }
 
+        // Show the current prompt, if any, in the title bar
+        if let Ok(prompt) = prompt.try_lock()
+            && *prompt != shown_prompt
+        {
+            window.set_title(prompt.as_deref().unwrap_or(TITLE));
+            shown_prompt = prompt.clone();
+        }
+
         // Get the latest frame
         let current_frame: Option<Arc<DynamicImage>> = {
             match latest_frame.try_lock() {
@@ -298,54 +771,75 @@ fn display_processor(
     Ok(())
 }
 
-// JPEG extraction functions
-fn extract_next_jpeg(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
-    let start_pos = find_jpeg_start(buffer)?;
-    let end_pos = find_jpeg_end(buffer, start_pos)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if end_pos < start_pos || end_pos >= buffer.len() {
-        return None;
-    }
+    #[test]
+    fn backoff_doubles_up_to_the_maximum_and_resets() {
+        let mut backoff = Backoff::new(Duration::from_millis(250), Duration::from_millis(1000));
 
-    let jpeg_data = buffer[start_pos..=end_pos].to_vec();
-    buffer.drain(..=end_pos);
-    Some(jpeg_data)
-}
+        let delays: Vec<u128> = (0..5).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, vec![250, 500, 1000, 1000, 1000]);
 
-fn find_jpeg_start(buffer: &[u8]) -> Option<usize> {
-    // Manual byte scan to avoid extra overhead from iterator/window machinery
-    let len = buffer.len();
-    let mut i = 0;
-    while i + 1 < len {
-        if buffer[i] == 0xFF && buffer[i + 1] == 0xD8 {
-            return Some(i);
-        }
-        i += 1;
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(250));
     }
-    None
-}
 
-fn find_jpeg_end(buffer: &[u8], start_pos: usize) -> Option<usize> {
-    if start_pos + 2 >= buffer.len() {
-        return None;
+    #[test]
+    fn connecting_retries_a_source_that_comes_up_late() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("face_auth_late_{}.png", uuid::Uuid::new_v4()));
+        let source_type = FrameSourceType::ImageFile(path.display().to_string());
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
+
+        let writer = {
+            let path = path.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                image::RgbImage::new(8, 8).save(&path)
+            })
+        };
+        let mut backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(20));
+        let connected = connect(&source_type, 0, &mut backoff, &shutdown_rx);
+        writer.join().expect("writer thread panicked")?;
+        std::fs::remove_file(&path)?;
+        assert!(connected.is_some());
+
+        // Shutdown stops the retries of a source that never comes up
+        shutdown_tx.send(())?;
+        assert!(connect(&source_type, 0, &mut backoff, &shutdown_rx).is_none());
+        Ok(())
     }
 
-    let len = buffer.len();
-    let mut i = start_pos + 2;
-    while i + 1 < len {
-        if buffer[i] == 0xFF && buffer[i + 1] == 0xD9 {
-            return Some(i + 1);
-        }
-        i += 1;
-    }
-    None
-}
+    #[test]
+    fn average_embedding_is_the_element_wise_mean() -> Result<()> {
+        let sample = |embedding: Vec<f32>| CapturedSample {
+            embedding,
+            captured_at: chrono::Utc::now(),
+            quality: FrameQuality { sharpness: 100., brightness: 128., contrast: 40., face_size: None, face_offset: None },
+            frame_hash: None,
+        };
 
-fn decode_jpeg(jpeg_data: &[u8]) -> Result<DynamicImage> {
-    if jpeg_data.len() < 10 {
-        return Err(anyhow::anyhow!("JPEG data too small"));
+        let average = average_embedding(&[sample(vec![1., 2., 3.]), sample(vec![3., 4., 5.])])?;
+        assert_eq!(average, vec![2., 3., 4.]);
+        assert!(average_embedding(&[]).is_err());
+        Ok(())
     }
 
-    Ok(image::load_from_memory_with_format(jpeg_data, ImageFormat::Jpeg)?)
+    #[test]
+    fn the_challenge_must_be_performed_by_the_sampled_face() {
+        let samples = vec![vec![1., 0.1, 0.], vec![0.9, 0., 0.1]];
+        // The same face, turned away for the action
+        let same = vec![vec![1., 0., 0.], vec![0.8, 0.3, 0.]];
+        assert!(check_challenge_faces(Challenge::TurnHeadLeft, &same, &samples, 0.5).is_ok());
+
+        // Someone else performed the action, then a photo of the user was sampled
+        let other = vec![vec![0.9, 0.1, 0.], vec![0., 1., 0.2]];
+        let error = check_challenge_faces(Challenge::TurnHeadLeft, &other, &samples, 0.5).expect_err("different face");
+        let failed = error.downcast_ref::<ChallengeFailed>().expect("challenge failure");
+        assert!(matches!(failed.reason, ChallengeFailure::DifferentFace { similarity, .. } if similarity < 0.5));
+
+        // No challenge faces can't vouch for anything
+        assert!(check_challenge_faces(Challenge::BlinkTwice, &[], &samples, 0.5).is_err());
+    }
 }
-
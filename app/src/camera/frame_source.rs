@@ -0,0 +1,261 @@
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageFormat};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::CONTENT_TYPE;
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use super::mjpeg::{split_jpeg_frames, StreamParser};
+
+// File backends replay their frames at roughly the rate of a 30 fps camera
+const PLAYBACK_INTERVAL: Duration = Duration::from_millis(33);
+
+/// A source of decoded camera frames.
+pub trait FrameSource: Send {
+    /// Returns the next frame, or `None` once the source has no more frames.
+    fn next_frame(&mut self) -> Result<Option<DynamicImage>>;
+
+    /// Re-establishes a dropped connection. Returns `false` for sources that
+    /// can't reconnect.
+    fn reconnect(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+pub enum FrameSourceType {
+    Http(String),
+    Directory(String),
+    ImageFile(String),
+    MjpegFile(String),
+}
+
+impl FrameSourceType {
+    pub fn create_source(&self, chunk_size: usize) -> Result<Box<dyn FrameSource>> {
+        match self {
+            FrameSourceType::Http(url) => Ok(Box::new(HttpMjpegSource::connect(url, chunk_size)?)),
+            FrameSourceType::Directory(path) => Ok(Box::new(DirectorySource::open(path)?)),
+            FrameSourceType::ImageFile(path) => Ok(Box::new(ImageFileSource::open(path)?)),
+            FrameSourceType::MjpegFile(path) => Ok(Box::new(MjpegFileSource::open(path)?)),
+        }
+    }
+}
+
+impl fmt::Display for FrameSourceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameSourceType::Http(url) => write!(f, "HTTP stream {url}"),
+            FrameSourceType::Directory(path) => write!(f, "image directory {path}"),
+            FrameSourceType::ImageFile(path) => write!(f, "image file {path}"),
+            FrameSourceType::MjpegFile(path) => write!(f, "MJPEG recording {path}"),
+        }
+    }
+}
+
+/// Live MJPEG stream served over HTTP, e.g. by the camera server.
+pub struct HttpMjpegSource {
+    url: String,
+    client: Client,
+    response: Response,
+    parser: StreamParser,
+    chunk_buffer: Vec<u8>,
+}
+
+impl HttpMjpegSource {
+    pub fn connect(url: &str, chunk_size: usize) -> Result<Self> {
+        let client = Client::builder()
+            .connect_timeout(Duration::from_secs(5))
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        let (response, parser) = open_stream(&client, url)?;
+
+        Ok(HttpMjpegSource {
+            url: url.to_string(),
+            client,
+            response,
+            parser,
+            chunk_buffer: vec![0u8; chunk_size],
+        })
+    }
+}
+
+fn open_stream(client: &Client, url: &str) -> Result<(Response, StreamParser)> {
+    let response = client.get(url).send()
+        .and_then(Response::error_for_status)
+        .with_context(|| format!("Failed to connect to stream {url}"))?;
+
+    // The multipart boundary comes from the response headers
+    let content_type = response.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    let parser = StreamParser::for_content_type(content_type);
+    Ok((response, parser))
+}
+
+impl FrameSource for HttpMjpegSource {
+    fn next_frame(&mut self) -> Result<Option<DynamicImage>> {
+        loop {
+            // Serve frames already in the buffer before reading more
+            while let Some(jpeg_data) = self.parser.next_frame()? {
+                if let Ok(image) = decode_jpeg(&jpeg_data) {
+                    return Ok(Some(image));
+                }
+            }
+
+            match self.response.read(&mut self.chunk_buffer) {
+                Ok(0) => return Ok(None),
+                Ok(n) => self.parser.push(&self.chunk_buffer[..n]),
+                Err(e) => return Err(anyhow::Error::new(e).context("Stream read error")),
+            }
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<bool> {
+        let (response, parser) = open_stream(&self.client, &self.url)?;
+        self.response = response;
+        self.parser = parser;
+        Ok(true)
+    }
+}
+
+/// Still images in a directory, replayed in file name order and looped.
+pub struct DirectorySource {
+    paths: Vec<PathBuf>,
+    next_index: usize,
+}
+
+impl DirectorySource {
+    pub fn open(path: &str) -> Result<Self> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(path)
+            .with_context(|| format!("Failed to read image directory '{path}'"))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && ImageFormat::from_path(path).is_ok())
+            .collect();
+        paths.sort();
+
+        if paths.is_empty() {
+            anyhow::bail!("No images found in directory '{path}'");
+        }
+
+        Ok(DirectorySource { paths, next_index: 0 })
+    }
+}
+
+impl FrameSource for DirectorySource {
+    fn next_frame(&mut self) -> Result<Option<DynamicImage>> {
+        thread::sleep(PLAYBACK_INTERVAL);
+        let path = &self.paths[self.next_index];
+        self.next_index = (self.next_index + 1) % self.paths.len();
+
+        let image = image::open(path)
+            .with_context(|| format!("Failed to decode image '{}'", path.display()))?;
+        Ok(Some(image))
+    }
+}
+
+/// A single still image, served as an endless stream of identical frames.
+pub struct ImageFileSource {
+    image: DynamicImage,
+}
+
+impl ImageFileSource {
+    pub fn open(path: &str) -> Result<Self> {
+        let image = image::open(path)
+            .with_context(|| format!("Failed to decode image '{path}'"))?;
+        Ok(ImageFileSource { image })
+    }
+}
+
+impl FrameSource for ImageFileSource {
+    fn next_frame(&mut self) -> Result<Option<DynamicImage>> {
+        thread::sleep(PLAYBACK_INTERVAL);
+        Ok(Some(self.image.clone()))
+    }
+}
+
+/// A recording made of concatenated JPEG frames, looped.
+pub struct MjpegFileSource {
+    frames: Vec<Vec<u8>>,
+    next_index: usize,
+}
+
+impl MjpegFileSource {
+    pub fn open(path: &str) -> Result<Self> {
+        let data = fs::read(path)
+            .with_context(|| format!("Failed to read MJPEG recording '{path}'"))?;
+
+        let frames = split_jpeg_frames(&data);
+
+        if frames.is_empty() {
+            anyhow::bail!("No JPEG frames found in '{path}'");
+        }
+
+        Ok(MjpegFileSource { frames, next_index: 0 })
+    }
+}
+
+impl FrameSource for MjpegFileSource {
+    fn next_frame(&mut self) -> Result<Option<DynamicImage>> {
+        thread::sleep(PLAYBACK_INTERVAL);
+        let jpeg_data = &self.frames[self.next_index];
+        let frame_index = self.next_index;
+        self.next_index = (self.next_index + 1) % self.frames.len();
+
+        let image = decode_jpeg(jpeg_data)
+            .with_context(|| format!("Failed to decode frame {frame_index} of MJPEG recording"))?;
+        Ok(Some(image))
+    }
+}
+
+fn decode_jpeg(jpeg_data: &[u8]) -> Result<DynamicImage> {
+    if jpeg_data.len() < 10 {
+        return Err(anyhow::anyhow!("JPEG data too small"));
+    }
+
+    Ok(image::load_from_memory_with_format(jpeg_data, ImageFormat::Jpeg)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn test_images_dir() -> String {
+        format!("{}/test_images", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    fn encode_jpeg(image: &DynamicImage) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        image.to_rgb8().write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg)?;
+        Ok(bytes)
+    }
+
+    #[test]
+    fn directory_source_loops_over_images() -> Result<()> {
+        let mut source = DirectorySource::open(&test_images_dir())?;
+        let count = source.paths.len();
+
+        for _ in 0..count + 1 {
+            assert!(source.next_frame()?.is_some());
+        }
+        assert_eq!(source.next_index, 1 % count);
+        Ok(())
+    }
+
+    #[test]
+    fn mjpeg_file_source_splits_concatenated_frames() -> Result<()> {
+        let frame = DynamicImage::new_rgb8(16, 12);
+        let jpeg = encode_jpeg(&frame)?;
+        let path = std::env::temp_dir().join(format!("face_auth_{}.mjpeg", uuid::Uuid::new_v4()));
+        fs::write(&path, [jpeg.clone(), jpeg.clone(), jpeg].concat())?;
+
+        let result = MjpegFileSource::open(path.to_str().unwrap());
+        let _ = fs::remove_file(&path);
+        let mut source = result?;
+
+        assert_eq!(source.frames.len(), 3);
+        let decoded = source.next_frame()?.expect("frame");
+        assert_eq!((decoded.width(), decoded.height()), (16, 12));
+        Ok(())
+    }
+}
@@ -0,0 +1,431 @@
+//! Parsers that split MJPEG byte streams into individual JPEG frames.
+use anyhow::Result;
+
+// Upper bound for a single frame; anything larger is treated as a corrupt stream
+const MAX_FRAME_SIZE: usize = 32 * 1024 * 1024;
+// Upper bound for the headers of a single multipart part
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+/// Splits an HTTP MJPEG response body into JPEG frames.
+pub enum StreamParser {
+    Multipart(MultipartParser),
+    Concatenated(ConcatenatedJpegParser),
+}
+
+impl StreamParser {
+    /// Picks the parser matching the response `Content-Type`. Streams that don't
+    /// announce a multipart boundary are split on JPEG markers instead.
+    pub fn for_content_type(content_type: Option<&str>) -> Self {
+        match content_type.and_then(boundary_from_content_type) {
+            Some(boundary) => StreamParser::Multipart(MultipartParser::new(&boundary)),
+            None => StreamParser::Concatenated(ConcatenatedJpegParser::default()),
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        match self {
+            StreamParser::Multipart(parser) => parser.push(data),
+            StreamParser::Concatenated(parser) => parser.push(data),
+        }
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        match self {
+            StreamParser::Multipart(parser) => parser.next_part(),
+            StreamParser::Concatenated(parser) => Ok(parser.next_frame()),
+        }
+    }
+}
+
+/// Extracts the boundary parameter of a `multipart/x-mixed-replace` content type.
+pub fn boundary_from_content_type(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let mime = params.next()?.trim();
+    if !mime.to_ascii_lowercase().starts_with("multipart/") {
+        return None;
+    }
+
+    params
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| {
+            let value = value.trim().trim_matches('"');
+            // Some servers repeat the leading dashes of the delimiter in the header
+            value.strip_prefix("--").unwrap_or(value).to_string()
+        })
+        .filter(|boundary| !boundary.is_empty())
+}
+
+/// Incremental parser for `multipart/x-mixed-replace` bodies (RFC 2046).
+///
+/// Parts with a `Content-Length` header are cut to exactly that length; parts
+/// without one end with their JPEG EOI or, failing that, at the next delimiter.
+pub struct MultipartParser {
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+impl MultipartParser {
+    pub fn new(boundary: &str) -> Self {
+        MultipartParser {
+            delimiter: format!("--{boundary}").into_bytes(),
+            buffer: Vec::with_capacity(300_000),
+            finished: false,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        if !self.finished {
+            self.buffer.extend_from_slice(data);
+        }
+    }
+
+    /// Returns the body of the next complete part, or `None` if more data is needed.
+    pub fn next_part(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if self.finished {
+                return Ok(None);
+            }
+
+            // Skip the preamble or epilogue of the previous part up to the delimiter
+            let Some(delimiter_pos) = find(&self.buffer, &self.delimiter, 0) else {
+                // Keep just enough bytes to match a delimiter split across chunks
+                let keep = self.delimiter.len().min(self.buffer.len());
+                self.buffer.drain(..self.buffer.len() - keep);
+                return Ok(None);
+            };
+
+            let after_delimiter = delimiter_pos + self.delimiter.len();
+            if self.buffer.len() < after_delimiter + 2 {
+                return Ok(None);
+            }
+            if &self.buffer[after_delimiter..after_delimiter + 2] == b"--" {
+                // Close delimiter, the stream has no more parts
+                self.finished = true;
+                self.buffer.clear();
+                return Ok(None);
+            }
+
+            let Some((headers_end, body_start)) = find_headers_end(&self.buffer, after_delimiter) else {
+                if self.buffer.len() - after_delimiter > MAX_HEADER_SIZE {
+                    // Not a real part header, resynchronise on the next delimiter
+                    self.buffer.drain(..after_delimiter);
+                    continue;
+                }
+                return Ok(None);
+            };
+
+            let headers = String::from_utf8_lossy(&self.buffer[after_delimiter..headers_end]);
+            let body_end = match content_length(&headers) {
+                Some(length) if length > MAX_FRAME_SIZE => {
+                    anyhow::bail!("Multipart part of {length} bytes exceeds the {MAX_FRAME_SIZE} byte limit");
+                }
+                Some(length) => {
+                    if self.buffer.len() < body_start + length {
+                        return Ok(None);
+                    }
+                    body_start + length
+                }
+                None => {
+                    // A JPEG body ends at its own EOI, so the frame doesn't have
+                    // to wait for the next part to arrive
+                    let mut next_delimiter = b"\r\n".to_vec();
+                    next_delimiter.extend_from_slice(&self.delimiter);
+                    match (scan_jpeg(&self.buffer[body_start..]), find(&self.buffer, &next_delimiter, body_start)) {
+                        (JpegScan::Complete(length), _) => body_start + length,
+                        (_, Some(pos)) => pos,
+                        _ if self.buffer.len() - body_start > MAX_FRAME_SIZE => {
+                            anyhow::bail!("Multipart part exceeds the {MAX_FRAME_SIZE} byte limit");
+                        }
+                        _ => return Ok(None),
+                    }
+                }
+            };
+
+            let body = self.buffer[body_start..body_end].to_vec();
+            self.buffer.drain(..body_end);
+            return Ok(Some(body));
+        }
+    }
+}
+
+/// Splits a plain concatenation of JPEG images, such as a `.mjpeg` recording,
+/// by walking the JPEG marker segments rather than searching for EOI bytes.
+#[derive(Default)]
+pub struct ConcatenatedJpegParser {
+    buffer: Vec<u8>,
+}
+
+impl ConcatenatedJpegParser {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let Some(start) = find(&self.buffer, &[0xFF, 0xD8], 0) else {
+                // Keep a trailing 0xFF which may be the first half of the next SOI
+                let keep = usize::from(self.buffer.last() == Some(&0xFF));
+                self.buffer.drain(..self.buffer.len() - keep);
+                return None;
+            };
+            self.buffer.drain(..start);
+
+            match scan_jpeg(&self.buffer) {
+                JpegScan::Complete(length) => {
+                    let frame = self.buffer[..length].to_vec();
+                    self.buffer.drain(..length);
+                    return Some(frame);
+                }
+                JpegScan::Incomplete if self.buffer.len() <= MAX_FRAME_SIZE => return None,
+                // Corrupt or oversized frame, skip its SOI and look for the next one
+                JpegScan::Incomplete | JpegScan::Invalid => {
+                    self.buffer.drain(..2);
+                }
+            }
+        }
+    }
+}
+
+/// Splits a complete buffer of concatenated JPEG images into frames.
+pub fn split_jpeg_frames(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut parser = ConcatenatedJpegParser::default();
+    parser.push(data);
+    std::iter::from_fn(|| parser.next_frame()).collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum JpegScan {
+    /// A full image of the given length, SOI through EOI inclusive
+    Complete(usize),
+    Incomplete,
+    Invalid,
+}
+
+/// Walks the marker segments of the JPEG starting at `data[0]`.
+///
+/// Segment payloads are skipped using their length fields, so markers inside
+/// them (e.g. an EXIF thumbnail with its own SOI/EOI) don't end the frame early.
+fn scan_jpeg(data: &[u8]) -> JpegScan {
+    if data.len() < 2 {
+        return JpegScan::Incomplete;
+    }
+    if data[0] != 0xFF || data[1] != 0xD8 {
+        return JpegScan::Invalid;
+    }
+
+    let mut pos = 2;
+    loop {
+        // Every segment starts with 0xFF, optionally preceded by fill bytes
+        match data.get(pos) {
+            None => return JpegScan::Incomplete,
+            Some(0xFF) => {}
+            Some(_) => return JpegScan::Invalid,
+        }
+        while data.get(pos) == Some(&0xFF) {
+            pos += 1;
+        }
+        let Some(&marker) = data.get(pos) else {
+            return JpegScan::Incomplete;
+        };
+        pos += 1;
+
+        match marker {
+            0xD9 => return JpegScan::Complete(pos),
+            // Markers without a payload
+            0x01 | 0xD0..=0xD7 => continue,
+            0xD8 | 0x00 => return JpegScan::Invalid,
+            _ => {}
+        }
+
+        let Some(length) = data.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize) else {
+            return JpegScan::Incomplete;
+        };
+        if length < 2 {
+            return JpegScan::Invalid;
+        }
+        pos += length;
+
+        if marker == 0xDA {
+            // Entropy-coded data runs until the next marker that isn't a
+            // stuffed 0xFF00 byte or a restart marker
+            loop {
+                match data.get(pos..pos + 2) {
+                    None => return JpegScan::Incomplete,
+                    Some([0xFF, next]) if *next == 0x00 || (0xD0..=0xD7).contains(next) || *next == 0xFF => {
+                        pos += if *next == 0xFF { 1 } else { 2 };
+                    }
+                    Some([0xFF, _]) => break,
+                    Some(_) => pos += 1,
+                }
+            }
+        }
+    }
+}
+
+fn find_headers_end(buffer: &[u8], from: usize) -> Option<(usize, usize)> {
+    // Headers end at an empty line; tolerate bare LF line endings
+    let crlf = find(buffer, b"\r\n\r\n", from).map(|pos| (pos, pos + 4));
+    let lf = find(buffer, b"\n\n", from).map(|pos| (pos, pos + 2));
+    match (crlf, lf) {
+        (Some(a), Some(b)) => Some(if a.0 <= b.0 { a } else { b }),
+        (a, b) => a.or(b),
+    }
+}
+
+fn content_length(headers: &str) -> Option<usize> {
+    headers
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if from >= haystack.len() || needle.is_empty() {
+        return None;
+    }
+    haystack[from..]
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|pos| pos + from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    fn encode_jpeg(width: u32, height: u32, seed: u8) -> Vec<u8> {
+        let image = RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x as u8).wrapping_mul(seed), (y as u8).wrapping_add(seed), seed])
+        });
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg)
+            .unwrap();
+        bytes
+    }
+
+    // Inserts an APP1 segment carrying a complete JPEG thumbnail right after SOI
+    fn with_exif_thumbnail(jpeg: &[u8]) -> Vec<u8> {
+        let thumbnail = encode_jpeg(8, 8, 3);
+        let mut payload = b"Exif\0\0".to_vec();
+        payload.extend_from_slice(&thumbnail);
+
+        let mut out = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        out.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        out.extend_from_slice(&payload);
+        out.extend_from_slice(&jpeg[2..]);
+        out
+    }
+
+    // Pads a JPEG with APP15 segments so it exceeds `min_size` bytes
+    fn padded_jpeg(jpeg: &[u8], min_size: usize) -> Vec<u8> {
+        let mut out = vec![0xFF, 0xD8];
+        while out.len() + jpeg.len() < min_size {
+            out.extend_from_slice(&[0xFF, 0xEF, 0xFF, 0xFF]);
+            out.extend_from_slice(&[0xAB; 0xFFFD]);
+        }
+        out.extend_from_slice(&jpeg[2..]);
+        out
+    }
+
+    fn multipart_stream(frames: &[Vec<u8>], boundary: &str, with_length: bool) -> Vec<u8> {
+        let mut stream = Vec::new();
+        for frame in frames {
+            stream.extend_from_slice(format!("--{boundary}\r\nContent-Type: image/jpeg\r\n").as_bytes());
+            if with_length {
+                stream.extend_from_slice(format!("Content-Length: {}\r\n", frame.len()).as_bytes());
+            }
+            stream.extend_from_slice(b"\r\n");
+            stream.extend_from_slice(frame);
+            stream.extend_from_slice(b"\r\n");
+        }
+        stream
+    }
+
+    fn parse_in_chunks(mut parser: StreamParser, stream: &[u8], chunk_size: usize) -> Result<Vec<Vec<u8>>> {
+        let mut frames = Vec::new();
+        for chunk in stream.chunks(chunk_size) {
+            parser.push(chunk);
+            while let Some(frame) = parser.next_frame()? {
+                frames.push(frame);
+            }
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn boundary_is_read_from_content_type() {
+        assert_eq!(boundary_from_content_type("multipart/x-mixed-replace; boundary=frame").as_deref(), Some("frame"));
+        assert_eq!(boundary_from_content_type("multipart/x-mixed-replace;boundary=\"--myboundary\"").as_deref(), Some("myboundary"));
+        assert_eq!(boundary_from_content_type("image/jpeg"), None);
+        assert_eq!(boundary_from_content_type("multipart/x-mixed-replace"), None);
+    }
+
+    #[test]
+    fn multipart_frames_survive_arbitrary_chunking() -> Result<()> {
+        let frames = vec![encode_jpeg(32, 24, 1), with_exif_thumbnail(&encode_jpeg(40, 30, 2)), encode_jpeg(16, 16, 5)];
+
+        for with_length in [true, false] {
+            let stream = multipart_stream(&frames, "frame", with_length);
+            for chunk_size in [1, 7, 512, stream.len()] {
+                let parser = StreamParser::for_content_type(Some("multipart/x-mixed-replace; boundary=frame"));
+                assert_eq!(parse_in_chunks(parser, &stream, chunk_size)?, frames);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn multipart_keeps_frames_larger_than_200kb() -> Result<()> {
+        let large = padded_jpeg(&encode_jpeg(64, 48, 9), 300_000);
+        let stream = multipart_stream(std::slice::from_ref(&large), "frame", true);
+
+        let parser = StreamParser::for_content_type(Some("multipart/x-mixed-replace; boundary=frame"));
+        let parsed = parse_in_chunks(parser, &stream, 8192)?;
+        assert_eq!(parsed, vec![large]);
+        Ok(())
+    }
+
+    #[test]
+    fn multipart_stops_at_close_delimiter() -> Result<()> {
+        let frame = encode_jpeg(16, 16, 4);
+        let mut stream = multipart_stream(std::slice::from_ref(&frame), "frame", false);
+        stream.extend_from_slice(b"--frame--\r\n");
+
+        let mut parser = MultipartParser::new("frame");
+        parser.push(&stream);
+        assert_eq!(parser.next_part()?, Some(frame));
+        assert_eq!(parser.next_part()?, None);
+        assert!(parser.finished);
+        Ok(())
+    }
+
+    #[test]
+    fn concatenated_parser_skips_embedded_thumbnail_markers() -> Result<()> {
+        let frames = vec![with_exif_thumbnail(&encode_jpeg(40, 30, 6)), encode_jpeg(24, 24, 7)];
+        let stream = frames.concat();
+
+        for chunk_size in [3, 1000, stream.len()] {
+            let parsed = parse_in_chunks(StreamParser::for_content_type(None), &stream, chunk_size)?;
+            assert_eq!(parsed, frames);
+        }
+        for frame in &frames {
+            image::load_from_memory_with_format(frame, ImageFormat::Jpeg)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn concatenated_parser_resynchronises_after_garbage() {
+        let frame = encode_jpeg(16, 16, 8);
+        let mut stream = b"garbage\xFF\xD8\x12\x34".to_vec();
+        stream.extend_from_slice(&frame);
+
+        assert_eq!(split_jpeg_frames(&stream), vec![frame]);
+    }
+}
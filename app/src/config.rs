@@ -1,77 +1,807 @@
+use crate::camera::frame_source::FrameSourceType;
+use crate::image_utils::quality::QualityThresholds;
+use crate::lockout::{LockoutPolicy, LockoutTracker};
+use crate::login::validate_threshold;
+use crate::replay::ReplayGuard;
+use crate::storage::encrypted_storage::{KeyRing, StorageKey};
+use crate::storage::hnsw_index::HnswParams;
 use crate::storage::vector_storage::StorageType;
+use crate::users::ThresholdOverrides;
+use anyhow::{Context, Result};
 use serde::Deserialize;
 use std::fs;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const DEFAULT_CONFIG_PATH: &str = "config.yaml";
 
 #[derive(Debug, Deserialize)]
 struct Config {
     storage: StorageConfig,
     stream: StreamConfig,
     model: ModelConfig,
+    #[serde(default)]
+    ui: UiConfig,
+    #[serde(default)]
+    face_detection: FaceDetectionConfig,
+    #[serde(default)]
+    alignment: AlignmentConfig,
+    #[serde(default)]
+    quality: QualityConfig,
+    #[serde(default)]
+    enrollment: EnrollmentConfig,
+    #[serde(default)]
+    auth: AuthConfig,
+    #[serde(default)]
+    identify: IdentifyConfig,
+    #[serde(default)]
+    liveness: LivenessConfig,
+    #[serde(default)]
+    replay: ReplayConfig,
+    #[serde(default)]
+    lockout: LockoutConfig,
 }
 
 #[derive(Debug, Deserialize)]
 struct StorageConfig {
     #[serde(rename = "type")]
     storage_type: String,
-    local_file: LocalFileConfig,
+    // Only the section of the selected type is required
+    local_file: Option<LocalFileConfig>,
+    sqlite: Option<SqliteConfig>,
+    #[serde(default)]
+    encryption: EncryptionConfig,
+    #[serde(default)]
+    index: IndexConfig,
 }
 
 #[derive(Debug, Deserialize)]
 struct LocalFileConfig {
     path: String,
+    // Start with an empty store when the file can't be parsed (it is kept as a backup)
+    #[serde(default)]
+    recover_corrupt: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SqliteConfig {
+    path: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct EncryptionConfig {
+    // Encrypt templates before they reach the backend
+    #[serde(default)]
+    enabled: bool,
+    // Current key as 64 hex characters, read from a file or an environment variable
+    key_file: Option<String>,
+    key_env: Option<String>,
+    // Keys of a rotation in progress, only used to read records not yet re-encrypted
+    #[serde(default)]
+    previous_key_files: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IndexConfig {
+    // Nearest neighbour index: "none" (exhaustive search) or "hnsw"
+    #[serde(rename = "type", default = "default_index_type")]
+    index_type: String,
+    // Index file, defaults to the storage path with ".hnsw" appended
+    path: Option<String>,
+    // Links per node (twice as many on the bottom layer)
+    #[serde(default = "default_hnsw_m")]
+    m: usize,
+    #[serde(default = "default_hnsw_ef_construction")]
+    ef_construction: usize,
+    // Candidates explored per search; higher is slower but closer to exhaustive
+    #[serde(default = "default_hnsw_ef_search")]
+    ef_search: usize,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        IndexConfig {
+            index_type: default_index_type(),
+            path: None,
+            m: default_hnsw_m(),
+            ef_construction: default_hnsw_ef_construction(),
+            ef_search: default_hnsw_ef_search(),
+        }
+    }
 }
 
+fn default_index_type() -> String {
+    "none".to_string()
+}
+
+fn default_hnsw_m() -> usize {
+    16
+}
+
+fn default_hnsw_ef_construction() -> usize {
+    200
+}
+
+fn default_hnsw_ef_search() -> usize {
+    64
+}
 
 
 #[derive(Debug, Deserialize)]
 struct StreamConfig {
+    // Frame source: "http", "directory", "image" or "mjpeg_file"
+    #[serde(default = "default_stream_source")]
+    source: String,
     url: String,
+    // Input for the file based sources
+    path: Option<String>,
     num_images: usize,
     interval_millis: u64,
     chunk_size: usize,
+    // Reconnect delay after the stream drops, doubled on every failed attempt
+    #[serde(default = "default_reconnect_initial_millis")]
+    reconnect_initial_millis: u64,
+    #[serde(default = "default_reconnect_max_millis")]
+    reconnect_max_millis: u64,
+    // Register and login fail if the samples aren't collected within this time
+    #[serde(default = "default_capture_timeout_secs")]
+    capture_timeout_secs: u64,
 }
 
 #[derive(Debug, Deserialize)]
 struct ModelConfig {
     name: String,
+    // Compute dtype: "f32", "f16" or "bf16"
+    #[serde(default = "default_model_dtype")]
+    dtype: String,
+    // Local safetensors file; takes precedence over the hub when set
+    path: Option<String>,
+    // Never touch the network, only read `path` or the hub cache
+    #[serde(default)]
+    offline: bool,
+    // Pre-seeded hub cache directory, defaults to $HF_HOME/hub
+    cache_dir: Option<String>,
 }
 
-lazy_static::lazy_static! {
-    static ref CONFIG: Config = load_config().expect("Failed to load configuration");
+#[derive(Debug, Deserialize)]
+struct UiConfig {
+    // Show the live preview window while capturing
+    #[serde(default = "default_true")]
+    preview: bool,
 }
 
-fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
-    let config_content = fs::read_to_string("config.yaml")?;
-    let config: Config = serde_yaml::from_str(&config_content)?;
-    Ok(config)
+impl Default for UiConfig {
+    fn default() -> Self {
+        UiConfig { preview: true }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FaceDetectionConfig {
+    // Crop the largest detected face before computing embeddings
+    #[serde(default)]
+    enabled: bool,
+    // UltraFace slim-320 weights converted to safetensors
+    weights_path: Option<String>,
+    #[serde(default = "default_face_score_threshold")]
+    score_threshold: f32,
+    #[serde(default = "default_face_iou_threshold")]
+    iou_threshold: f32,
+    // Extra context around the face box, as a fraction of the face size per side
+    #[serde(default = "default_face_margin")]
+    margin: f32,
+}
+
+impl Default for FaceDetectionConfig {
+    fn default() -> Self {
+        FaceDetectionConfig {
+            enabled: false,
+            weights_path: None,
+            score_threshold: default_face_score_threshold(),
+            iou_threshold: default_face_iou_threshold(),
+            margin: default_face_margin(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AlignmentConfig {
+    // Warp the detected face onto reference landmarks; requires face detection
+    #[serde(default)]
+    enabled: bool,
+    // MTCNN O-Net weights converted to safetensors
+    weights_path: Option<String>,
+    // Side of the aligned square crop in pixels
+    #[serde(default = "default_aligned_size")]
+    output_size: u32,
+}
+
+impl Default for AlignmentConfig {
+    fn default() -> Self {
+        AlignmentConfig {
+            enabled: false,
+            weights_path: None,
+            output_size: default_aligned_size(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthConfig {
+    // Minimum cosine similarity for a successful login; users may override it
+    #[serde(default = "default_login_threshold")]
+    login_threshold: f32,
+    // Per-user overrides; defaults to the storage path with a `.thresholds.json` suffix
+    thresholds_path: Option<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig { login_threshold: default_login_threshold(), thresholds_path: None }
+    }
+}
+
+fn default_login_threshold() -> f32 {
+    0.7
+}
+
+#[derive(Debug, Deserialize)]
+struct IdentifyConfig {
+    // Number of candidate users reported by `identify`
+    #[serde(default = "default_identify_top_k")]
+    top_k: usize,
+}
+
+impl Default for IdentifyConfig {
+    fn default() -> Self {
+        IdentifyConfig { top_k: default_identify_top_k() }
+    }
+}
+
+fn default_identify_top_k() -> usize {
+    3
+}
+
+#[derive(Debug, Deserialize)]
+struct LivenessConfig {
+    // Reject captures that look like a photo or screen; off until calibrated
+    #[serde(default)]
+    enabled: bool,
+    // Minimum combined liveness score, 0 (spoof) to 1 (live)
+    #[serde(default = "default_liveness_threshold")]
+    threshold: f32,
+    #[serde(default)]
+    challenge: ChallengeConfig,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        LivenessConfig { enabled: false, threshold: default_liveness_threshold(), challenge: ChallengeConfig::default() }
+    }
+}
+
+fn default_liveness_threshold() -> f32 {
+    0.5
+}
+
+#[derive(Debug, Deserialize)]
+struct ChallengeConfig {
+    // Ask for a random head turn or blinks before login and identify capture
+    #[serde(default)]
+    enabled: bool,
+    // Time the user has to perform the action
+    #[serde(default = "default_challenge_timeout_secs")]
+    timeout_secs: u64,
+    // Minimum cosine similarity of the faces that performed the action to the
+    // sampled face; lower than the login threshold, as the pose differs
+    #[serde(default = "default_challenge_min_similarity")]
+    min_similarity: f32,
+}
+
+impl Default for ChallengeConfig {
+    fn default() -> Self {
+        ChallengeConfig {
+            enabled: false,
+            timeout_secs: default_challenge_timeout_secs(),
+            min_similarity: default_challenge_min_similarity(),
+        }
+    }
+}
+
+fn default_challenge_timeout_secs() -> u64 {
+    10
+}
+
+fn default_challenge_min_similarity() -> f32 {
+    0.5
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplayConfig {
+    // Reject frozen captures and frames reused from earlier attempts
+    #[serde(default)]
+    enabled: bool,
+    // Defaults to the storage path with a `.replay.json` suffix
+    path: Option<String>,
+    // Frames of recent attempts kept for comparison
+    #[serde(default = "default_replay_history_size")]
+    history_size: usize,
+    // Perceptual hash bits (of 256) a reused frame may differ by; 0 = exact copies only
+    #[serde(default = "default_replay_max_distance")]
+    max_distance: u32,
+    // Consecutive frames that must replay an earlier attempt in order
+    #[serde(default = "default_replay_min_sequence")]
+    min_sequence: usize,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        ReplayConfig {
+            enabled: false,
+            path: None,
+            history_size: default_replay_history_size(),
+            max_distance: default_replay_max_distance(),
+            min_sequence: default_replay_min_sequence(),
+        }
+    }
+}
+
+fn default_replay_history_size() -> usize {
+    2000
+}
+
+fn default_replay_max_distance() -> u32 {
+    8
 }
 
-pub fn get_storage_config() -> StorageType {
-    match CONFIG.storage.storage_type.as_str() {
-        "local_file" => StorageType::LocalFile(CONFIG.storage.local_file.path.clone()),
-        _ => {
-            eprintln!("Unknown storage type: {}, defaulting to local_file", CONFIG.storage.storage_type);
-            StorageType::LocalFile("embeddings.json".to_string())
+fn default_replay_min_sequence() -> usize {
+    3
+}
+
+#[derive(Debug, Deserialize)]
+struct LockoutConfig {
+    // Lock a user out of `login` after repeated failures
+    #[serde(default = "default_true")]
+    enabled: bool,
+    // Defaults to the storage path with an `.attempts.json` suffix
+    path: Option<String>,
+    // Failed attempts within the window that trigger a lockout
+    #[serde(default = "default_lockout_max_failures")]
+    max_failures: usize,
+    #[serde(default = "default_lockout_window_secs")]
+    window_secs: u64,
+    // First lockout; every further one in a row doubles it, up to the maximum
+    #[serde(default = "default_lockout_cooldown_secs")]
+    cooldown_secs: u64,
+    #[serde(default = "default_lockout_max_cooldown_secs")]
+    max_cooldown_secs: u64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        LockoutConfig {
+            enabled: true,
+            path: None,
+            max_failures: default_lockout_max_failures(),
+            window_secs: default_lockout_window_secs(),
+            cooldown_secs: default_lockout_cooldown_secs(),
+            max_cooldown_secs: default_lockout_max_cooldown_secs(),
+        }
+    }
+}
+
+fn default_lockout_max_failures() -> usize {
+    5
+}
+
+fn default_lockout_window_secs() -> u64 {
+    900
+}
+
+fn default_lockout_cooldown_secs() -> u64 {
+    60
+}
+
+fn default_lockout_max_cooldown_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Deserialize)]
+struct EnrollmentConfig {
+    // Store every sample embedding next to the centroid
+    #[serde(default = "default_true")]
+    store_samples: bool,
+}
+
+impl Default for EnrollmentConfig {
+    fn default() -> Self {
+        EnrollmentConfig { store_samples: true }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct QualityConfig {
+    // Skip blurred, badly exposed and poorly framed frames; off when the section
+    // is missing, so existing installs keep capturing as before
+    #[serde(default)]
+    enabled: bool,
+    // Laplacian variance of the face (or frame) resampled to 128x128
+    #[serde(default = "default_min_sharpness")]
+    min_sharpness: f32,
+    // Mean luma range, 0-255
+    #[serde(default = "default_min_brightness")]
+    min_brightness: f32,
+    #[serde(default = "default_max_brightness")]
+    max_brightness: f32,
+    // Standard deviation of luma, 0-255
+    #[serde(default = "default_min_contrast")]
+    min_contrast: f32,
+    // Face side as a fraction of the frame; only checked with face detection
+    #[serde(default = "default_min_face_size")]
+    min_face_size: f32,
+    // Face centre distance from the frame centre, 0 (centred) to 1 (at the edge)
+    #[serde(default = "default_max_face_offset")]
+    max_face_offset: f32,
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        QualityConfig {
+            enabled: false,
+            min_sharpness: default_min_sharpness(),
+            min_brightness: default_min_brightness(),
+            max_brightness: default_max_brightness(),
+            min_contrast: default_min_contrast(),
+            min_face_size: default_min_face_size(),
+            max_face_offset: default_max_face_offset(),
         }
     }
 }
 
-pub fn get_stream_url() -> &'static str {
-    &CONFIG.stream.url
+fn default_min_sharpness() -> f32 {
+    30.
+}
+
+fn default_min_brightness() -> f32 {
+    40.
+}
+
+fn default_max_brightness() -> f32 {
+    215.
+}
+
+fn default_min_contrast() -> f32 {
+    15.
+}
+
+fn default_min_face_size() -> f32 {
+    0.15
+}
+
+fn default_max_face_offset() -> f32 {
+    0.5
+}
+
+fn default_aligned_size() -> u32 {
+    224
+}
+
+fn default_face_score_threshold() -> f32 {
+    0.7
+}
+
+fn default_face_iou_threshold() -> f32 {
+    0.3
+}
+
+fn default_face_margin() -> f32 {
+    0.2
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_stream_source() -> String {
+    "http".to_string()
+}
+
+fn default_reconnect_initial_millis() -> u64 {
+    250
+}
+
+fn default_reconnect_max_millis() -> u64 {
+    5000
+}
+
+fn default_capture_timeout_secs() -> u64 {
+    30
+}
+
+fn default_model_dtype() -> String {
+    "f16".to_string()
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+// Set by --headless, overrides ui.preview
+static FORCE_HEADLESS: AtomicBool = AtomicBool::new(false);
+
+fn load_config(path: &str) -> Result<Config> {
+    let config_content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read configuration file '{path}'"))?;
+    let config: Config = serde_yaml::from_str(&config_content)
+        .with_context(|| format!("Failed to parse configuration file '{path}'"))?;
+    validate_threshold(config.auth.login_threshold)
+        .with_context(|| format!("Invalid auth.login_threshold in '{path}'"))?;
+    if !(0.0..=1.0).contains(&config.liveness.threshold) {
+        anyhow::bail!("Invalid liveness.threshold in '{path}': must be between 0 and 1, got {}", config.liveness.threshold);
+    }
+    if config.liveness.challenge.enabled && !(config.face_detection.enabled && config.alignment.enabled) {
+        anyhow::bail!("Invalid liveness.challenge in '{path}': it needs face_detection and alignment enabled");
+    }
+    if !(0.0..=1.0).contains(&config.liveness.challenge.min_similarity) {
+        anyhow::bail!("Invalid liveness.challenge.min_similarity in '{path}': must be between 0 and 1, got {}",
+                      config.liveness.challenge.min_similarity);
+    }
+    Ok(config)
+}
+
+/// Loads the configuration from `path`. Must be called before any getter if a
+/// non-default path is wanted; otherwise `config.yaml` is loaded lazily.
+pub fn init(path: &str) -> Result<()> {
+    let config = load_config(path)?;
+    if CONFIG.set(config).is_err() {
+        anyhow::bail!("Configuration already initialised");
+    }
+    Ok(())
+}
+
+fn config() -> &'static Config {
+    CONFIG.get_or_init(|| load_config(DEFAULT_CONFIG_PATH).expect("Failed to load configuration"))
+}
+
+// Path of the selected storage backend; state files default to sitting next to it
+fn storage_path() -> Result<&'static str> {
+    let storage = &config().storage;
+    match storage.storage_type.as_str() {
+        "local_file" => Ok(&storage.local_file.as_ref().context("storage.local_file.path is required for local_file storage")?.path),
+        "sqlite" => Ok(&storage.sqlite.as_ref().context("storage.sqlite.path is required for sqlite storage")?.path),
+        other => anyhow::bail!("Unknown storage type '{other}' (expected 'local_file' or 'sqlite')"),
+    }
+}
+
+pub fn get_storage_config() -> Result<StorageType> {
+    let storage = &config().storage;
+    let path = storage_path()?;
+    // storage_path() has already rejected unknown types
+    let backend = match storage.storage_type.as_str() {
+        "local_file" => StorageType::LocalFile {
+            path: path.to_string(),
+            recover_corrupt: storage.local_file.as_ref().is_some_and(|local_file| local_file.recover_corrupt),
+        },
+        _ => StorageType::Sqlite(path.to_string()),
+    };
+
+    let encryption = &storage.encryption;
+    let backend = if encryption.enabled {
+        let current = match (&encryption.key_file, &encryption.key_env) {
+            (Some(path), None) => StorageKey::from_file(path)?,
+            (None, Some(variable)) => StorageKey::from_env(variable)?,
+            _ => anyhow::bail!("storage.encryption needs exactly one of key_file and key_env"),
+        };
+        let previous = encryption
+            .previous_key_files
+            .iter()
+            .map(|path| StorageKey::from_file(path))
+            .collect::<Result<_>>()?;
+        StorageType::Encrypted { storage: Box::new(backend), keys: KeyRing::new(current, previous) }
+    } else {
+        backend
+    };
+
+    let index = &storage.index;
+    Ok(match index.index_type.as_str() {
+        "none" => backend,
+        "hnsw" => StorageType::Indexed {
+            storage: Box::new(backend),
+            path: index.path.clone().unwrap_or_else(|| format!("{path}.hnsw")),
+            params: HnswParams { m: index.m.max(2), ef_construction: index.ef_construction.max(1), ef_search: index.ef_search.max(1) },
+        },
+        other => anyhow::bail!("Unknown storage index type '{other}' (expected 'none' or 'hnsw')"),
+    })
+}
+
+pub fn get_frame_source_config() -> Result<FrameSourceType> {
+    let stream = &config().stream;
+    let path = || {
+        stream.path.clone().ok_or_else(|| {
+            anyhow::anyhow!("stream.path is required for the '{}' frame source", stream.source)
+        })
+    };
+
+    match stream.source.as_str() {
+        "http" => Ok(FrameSourceType::Http(stream.url.clone())),
+        "directory" => Ok(FrameSourceType::Directory(path()?)),
+        "image" => Ok(FrameSourceType::ImageFile(path()?)),
+        "mjpeg_file" => Ok(FrameSourceType::MjpegFile(path()?)),
+        other => Err(anyhow::anyhow!(
+            "Unknown stream source '{other}', expected http, directory, image or mjpeg_file"
+        )),
+    }
 }
 
 pub fn get_num_images() -> usize {
-    CONFIG.stream.num_images
+    config().stream.num_images
 }
 
 pub fn get_interval_millis() -> u64 {
-    CONFIG.stream.interval_millis
+    config().stream.interval_millis
 }
 
 pub fn get_chunk_size() -> usize {
-    CONFIG.stream.chunk_size
+    config().stream.chunk_size
+}
+
+pub fn get_reconnect_initial_millis() -> u64 {
+    config().stream.reconnect_initial_millis
+}
+
+pub fn get_reconnect_max_millis() -> u64 {
+    config().stream.reconnect_max_millis
+}
+
+pub fn get_capture_timeout_secs() -> u64 {
+    config().stream.capture_timeout_secs
 }
 
 pub fn get_model_name() -> &'static str {
-    &CONFIG.model.name
+    &config().model.name
+}
+
+pub fn get_model_path() -> Option<&'static str> {
+    config().model.path.as_deref()
+}
+
+pub fn is_model_offline() -> bool {
+    config().model.offline
+}
+
+pub fn get_model_cache_dir() -> Option<&'static str> {
+    config().model.cache_dir.as_deref()
+}
+
+pub fn get_model_dtype() -> &'static str {
+    &config().model.dtype
+}
+
+pub fn force_headless() {
+    FORCE_HEADLESS.store(true, Ordering::Relaxed);
+}
+
+pub fn is_preview_enabled() -> bool {
+    !FORCE_HEADLESS.load(Ordering::Relaxed) && config().ui.preview
+}
+
+pub fn is_face_detection_enabled() -> bool {
+    config().face_detection.enabled
+}
+
+pub fn get_face_detection_weights() -> Result<&'static str> {
+    config().face_detection.weights_path.as_deref().ok_or_else(|| {
+        anyhow::anyhow!("face_detection.weights_path is required when face detection is enabled")
+    })
+}
+
+pub fn get_face_score_threshold() -> f32 {
+    config().face_detection.score_threshold
+}
+
+pub fn get_face_iou_threshold() -> f32 {
+    config().face_detection.iou_threshold
+}
+
+pub fn get_face_margin() -> f32 {
+    config().face_detection.margin
+}
+
+pub fn is_alignment_enabled() -> bool {
+    config().alignment.enabled
+}
+
+pub fn get_alignment_weights() -> Result<&'static str> {
+    config().alignment.weights_path.as_deref().ok_or_else(|| {
+        anyhow::anyhow!("alignment.weights_path is required when alignment is enabled")
+    })
+}
+
+pub fn get_aligned_size() -> u32 {
+    config().alignment.output_size
+}
+
+/// Quality thresholds for sampled frames, or `None` when gating is disabled.
+pub fn get_quality_thresholds() -> Option<QualityThresholds> {
+    let quality = &config().quality;
+    quality.enabled.then_some(QualityThresholds {
+        min_sharpness: quality.min_sharpness,
+        min_brightness: quality.min_brightness,
+        max_brightness: quality.max_brightness,
+        min_contrast: quality.min_contrast,
+        min_face_size: quality.min_face_size,
+        max_face_offset: quality.max_face_offset,
+    })
+}
+
+pub fn is_sample_storage_enabled() -> bool {
+    config().enrollment.store_samples
+}
+
+pub fn get_login_threshold() -> f32 {
+    config().auth.login_threshold
+}
+
+pub fn get_threshold_overrides() -> Result<ThresholdOverrides> {
+    let path = match &config().auth.thresholds_path {
+        Some(path) => path.clone(),
+        None => format!("{}.thresholds.json", storage_path()?),
+    };
+    Ok(ThresholdOverrides::new(path.into()))
+}
+
+pub fn get_identify_top_k() -> usize {
+    config().identify.top_k
+}
+
+pub fn is_challenge_enabled() -> bool {
+    config().liveness.challenge.enabled
+}
+
+pub fn get_challenge_timeout_secs() -> u64 {
+    config().liveness.challenge.timeout_secs
+}
+
+pub fn get_challenge_min_similarity() -> f32 {
+    config().liveness.challenge.min_similarity
+}
+
+pub fn is_replay_guard_enabled() -> bool {
+    config().replay.enabled
+}
+
+/// The replay guard for login and identify captures, or `None` when it is disabled.
+pub fn get_replay_guard() -> Result<Option<ReplayGuard>> {
+    let replay = &config().replay;
+    if !replay.enabled {
+        return Ok(None);
+    }
+    let path = match &replay.path {
+        Some(path) => path.clone(),
+        None => format!("{}.replay.json", storage_path()?),
+    };
+    Ok(Some(ReplayGuard::new(path.into(), replay.history_size.max(1), replay.max_distance, replay.min_sequence)))
+}
+
+/// The failed login tracker, or `None` when lockouts are disabled.
+pub fn get_lockout_tracker() -> Result<Option<LockoutTracker>> {
+    let lockout = &config().lockout;
+    if !lockout.enabled {
+        return Ok(None);
+    }
+    let path = match &lockout.path {
+        Some(path) => path.clone(),
+        None => format!("{}.attempts.json", storage_path()?),
+    };
+    let seconds = |secs: u64| chrono::Duration::seconds(secs.min(i64::MAX as u64 / 1000) as i64);
+    let policy = LockoutPolicy {
+        max_failures: lockout.max_failures.max(1),
+        window: seconds(lockout.window_secs),
+        cooldown: seconds(lockout.cooldown_secs),
+        max_cooldown: seconds(lockout.max_cooldown_secs),
+    };
+    Ok(Some(LockoutTracker::new(path.into(), policy)))
+}
+
+/// Minimum liveness score of a capture, or `None` when the check is disabled.
+pub fn get_liveness_threshold() -> Option<f32> {
+    let liveness = &config().liveness;
+    liveness.enabled.then_some(liveness.threshold)
 }
@@ -0,0 +1,76 @@
+use anyhow::Result;
+use candle_core::DType;
+use candle_transformers::models::convnext;
+
+/// Architecture and output size of a supported ConvNeXt variant.
+pub struct ModelSpec {
+    pub variant: &'static str,
+    pub embedding_dim: usize,
+    config: fn() -> convnext::Config,
+}
+
+impl ModelSpec {
+    pub fn config(&self) -> convnext::Config {
+        (self.config)()
+    }
+}
+
+// Embedding dimension is the channel count of the last ConvNeXt stage
+const MODELS: &[ModelSpec] = &[
+    ModelSpec { variant: "atto", embedding_dim: 320, config: convnext::Config::atto },
+    ModelSpec { variant: "femto", embedding_dim: 384, config: convnext::Config::femto },
+    ModelSpec { variant: "pico", embedding_dim: 512, config: convnext::Config::pico },
+    ModelSpec { variant: "nano", embedding_dim: 640, config: convnext::Config::nano },
+    ModelSpec { variant: "tiny", embedding_dim: 768, config: convnext::Config::tiny },
+    ModelSpec { variant: "small", embedding_dim: 768, config: convnext::Config::small },
+    ModelSpec { variant: "base", embedding_dim: 1024, config: convnext::Config::base },
+    ModelSpec { variant: "large", embedding_dim: 1536, config: convnext::Config::large },
+];
+
+/// Resolves a hub model name such as `timm/convnext_tiny.fb_in1k` to its ConvNeXt variant.
+pub fn lookup(model_name: &str) -> Result<&'static ModelSpec> {
+    let repo = model_name.rsplit('/').next().unwrap_or(model_name);
+    let variant = repo
+        .strip_prefix("convnext_")
+        .and_then(|rest| rest.split(['.', '_']).next())
+        .unwrap_or_default();
+
+    MODELS
+        .iter()
+        .find(|spec| spec.variant == variant)
+        .ok_or_else(|| {
+            let supported: Vec<&str> = MODELS.iter().map(|spec| spec.variant).collect();
+            anyhow::anyhow!(
+                "Unsupported model '{model_name}', expected a ConvNeXt variant ({})",
+                supported.join(", ")
+            )
+        })
+}
+
+pub fn parse_dtype(name: &str) -> Result<DType> {
+    match name.to_ascii_lowercase().as_str() {
+        "f32" => Ok(DType::F32),
+        "f16" => Ok(DType::F16),
+        "bf16" => Ok(DType::BF16),
+        _ => Err(anyhow::anyhow!("Unsupported model dtype '{name}', expected f32, f16 or bf16")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_resolves_variant_from_hub_name() -> Result<()> {
+        assert_eq!(lookup("timm/convnext_atto.d2_in1k")?.variant, "atto");
+        assert_eq!(lookup("timm/convnext_tiny.fb_in22k_ft_in1k")?.embedding_dim, 768);
+        assert_eq!(lookup("convnext_large")?.embedding_dim, 1536);
+        Ok(())
+    }
+
+    #[test]
+    fn lookup_rejects_unknown_models() {
+        assert!(lookup("timm/resnet50.a1_in1k").is_err());
+        assert!(lookup("timm/convnext_gigantic.in1k").is_err());
+    }
+}
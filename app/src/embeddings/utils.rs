@@ -1,10 +1,59 @@
 use candle_core::{DType, Device, Tensor};
 use candle_nn::{Module, VarBuilder, Func};
-use anyhow::Result;
+use anyhow::{Context, Result};
 use candle_transformers::models::{convnext};
+use super::registry::{self, ModelSpec};
+use hf_hub::Cache;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use crate::image_utils::alignment::{self, FaceAligner};
+use crate::image_utils::face_detection::{self, FaceDetector};
+use crate::image_utils::imagenet;
+use crate::storage::vector_storage::ModelFingerprint;
+use crate::config::{
+    get_aligned_size, get_alignment_weights, get_face_detection_weights, get_face_iou_threshold,
+    get_face_margin, get_face_score_threshold, get_model_cache_dir, get_model_dtype, get_model_path,
+    is_alignment_enabled, is_face_detection_enabled, is_model_offline,
+};
 
+const MODEL_WEIGHTS_FILE: &str = "model.safetensors";
 
-pub fn compute_embeddings(model: &Func, image: &Tensor) -> Result<Tensor> {
+/// A loaded ConvNeXt backbone together with the dtype it computes in and the
+/// optional face detector and aligner that prepare its inputs.
+pub struct EmbeddingModel {
+    model: Func<'static>,
+    dtype: DType,
+    spec: &'static ModelSpec,
+    fingerprint: ModelFingerprint,
+    face_detector: Option<FaceDetector>,
+    face_aligner: Option<FaceAligner>,
+}
+
+impl EmbeddingModel {
+    pub fn embedding_dim(&self) -> usize {
+        self.spec.embedding_dim
+    }
+
+    pub fn fingerprint(&self) -> &ModelFingerprint {
+        &self.fingerprint
+    }
+
+    pub fn face_detector(&self) -> Option<&FaceDetector> {
+        self.face_detector.as_ref()
+    }
+
+    pub fn face_aligner(&self) -> Option<&FaceAligner> {
+        self.face_aligner.as_ref()
+    }
+}
+
+
+pub fn compute_embeddings(model: &EmbeddingModel, image: &Tensor) -> Result<Tensor> {
     // Check if input is a single image (3D: [C, H, W]) or batch (4D: [N, C, H, W])
     let input = if image.dims().len() == 3 {
         // Single image: add batch dimension
@@ -14,21 +63,205 @@ pub fn compute_embeddings(model: &Func, image: &Tensor) -> Result<Tensor> {
         image.clone()
     };
 
-    let input = input.to_dtype(DType::F16)?;
-    let embeddings = model.forward(&input)?;
+    let input = input.to_dtype(model.dtype)?;
+    let embeddings = model.model.forward(&input)?;
+    let dim = embeddings.dim(1)?;
+    if dim != model.embedding_dim() {
+        anyhow::bail!("ConvNeXt-{} produced {dim}-d embeddings, expected {}", model.spec.variant, model.embedding_dim());
+    }
     Ok(embeddings.to_dtype(DType::F32)?)
 }
 
-pub fn build_model(model_name: &str) -> Result<Func<'static>> {
+pub fn build_model(model_name: &str) -> Result<EmbeddingModel> {
     let device = &Device::Cpu;
-    let model_file = {
-        let api = hf_hub::api::sync::Api::new()?;
-        let api = api.model(model_name.to_string());
-        api.get("model.safetensors")?
+    let spec = registry::lookup(model_name)?;
+    let dtype = registry::parse_dtype(get_model_dtype())?;
+    let model_file = resolve_model_file(model_name)?;
+    let fingerprint = fingerprint_for(model_name, spec, &model_file)?;
+
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[model_file], dtype, device)? };
+    let model = convnext::convnext_no_final_layer(&spec.config(), vb)
+        .with_context(|| format!("Failed to build ConvNeXt-{} from '{model_name}'", spec.variant))?;
+
+    println!("[*] Loaded ConvNeXt-{} ({:?}, {}-d embeddings)", spec.variant, dtype, spec.embedding_dim);
+
+    let face_detector = if is_face_detection_enabled() {
+        let detector = FaceDetector::load(
+            get_face_detection_weights()?,
+            get_face_score_threshold(),
+            get_face_iou_threshold(),
+            get_face_margin(),
+        )?;
+        println!("[*] Loaded face detector {}", face_detection::DETECTOR_NAME);
+        Some(detector)
+    } else {
+        None
+    };
+
+    let face_aligner = if is_alignment_enabled() {
+        if face_detector.is_none() {
+            anyhow::bail!("alignment.enabled requires face_detection.enabled");
+        }
+        let aligner = FaceAligner::load(get_alignment_weights()?, get_aligned_size())?;
+        println!("[*] Loaded landmark model {}", alignment::ALIGNER_NAME);
+        Some(aligner)
+    } else {
+        None
+    };
+
+    Ok(EmbeddingModel { model, dtype, spec, fingerprint, face_detector, face_aligner })
+}
+
+/// Fingerprint of the configured model without building it, e.g. for stale-template reports.
+pub fn current_fingerprint(model_name: &str) -> Result<ModelFingerprint> {
+    let spec = registry::lookup(model_name)?;
+    let model_file = resolve_model_file(model_name)?;
+    fingerprint_for(model_name, spec, &model_file)
+}
+
+fn fingerprint_for(model_name: &str, spec: &ModelSpec, model_file: &Path) -> Result<ModelFingerprint> {
+    Ok(ModelFingerprint {
+        model_id: model_name.to_string(),
+        weights_sha256: sha256_file(model_file)?,
+        preprocessing: preprocessing_descriptor(),
+        embedding_dim: spec.embedding_dim,
+    })
+}
+
+// Face cropping and alignment change what the backbone sees, so they are part of the fingerprint
+fn preprocessing_descriptor() -> String {
+    let mut descriptor = imagenet::preprocessing_descriptor();
+    if is_face_detection_enabled() {
+        descriptor.push_str(&format!(";face_crop={}.v{}", face_detection::DETECTOR_NAME, face_detection::DECODER_VERSION));
+        if is_alignment_enabled() {
+            descriptor.push_str(&format!(";align={};size={}", alignment::ALIGNER_NAME, get_aligned_size()));
+        } else {
+            descriptor.push_str(&format!(";margin={:.2}", get_face_margin()));
+        }
+    }
+    descriptor
+}
+
+// Digest of a weights file as of its size and modification time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CachedDigest {
+    size: u64,
+    modified_nanos: u128,
+    sha256: String,
+}
+
+// Digests computed by this process, for weights directories that aren't writable
+static DIGESTS: Mutex<Vec<(PathBuf, CachedDigest)>> = Mutex::new(Vec::new());
+
+/// SHA-256 of a weights file. Hashing hundreds of MB on every command is slow,
+/// so the digest is kept in a `<file>.sha256` sidecar (and in memory) and
+/// reused while the file's size and modification time are unchanged.
+fn sha256_file(path: &Path) -> Result<String> {
+    // Hub snapshots are symlinks into the blob store; key on the blob itself
+    let path = fs::canonicalize(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+    let metadata = fs::metadata(&path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+    let modified_nanos = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    let current = |digest: &CachedDigest| digest.size == metadata.len() && digest.modified_nanos == modified_nanos;
+
+    let mut digests = DIGESTS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some((_, digest)) = digests.iter().find(|(cached, digest)| *cached == path && current(digest)) {
+        return Ok(digest.sha256.clone());
+    }
+    let sidecar = PathBuf::from(format!("{}.sha256", path.display()));
+    let stored = fs::read(&sidecar).ok().and_then(|contents| serde_json::from_slice::<CachedDigest>(&contents).ok());
+    let digest = match stored.filter(|digest| current(digest)) {
+        Some(digest) => digest,
+        None => {
+            let digest = CachedDigest { size: metadata.len(), modified_nanos, sha256: hash_file(&path)? };
+            // Best effort: a read-only cache only costs the hash on the next run
+            if let Ok(contents) = serde_json::to_vec(&digest) {
+                let _ = fs::write(&sidecar, contents);
+            }
+            digest
+        }
+    };
+    digests.retain(|(cached, _)| *cached != path);
+    digests.push((path, digest.clone()));
+    Ok(digest.sha256)
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let file = File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn resolve_model_file(model_name: &str) -> Result<PathBuf> {
+    // An explicit path always wins and never falls back to the network
+    if let Some(path) = get_model_path() {
+        let path = PathBuf::from(path);
+        if !path.is_file() {
+            anyhow::bail!("Model weights not found at '{}' (model.path)", path.display());
+        }
+        return Ok(path);
+    }
+
+    let cache = match get_model_cache_dir() {
+        Some(dir) => Cache::new(PathBuf::from(dir)),
+        None => Cache::from_env(),
     };
 
-    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[model_file], DType::F16, &device)? };
-    let model = convnext::convnext_no_final_layer(&convnext::Config::atto(), vb)?;
+    if is_model_offline() || std::env::var("HF_HUB_OFFLINE").is_ok_and(|v| v == "1") {
+        return cache
+            .model(model_name.to_string())
+            .get(MODEL_WEIGHTS_FILE)
+            .ok_or_else(|| anyhow::anyhow!(
+                "Offline mode: '{MODEL_WEIGHTS_FILE}' for '{model_name}' not found in hub cache '{}'",
+                cache.path().display()
+            ));
+    }
+
+    let api = hf_hub::api::sync::ApiBuilder::from_cache(cache).build()?;
+    let api = api.model(model_name.to_string());
+    api.get(MODEL_WEIGHTS_FILE)
+        .with_context(|| format!("Failed to download '{MODEL_WEIGHTS_FILE}' for '{model_name}'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weight_digests_are_reused_until_the_file_changes() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("face_auth_weights_{}.safetensors", uuid::Uuid::new_v4()));
+        fs::write(&path, b"abc")?;
+        let sidecar = PathBuf::from(format!("{}.sha256", fs::canonicalize(&path)?.display()));
+        let result = (|| {
+            let digest = sha256_file(&path)?;
+            assert_eq!(digest, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+            assert!(sidecar.exists());
+
+            // The sidecar is trusted while the file is unchanged, and rehashed once it changes
+            let mut stored: CachedDigest = serde_json::from_slice(&fs::read(&sidecar)?)?;
+            stored.sha256 = "cached".to_string();
+            fs::write(&sidecar, serde_json::to_vec(&stored)?)?;
+            DIGESTS.lock().unwrap().clear();
+            assert_eq!(sha256_file(&path)?, "cached");
 
-    Ok(model)
+            fs::write(&path, b"abcd")?;
+            assert_eq!(sha256_file(&path)?, "88d4266fd4e6338d13b845fcf289579d209c897823b9217da3e161936f031589");
+            Ok(())
+        })();
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&sidecar);
+        result
+    }
 }
@@ -0,0 +1,324 @@
+use crate::camera::camera_interactions::{average_embedding, capture_samples, CaptureStats};
+use crate::config::{get_lockout_tracker, get_login_threshold, get_threshold_overrides};
+use crate::embeddings::utils::EmbeddingModel;
+use crate::lockout::LockoutTracker;
+use crate::login::{capture_failure, liveness_challenge, liveness_failure, replay_failure, resolve_threshold, Decision, MatchTimings, ThresholdSource};
+use crate::storage::vector_storage::{EmbeddingRecord, EmbeddingStorage};
+use crate::users::ThresholdOverrides;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// A registered user ranked by their best matching template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub user_name: String,
+    pub record_id: String,
+    pub similarity: f32,
+    pub threshold: f32,
+    pub threshold_source: ThresholdSource,
+}
+
+impl Candidate {
+    pub fn passes_threshold(&self) -> bool {
+        self.similarity > self.threshold
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IdentifyResult {
+    /// `Accepted` when the top candidate passed its threshold, `Rejected` for an
+    /// unknown face, `UnknownUser` when nobody is registered, `LockedOut` when the
+    /// top candidate is locked out of login.
+    pub decision: Decision,
+    /// The top-k users, best first, also for a capture refused by the replay
+    /// or liveness checks.
+    pub candidates: Vec<Candidate>,
+    /// `None` when the attempt was decided before capturing.
+    pub capture: Option<CaptureStats>,
+    pub timings: MatchTimings,
+}
+
+impl IdentifyResult {
+    /// The identified user, if the face wasn't rejected as unknown.
+    pub fn identified(&self) -> Option<&Candidate> {
+        match self.decision {
+            Decision::Accepted => self.candidates.first(),
+            _ => None,
+        }
+    }
+}
+
+// Templates fetched from the nearest neighbour search per requested user, as
+// every user has several (the samples and their average)
+const HITS_PER_CANDIDATE: usize = 10;
+const MIN_HITS: usize = 50;
+
+/// Captures a face and searches the stored templates for it. The best user is
+/// only reported when their score passes their login threshold (open-set rejection).
+pub fn identify(model: &EmbeddingModel, storage: &dyn EmbeddingStorage, k: usize) -> Result<IdentifyResult> {
+    println!("[*] Identifying face against all registered users");
+    let start_time = Instant::now();
+    let lockout = get_lockout_tracker()?;
+
+    let mut result = IdentifyResult {
+        decision: Decision::UnknownUser,
+        candidates: Vec::new(),
+        capture: None,
+        timings: MatchTimings::default(),
+    };
+
+    let capture_start = Instant::now();
+    let capture = capture_samples(model, liveness_challenge());
+    result.timings.capture = capture_start.elapsed();
+    let capture = match capture {
+        Ok(capture) => capture,
+        Err(e) => {
+            (result.decision, result.capture) = capture_failure(&e);
+            println!("[!] Identification failed: {}", result.decision);
+            result.timings.total = start_time.elapsed();
+            return Ok(result);
+        }
+    };
+    let live_embedding = average_embedding(&capture.samples)?;
+    result.capture = Some(capture.stats);
+
+    // The replay guard runs first so every capture's frames are recorded
+    let rejection = match replay_failure(&capture.samples)? {
+        Some(decision) => Some(decision),
+        None => result.capture.as_ref().and_then(liveness_failure),
+    };
+
+    // Only templates produced by the current model and preprocessing are comparable.
+    // A refused capture is still matched, so the failure counts against the user it matches.
+    let current = model.fingerprint();
+    let matching_start = Instant::now();
+    let hits = storage.nearest(&live_embedding, (k * HITS_PER_CANDIDATE).max(MIN_HITS), current)?;
+    result.candidates = top_k_users(hits, storage, &get_threshold_overrides()?, k, get_login_threshold())?;
+    result.timings.matching = matching_start.elapsed();
+
+    let now = Utc::now();
+    let locked_until = match (&lockout, result.candidates.first()) {
+        (Some(lockout), Some(best)) if best.passes_threshold() => lockout.locked_until(&best.user_name, now)?,
+        _ => None,
+    };
+    result.decision = match (rejection, result.candidates.first(), locked_until) {
+        // A locked out user can't get in through identify instead of login
+        (_, Some(best), Some(until)) => {
+            println!("[!] Identified '{}', but they are locked out until {}", best.user_name, until.to_rfc3339());
+            Decision::LockedOut { until }
+        }
+        (Some(decision), _, _) => {
+            println!("[!] Identification failed: {decision}");
+            decision
+        }
+        (None, Some(best), None) if best.passes_threshold() => {
+            println!("[+] Identified '{}' with similarity: {:.4} (threshold {:.4}, {})",
+                     best.user_name, best.similarity, best.threshold, best.threshold_source);
+            Decision::Accepted
+        }
+        (None, Some(best), None) => {
+            println!("[!] Unknown face. Best similarity: {:.4}", best.similarity);
+            Decision::Rejected
+        }
+        (None, None, _) => {
+            // Tell an empty gallery apart from one enrolled with another model
+            let all_embeddings = storage.get_all_embeddings()?;
+            let stale = all_embeddings.iter().filter(|record| record.stale_reason(current).is_some()).count();
+            let decision = match all_embeddings.len() {
+                0 => Decision::UnknownUser,
+                count if count == stale => Decision::StaleTemplates { count },
+                _ => Decision::Rejected,
+            };
+            println!("[!] Identification failed: {decision}");
+            decision
+        }
+    };
+
+    if let Some(lockout) = &lockout
+        && let Some(best) = result.candidates.first()
+        && let Some(until) = record_attempt(lockout, &result, now)?
+    {
+        println!("[!] Too many failed attempts for '{}', locked out until {}", best.user_name, until.to_rfc3339());
+    }
+
+    result.timings.total = start_time.elapsed();
+    Ok(result)
+}
+
+/// Counts the attempt against the best candidate, as there is no claimed user,
+/// but only when the face matched them: a success resets their failures, and a
+/// match refused by the replay or liveness checks adds one. An unknown face isn't
+/// charged, or any stranger could lock out whoever they look most like.
+/// Returns the end of the lockout this attempt triggered, if any.
+pub fn record_attempt(lockout: &LockoutTracker, result: &IdentifyResult, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>> {
+    let Some(best) = result.candidates.first().filter(|best| best.passes_threshold()) else {
+        return Ok(None);
+    };
+    // A locked out candidate is `LockedOut`, which isn't a failed attempt
+    if result.decision == Decision::Accepted {
+        lockout.record_success(&best.user_name)?;
+    } else if result.decision.is_failed_attempt() {
+        return lockout.record_failure(&best.user_name, now);
+    }
+    Ok(None)
+}
+
+/// Ranks users by their best template among the search `hits` and keeps the `k` best.
+pub fn top_k_users(
+    hits: Vec<(EmbeddingRecord, f32)>,
+    storage: &dyn EmbeddingStorage,
+    overrides: &ThresholdOverrides,
+    k: usize,
+    default_threshold: f32,
+) -> Result<Vec<Candidate>> {
+    // Best template per user
+    let mut best: BTreeMap<String, (String, f32)> = BTreeMap::new();
+    for (record, similarity) in hits {
+        let entry = best.entry(record.name).or_insert((record.id.clone(), similarity));
+        if similarity > entry.1 {
+            *entry = (record.id, similarity);
+        }
+    }
+
+    // Rank first, so only the k kept users have their templates read for thresholds
+    let mut ranked: Vec<(String, (String, f32))> = best.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.1.total_cmp(&a.1.1));
+    ranked.truncate(k);
+
+    let mut candidates = Vec::with_capacity(ranked.len());
+    for (user_name, (record_id, similarity)) in ranked {
+        // An older version's override may sit on any of the user's templates, stale or not
+        let user_records = storage.get_user_embeddings(&user_name)?;
+        let (threshold, threshold_source) = resolve_threshold(overrides, &user_name, &user_records, default_threshold)?;
+        candidates.push(Candidate { user_name, record_id, similarity, threshold, threshold_source });
+    }
+    Ok(candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::local_file_vector_storage::LocalFileVectorStorage;
+    use crate::lockout::LockoutPolicy;
+    use crate::storage::vector_storage::ModelFingerprint;
+    use chrono::Duration;
+    use std::collections::HashMap;
+
+    fn fingerprint() -> ModelFingerprint {
+        ModelFingerprint {
+            model_id: "test".to_string(),
+            weights_sha256: "0".to_string(),
+            preprocessing: "none".to_string(),
+            embedding_dim: 2,
+        }
+    }
+
+    fn record(name: &str, embedding: Vec<f32>, model: Option<ModelFingerprint>) -> EmbeddingRecord {
+        EmbeddingRecord {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            embedding,
+            created_at: chrono::Utc::now(),
+            metadata: HashMap::new(),
+            model,
+        }
+    }
+
+    #[test]
+    fn users_are_ranked_by_their_best_comparable_template() -> Result<()> {
+        let current = fingerprint();
+        let mut strict = record("carol", vec![1., 0.05], None);
+        strict.metadata.insert("login_threshold".to_string(), "0.9999".to_string());
+        let records = vec![
+            record("alice", vec![0., 1.], Some(current.clone())),
+            record("alice", vec![1., 0.1], Some(current.clone())),
+            record("bob", vec![0.6, 0.8], Some(current.clone())),
+            // Stale templates are never matched, even when they are identical
+            record("dave", vec![1., 0.], None),
+            record("carol", vec![1., 0.05], Some(current.clone())),
+            // Carol's override, set by an older version, sits on a stale template and still applies
+            strict,
+        ];
+
+        let path = std::env::temp_dir().join(format!("face_auth_{}.json", uuid::Uuid::new_v4()));
+        let mut storage = LocalFileVectorStorage::new(path.to_string_lossy().into_owned(), false)?;
+        for record in &records {
+            storage.store_embedding(record.clone())?;
+        }
+        let hits = storage.nearest(&[1., 0.], 10, &current)?;
+        let overrides_path = path.with_extension("thresholds.json");
+        let overrides = ThresholdOverrides::new(overrides_path.clone());
+        let candidates = top_k_users(hits, &storage, &overrides, 2, 0.7);
+        for leftover in [&path, &overrides_path] {
+            let _ = std::fs::remove_file(leftover);
+            let _ = std::fs::remove_file(format!("{}.lock", leftover.display()));
+        }
+        let candidates = candidates?;
+
+        let names: Vec<&str> = candidates.iter().map(|c| c.user_name.as_str()).collect();
+        assert_eq!(names, ["carol", "alice"]);
+        assert_eq!(candidates[1].record_id, records[1].id);
+
+        // Carol is closest but fails her own threshold; alice passes the default one
+        assert!(!candidates[0].passes_threshold());
+        assert_eq!(candidates[0].threshold_source, ThresholdSource::User);
+        assert!(candidates[1].passes_threshold());
+        Ok(())
+    }
+
+    #[test]
+    fn only_matched_faces_count_against_the_best_candidate() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("face_auth_attempts_{}.json", uuid::Uuid::new_v4()));
+        let result = (|| {
+            let tracker = LockoutTracker::new(path.clone(), LockoutPolicy {
+                max_failures: 2,
+                window: Duration::minutes(10),
+                cooldown: Duration::minutes(1),
+                max_cooldown: Duration::minutes(1),
+            });
+            let candidate = |user_name: &str, similarity: f32| Candidate {
+                user_name: user_name.to_string(),
+                record_id: "id".to_string(),
+                similarity,
+                threshold: 0.7,
+                threshold_source: ThresholdSource::Config,
+            };
+            let attempt = |decision: Decision, candidates: Vec<Candidate>| IdentifyResult {
+                decision,
+                candidates,
+                capture: None,
+                timings: MatchTimings::default(),
+            };
+            let refused = Decision::LivenessFailed { score: 0.2, threshold: 0.5 };
+            let now = Utc::now();
+
+            // Unknown faces aren't charged to whoever is closest, however often they try
+            for decision in [Decision::Rejected, Decision::Rejected, refused.clone()] {
+                assert_eq!(record_attempt(&tracker, &attempt(decision, vec![candidate("alice", 0.5)]), now)?, None);
+            }
+            assert_eq!(record_attempt(&tracker, &attempt(refused.clone(), Vec::new()), now)?, None);
+            assert_eq!(tracker.locked_until("alice", now)?, None);
+
+            // A matched face that is refused counts, and a success resets the count
+            record_attempt(&tracker, &attempt(refused.clone(), vec![candidate("alice", 0.9)]), now)?;
+            record_attempt(&tracker, &attempt(Decision::Accepted, vec![candidate("alice", 0.9)]), now)?;
+            let both = vec![candidate("alice", 0.9), candidate("bob", 0.8)];
+            assert_eq!(record_attempt(&tracker, &attempt(refused.clone(), both), now)?, None);
+            let until = record_attempt(&tracker, &attempt(refused.clone(), vec![candidate("alice", 0.9)]), now)?;
+            assert_eq!(until, Some(now + Duration::minutes(1)));
+            assert_eq!(tracker.locked_until("bob", now)?, None);
+
+            // Attempts while locked out don't extend the lockout
+            let locked = Decision::LockedOut { until: now + Duration::minutes(1) };
+            record_attempt(&tracker, &attempt(locked.clone(), vec![candidate("alice", 0.9)]), now)?;
+            record_attempt(&tracker, &attempt(locked, vec![candidate("alice", 0.9)]), now)?;
+            assert_eq!(tracker.locked_until("alice", now)?, until);
+            Ok(())
+        })();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}.lock", path.display()));
+        result
+    }
+}
@@ -1 +1,7 @@
-pub mod imagenet;
\ No newline at end of file
+pub mod imagenet;
+pub mod face_detection;
+pub mod alignment;
+pub mod quality;
+pub mod liveness;
+pub mod challenge;
+pub mod frame_hash;
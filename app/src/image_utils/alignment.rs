@@ -0,0 +1,288 @@
+//! Five-point landmark alignment.
+//!
+//! Landmarks come from MTCNN's O-Net. Weights are facenet-pytorch's `onet.pt`
+//! converted to safetensors, keeping the PyTorch parameter names (`conv1`..`conv4`,
+//! `prelu1`..`prelu5`, `dense5`, `dense6_1`..`dense6_3`). The face is then warped
+//! with a similarity transform onto the ArcFace reference points.
+use anyhow::{Context, Result};
+use candle_core::{DType, Device, Module, Tensor, D};
+use candle_nn::{conv2d, linear, prelu, Func, VarBuilder};
+use image::{DynamicImage, Rgb, RgbImage};
+use super::face_detection::FaceBox;
+
+pub const ALIGNER_NAME: &str = "mtcnn-onet-5pt";
+
+const ONET_INPUT_SIZE: u32 = 48;
+// Reference landmark positions in a 112x112 crop: left eye, right eye, nose tip,
+// left mouth corner, right mouth corner (as seen in the image)
+const REFERENCE_SIZE: f32 = 112.;
+const REFERENCE_LANDMARKS: [[f32; 2]; 5] = [
+    [38.2946, 51.6963],
+    [73.5318, 51.5014],
+    [56.0252, 71.7366],
+    [41.5493, 92.3655],
+    [70.7299, 92.2041],
+];
+
+/// Five facial landmarks in frame pixel coordinates, in the same order as the reference points.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaceLandmarks {
+    pub points: [[f32; 2]; 5],
+}
+
+/// A 2D similarity transform (rotation, uniform scale and translation):
+/// `x' = a*x - b*y + tx`, `y' = b*x + a*y + ty`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimilarityTransform {
+    a: f32,
+    b: f32,
+    tx: f32,
+    ty: f32,
+}
+
+impl SimilarityTransform {
+    /// Least-squares (Umeyama) estimate of the transform mapping `src` onto `dst`.
+    /// Returns `None` when the source points all coincide.
+    pub fn estimate(src: &[[f32; 2]], dst: &[[f32; 2]]) -> Option<Self> {
+        let n = src.len().min(dst.len());
+        if n < 2 {
+            return None;
+        }
+        let mean = |points: &[[f32; 2]]| {
+            let sum = points[..n].iter().fold([0., 0.], |acc, p| [acc[0] + p[0], acc[1] + p[1]]);
+            [sum[0] / n as f32, sum[1] / n as f32]
+        };
+        let (src_mean, dst_mean) = (mean(src), mean(dst));
+
+        let (mut dot, mut cross, mut norm) = (0f32, 0f32, 0f32);
+        for (s, d) in src[..n].iter().zip(&dst[..n]) {
+            let (sx, sy) = (s[0] - src_mean[0], s[1] - src_mean[1]);
+            let (dx, dy) = (d[0] - dst_mean[0], d[1] - dst_mean[1]);
+            dot += sx * dx + sy * dy;
+            cross += sx * dy - sy * dx;
+            norm += sx * sx + sy * sy;
+        }
+        if norm <= f32::EPSILON {
+            return None;
+        }
+
+        let (a, b) = (dot / norm, cross / norm);
+        Some(SimilarityTransform {
+            a,
+            b,
+            tx: dst_mean[0] - (a * src_mean[0] - b * src_mean[1]),
+            ty: dst_mean[1] - (b * src_mean[0] + a * src_mean[1]),
+        })
+    }
+
+    pub fn apply(&self, point: [f32; 2]) -> [f32; 2] {
+        [
+            self.a * point[0] - self.b * point[1] + self.tx,
+            self.b * point[0] + self.a * point[1] + self.ty,
+        ]
+    }
+
+    pub fn inverse(&self) -> Self {
+        let det = self.a * self.a + self.b * self.b;
+        let (a, b) = (self.a / det, -self.b / det);
+        SimilarityTransform {
+            a,
+            b,
+            tx: -(a * self.tx - b * self.ty),
+            ty: -(b * self.tx + a * self.ty),
+        }
+    }
+}
+
+pub struct FaceAligner {
+    onet: Func<'static>,
+    output_size: u32,
+}
+
+impl FaceAligner {
+    pub fn load(weights_path: &str, output_size: u32) -> Result<Self> {
+        if !std::path::Path::new(weights_path).is_file() {
+            anyhow::bail!("Landmark model weights not found at '{weights_path}' (alignment.weights_path)");
+        }
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights_path], DType::F32, &Device::Cpu)? };
+        let onet = onet(vb).with_context(|| format!("Failed to build {ALIGNER_NAME} from '{weights_path}'"))?;
+
+        Ok(FaceAligner { onet, output_size })
+    }
+
+    /// Locates the five landmarks inside a detected face box.
+    pub fn detect_landmarks(&self, image: &DynamicImage, face: &FaceBox) -> Result<FaceLandmarks> {
+        // O-Net expects a square box around the face
+        let side = face.width().max(face.height()).max(1.);
+        let x0 = (face.x1 + face.x2 - side) / 2.;
+        let y0 = (face.y1 + face.y2 - side) / 2.;
+        let scale = ONET_INPUT_SIZE as f32 / side;
+        let to_input = SimilarityTransform { a: scale, b: 0., tx: -x0 * scale, ty: -y0 * scale };
+
+        let patch = warp(&image.to_rgb8(), &to_input, ONET_INPUT_SIZE);
+        let output = self.onet.forward(&onet_input(&patch)?)?.squeeze(0)?.to_vec1::<f32>()?;
+
+        // Landmarks are the last ten outputs: five x then five y, relative to the box
+        let offsets = &output[6..16];
+        let mut points = [[0f32; 2]; 5];
+        for (i, point) in points.iter_mut().enumerate() {
+            *point = [x0 + offsets[i] * side, y0 + offsets[i + 5] * side];
+        }
+        Ok(FaceLandmarks { points })
+    }
+
+    /// Warps the face so its landmarks land on the reference points of an
+    /// `output_size` square crop.
+    pub fn align(&self, image: &DynamicImage, landmarks: &FaceLandmarks) -> Result<DynamicImage> {
+        let transform = alignment_transform(landmarks, self.output_size)
+            .ok_or_else(|| anyhow::anyhow!("Degenerate landmarks, can't align face"))?;
+        Ok(DynamicImage::ImageRgb8(warp(&image.to_rgb8(), &transform, self.output_size)))
+    }
+
+    pub fn align_face(&self, image: &DynamicImage, face: &FaceBox) -> Result<(FaceLandmarks, DynamicImage)> {
+        let landmarks = self.detect_landmarks(image, face)?;
+        let aligned = self.align(image, &landmarks)?;
+        Ok((landmarks, aligned))
+    }
+}
+
+/// Transform from frame coordinates to an aligned `output_size` crop.
+pub fn alignment_transform(landmarks: &FaceLandmarks, output_size: u32) -> Option<SimilarityTransform> {
+    let scale = output_size as f32 / REFERENCE_SIZE;
+    let reference = REFERENCE_LANDMARKS.map(|[x, y]| [x * scale, y * scale]);
+    SimilarityTransform::estimate(&landmarks.points, &reference)
+}
+
+/// Renders a `size` square image whose pixel `q` is sampled bilinearly from
+/// `image` at `transform⁻¹(q)`. Pixels outside the source are black.
+fn warp(image: &RgbImage, transform: &SimilarityTransform, size: u32) -> RgbImage {
+    let inverse = transform.inverse();
+    let (width, height) = (image.width() as i64, image.height() as i64);
+    let pixel = |x: i64, y: i64| -> [f32; 3] {
+        if x < 0 || y < 0 || x >= width || y >= height {
+            return [0.; 3];
+        }
+        image.get_pixel(x as u32, y as u32).0.map(f32::from)
+    };
+
+    RgbImage::from_fn(size, size, |u, v| {
+        // Sample at pixel centres
+        let [x, y] = inverse.apply([u as f32 + 0.5, v as f32 + 0.5]);
+        let (x, y) = (x - 0.5, y - 0.5);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let (p00, p10, p01, p11) = (pixel(x0, y0), pixel(x0 + 1, y0), pixel(x0, y0 + 1), pixel(x0 + 1, y0 + 1));
+        let mut out = [0u8; 3];
+        for c in 0..3 {
+            let top = p00[c] * (1. - fx) + p10[c] * fx;
+            let bottom = p01[c] * (1. - fx) + p11[c] * fx;
+            out[c] = (top * (1. - fy) + bottom * fy).round().clamp(0., 255.) as u8;
+        }
+        Rgb(out)
+    })
+}
+
+fn onet_input(patch: &RgbImage) -> Result<Tensor> {
+    let size = ONET_INPUT_SIZE as usize;
+    let data = Tensor::from_vec(patch.as_raw().clone(), (size, size, 3), &Device::Cpu)?
+        .permute((2, 0, 1))?
+        .to_dtype(DType::F32)?;
+    Ok(((data - 127.5)? * 0.0078125)?.unsqueeze(0)?)
+}
+
+// PyTorch's `MaxPool2d(ceil_mode=True)`: pad the bottom/right edge so the last
+// partial window is kept; repeating the edge value doesn't change its maximum
+fn max_pool_ceil(xs: &Tensor, kernel: usize, stride: usize) -> candle_core::Result<Tensor> {
+    let (_, _, h, w) = xs.dims4()?;
+    let pad = |n: usize| ((n - kernel).div_ceil(stride)) * stride + kernel - n;
+    xs.pad_with_same(2, 0, pad(h))?
+        .pad_with_same(3, 0, pad(w))?
+        .max_pool2d_with_stride(kernel, stride)
+}
+
+/// Builds O-Net; the output is (N, 16) holding the two face probabilities,
+/// four box offsets and ten landmark coordinates.
+fn onet(vb: VarBuilder) -> Result<Func<'static>> {
+    let conv1 = conv2d(3, 32, 3, Default::default(), vb.pp("conv1"))?;
+    let prelu1 = prelu(Some(32), vb.pp("prelu1"))?;
+    let conv2 = conv2d(32, 64, 3, Default::default(), vb.pp("conv2"))?;
+    let prelu2 = prelu(Some(64), vb.pp("prelu2"))?;
+    let conv3 = conv2d(64, 64, 3, Default::default(), vb.pp("conv3"))?;
+    let prelu3 = prelu(Some(64), vb.pp("prelu3"))?;
+    let conv4 = conv2d(64, 128, 2, Default::default(), vb.pp("conv4"))?;
+    let prelu4 = prelu(Some(128), vb.pp("prelu4"))?;
+    let dense5 = linear(1152, 256, vb.pp("dense5"))?;
+    let prelu5 = prelu(Some(256), vb.pp("prelu5"))?;
+    let dense6_1 = linear(256, 2, vb.pp("dense6_1"))?;
+    let dense6_2 = linear(256, 4, vb.pp("dense6_2"))?;
+    let dense6_3 = linear(256, 10, vb.pp("dense6_3"))?;
+
+    Ok(Func::new(move |xs| {
+        let xs = max_pool_ceil(&xs.apply(&conv1)?.apply(&prelu1)?, 3, 2)?;
+        let xs = max_pool_ceil(&xs.apply(&conv2)?.apply(&prelu2)?, 3, 2)?;
+        let xs = max_pool_ceil(&xs.apply(&conv3)?.apply(&prelu3)?, 2, 2)?;
+        let xs = xs.apply(&conv4)?.apply(&prelu4)?;
+
+        // The original Caffe weights flatten in (W, H, C) order
+        let n = xs.dim(0)?;
+        let xs = xs.permute((0, 3, 2, 1))?.contiguous()?.reshape((n, ()))?;
+        let xs = xs.apply(&dense5)?.apply(&prelu5)?;
+
+        let scores = candle_nn::ops::softmax(&xs.apply(&dense6_1)?, D::Minus1)?;
+        Tensor::cat(&[scores, xs.apply(&dense6_2)?, xs.apply(&dense6_3)?], D::Minus1)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_nn::VarMap;
+
+    #[test]
+    fn estimate_recovers_similarity_transform() {
+        let expected = SimilarityTransform { a: 1.2, b: -0.4, tx: 15., ty: -7. };
+        let dst: Vec<[f32; 2]> = REFERENCE_LANDMARKS.iter().map(|p| expected.apply(*p)).collect();
+
+        let estimated = SimilarityTransform::estimate(&REFERENCE_LANDMARKS, &dst).expect("transform");
+        for (p, q) in REFERENCE_LANDMARKS.iter().zip(&dst) {
+            let mapped = estimated.apply(*p);
+            assert!((mapped[0] - q[0]).abs() < 1e-3 && (mapped[1] - q[1]).abs() < 1e-3);
+        }
+
+        let round_trip = estimated.inverse().apply(estimated.apply([3., 4.]));
+        assert!((round_trip[0] - 3.).abs() < 1e-4 && (round_trip[1] - 4.).abs() < 1e-4);
+        assert!(SimilarityTransform::estimate(&[[1., 1.]; 5], &REFERENCE_LANDMARKS).is_none());
+    }
+
+    #[test]
+    fn align_maps_landmarks_onto_reference_points() -> Result<()> {
+        // A face rotated by 30 degrees and shrunk, marked by a white pixel at the left eye
+        let tilt = SimilarityTransform { a: 0.5 * 30f32.to_radians().cos(), b: 0.5 * 30f32.to_radians().sin(), tx: 40., ty: 20. };
+        let landmarks = FaceLandmarks { points: REFERENCE_LANDMARKS.map(|p| tilt.apply(p)) };
+        let [ex, ey] = landmarks.points[0];
+        let mut frame = RgbImage::new(160, 120);
+        frame.put_pixel(ex as u32, ey as u32, Rgb([255, 255, 255]));
+
+        let transform = alignment_transform(&landmarks, 112).expect("transform");
+        // Undoes the tilt: rotated back by 30 degrees and scaled up twice
+        assert!((transform.b.atan2(transform.a).to_degrees() + 30.).abs() < 1e-2);
+        assert!((transform.a.hypot(transform.b) - 2.).abs() < 1e-3);
+
+        let aligned = warp(&frame, &transform, 112);
+        let [rx, ry] = transform.apply([ex.floor() + 0.5, ey.floor() + 0.5]);
+        assert!(aligned.get_pixel(rx as u32, ry as u32).0[0] > 100);
+        Ok(())
+    }
+
+    #[test]
+    fn onet_outputs_scores_boxes_and_landmarks() -> Result<()> {
+        let varmap = VarMap::new();
+        let model = onet(VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu))?;
+
+        let input = onet_input(&RgbImage::new(ONET_INPUT_SIZE, ONET_INPUT_SIZE))?;
+        assert_eq!(model.forward(&input)?.dims(), &[1, 16]);
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! Active liveness: the user is asked for a random action (turn the head,
+//! blink) and the landmarks of the following frames must show it.
+use image::GrayImage;
+use std::fmt;
+use super::alignment::FaceLandmarks;
+use super::face_detection::FaceBox;
+
+// Nose offset from the eye midpoint, in eye distances, that counts as a turn
+// away from where the head started. A flat photo turned the same way keeps the
+// ratio, since its nose and eyes are foreshortened alike.
+const TURN_DELTA: f32 = 0.2;
+// Eye contrast relative to open eyes below which they count as closed, and
+// above which they count as open again
+const CLOSED_RATIO: f32 = 0.6;
+const OPEN_RATIO: f32 = 0.8;
+// Half-side of the patch around each eye landmark, in eye distances
+const EYE_PATCH: f32 = 0.2;
+const REQUIRED_BLINKS: usize = 2;
+// Largest move of the face box centre between two tracked frames, in face
+// widths, and the largest change of its width by either factor
+const MAX_TRACK_SHIFT: f32 = 0.5;
+const MAX_TRACK_SCALE: f32 = 1.5;
+
+/// An action the user is asked to perform in front of the camera.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Challenge {
+    /// Towards the user's own left, i.e. the right of an unmirrored camera image.
+    TurnHeadLeft,
+    TurnHeadRight,
+    BlinkTwice,
+}
+
+impl fmt::Display for Challenge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Challenge::TurnHeadLeft => write!(f, "turn your head left"),
+            Challenge::TurnHeadRight => write!(f, "turn your head right"),
+            Challenge::BlinkTwice => write!(f, "blink twice"),
+        }
+    }
+}
+
+impl Challenge {
+    const ALL: [Challenge; 3] = [Challenge::TurnHeadLeft, Challenge::TurnHeadRight, Challenge::BlinkTwice];
+
+    /// A challenge the user can't know in advance.
+    pub fn random() -> Self {
+        let bytes = uuid::Uuid::new_v4().into_bytes();
+        Self::ALL[bytes[0] as usize % Self::ALL.len()]
+    }
+}
+
+/// Head pose and eye state of one frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Observation {
+    /// Nose offset from the eye midpoint in eye distances, positive towards the
+    /// right of the image.
+    pub yaw: f32,
+    /// Luma standard deviation around the eyes; drops when the eyelids close.
+    pub eye_contrast: f32,
+}
+
+/// Measures one frame from its landmarks (left eye, right eye, nose, ...).
+pub fn observe(gray: &GrayImage, landmarks: &FaceLandmarks) -> Observation {
+    let [left_eye, right_eye, nose, ..] = landmarks.points;
+    let eye_distance = ((right_eye[0] - left_eye[0]).powi(2) + (right_eye[1] - left_eye[1]).powi(2))
+        .sqrt()
+        .max(1.);
+    let midpoint = (left_eye[0] + right_eye[0]) / 2.;
+
+    let half_side = EYE_PATCH * eye_distance;
+    let eye_contrast = (patch_deviation(gray, left_eye, half_side) + patch_deviation(gray, right_eye, half_side)) / 2.;
+    Observation { yaw: (nose[0] - midpoint) / eye_distance, eye_contrast }
+}
+
+// Standard deviation of the luma in the square of `half_side` around `centre`
+fn patch_deviation(gray: &GrayImage, centre: [f32; 2], half_side: f32) -> f32 {
+    let clamp_x = |x: f32| (x.max(0.) as u32).min(gray.width());
+    let clamp_y = |y: f32| (y.max(0.) as u32).min(gray.height());
+    let (x0, x1) = (clamp_x(centre[0] - half_side), clamp_x(centre[0] + half_side));
+    let (y0, y1) = (clamp_y(centre[1] - half_side), clamp_y(centre[1] + half_side));
+
+    let values: Vec<f32> = (y0..y1)
+        .flat_map(|y| (x0..x1).map(move |x| (x, y)))
+        .map(|(x, y)| gray.get_pixel(x, y).0[0] as f32)
+        .collect();
+    if values.is_empty() {
+        return 0.;
+    }
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    (values.iter().map(|value| (value - mean).powi(2)).sum::<f32>() / values.len() as f32).sqrt()
+}
+
+/// Whether `next` can be the face of `previous` a moment later. Swapping in
+/// another face, e.g. a photo, shows up as a jump of the box or a gap without one.
+pub fn same_track(previous: &FaceBox, next: &FaceBox) -> bool {
+    let width = previous.width().max(1.);
+    let shift_x = (next.x1 + next.x2 - previous.x1 - previous.x2) / 2.;
+    let shift_y = (next.y1 + next.y2 - previous.y1 - previous.y2) / 2.;
+    let scale = next.width().max(1.) / width;
+    (shift_x * shift_x + shift_y * shift_y).sqrt() <= MAX_TRACK_SHIFT * width
+        && (1. / MAX_TRACK_SCALE..=MAX_TRACK_SCALE).contains(&scale)
+}
+
+/// Whether the observations, in capture order, show the requested action.
+pub fn completed(challenge: Challenge, observations: &[Observation]) -> bool {
+    let Some(first) = observations.first() else {
+        return false;
+    };
+    match challenge {
+        Challenge::TurnHeadLeft => observations.iter().any(|o| o.yaw - first.yaw >= TURN_DELTA),
+        Challenge::TurnHeadRight => observations.iter().any(|o| first.yaw - o.yaw >= TURN_DELTA),
+        Challenge::BlinkTwice => count_blinks(observations) >= REQUIRED_BLINKS,
+    }
+}
+
+// Closed-then-open transitions, relative to the contrast of open eyes
+fn count_blinks(observations: &[Observation]) -> usize {
+    // Eyes are open most of the time, so a high percentile stands for open
+    let mut contrasts: Vec<f32> = observations.iter().map(|o| o.eye_contrast).collect();
+    contrasts.sort_by(f32::total_cmp);
+    let open = contrasts[contrasts.len() * 3 / 4];
+    if open <= 0. {
+        return 0;
+    }
+
+    // Eyes must be seen open before a closure counts
+    let (mut seen_open, mut closed, mut blinks) = (false, false, 0);
+    for observation in observations {
+        let ratio = observation.eye_contrast / open;
+        if ratio >= OPEN_RATIO {
+            if closed {
+                blinks += 1;
+            }
+            seen_open = true;
+            closed = false;
+        } else if ratio < CLOSED_RATIO && seen_open {
+            closed = true;
+        }
+    }
+    blinks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    fn observation(yaw: f32, eye_contrast: f32) -> Observation {
+        Observation { yaw, eye_contrast }
+    }
+
+    #[test]
+    fn head_turns_are_measured_from_the_starting_pose() {
+        let turning_left: Vec<Observation> = [0.05, 0.1, 0.2, 0.3].map(|yaw| observation(yaw, 30.)).to_vec();
+        assert!(completed(Challenge::TurnHeadLeft, &turning_left));
+        assert!(!completed(Challenge::TurnHeadRight, &turning_left));
+
+        // Already turned at the start, so nothing changes
+        let held: Vec<Observation> = [0.3, 0.32, 0.29].map(|yaw| observation(yaw, 30.)).to_vec();
+        assert!(!completed(Challenge::TurnHeadLeft, &held));
+        assert!(!completed(Challenge::TurnHeadLeft, &[]));
+    }
+
+    #[test]
+    fn blinks_need_open_closed_open() {
+        let blinks = |contrasts: &[f32]| {
+            let observations: Vec<Observation> = contrasts.iter().map(|&c| observation(0., c)).collect();
+            completed(Challenge::BlinkTwice, &observations)
+        };
+        assert!(blinks(&[30., 31., 10., 29., 30., 12., 11., 30., 30.]));
+        // One blink, and jitter that never looks closed
+        assert!(!blinks(&[30., 31., 10., 29., 30., 30., 30., 30.]));
+        assert!(!blinks(&[30., 27., 31., 26., 30., 28., 30., 29.]));
+        // Closed at the start isn't a blink
+        assert!(!blinks(&[10., 30., 30., 12., 30., 30., 30., 30.]));
+    }
+
+    #[test]
+    fn tracks_follow_small_moves_only() {
+        let face = |x1: f32, width: f32| FaceBox { x1, y1: 100., x2: x1 + width, y2: 100. + width, score: 0.9 };
+        let start = face(100., 100.);
+        assert!(same_track(&start, &face(130., 110.)));
+        // Another face held up elsewhere in the frame, or much closer
+        assert!(!same_track(&start, &face(300., 100.)));
+        assert!(!same_track(&start, &face(80., 180.)));
+    }
+
+    #[test]
+    fn observations_come_from_the_landmarks() {
+        // Dark pupils on skin: contrast around the eyes, none once they are "closed"
+        let eyes = |open: bool| {
+            GrayImage::from_fn(100, 100, |x, y| {
+                let pupil = open && y.abs_diff(40) < 3 && (x.abs_diff(30) < 3 || x.abs_diff(70) < 3);
+                Luma([if pupil { 20 } else { 160 }])
+            })
+        };
+        let landmarks = FaceLandmarks { points: [[30., 40.], [70., 40.], [58., 60.], [35., 80.], [65., 80.]] };
+
+        let open = observe(&eyes(true), &landmarks);
+        let closed = observe(&eyes(false), &landmarks);
+        assert!((open.yaw - 0.2).abs() < 1e-6);
+        assert!(open.eye_contrast > 20.);
+        assert_eq!(closed.eye_contrast, 0.);
+    }
+}
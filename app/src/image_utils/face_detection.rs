@@ -0,0 +1,355 @@
+//! UltraFace ("version-slim-320") face detector running on candle.
+//!
+//! Weights are the upstream `version-slim-320.pth` checkpoint converted to
+//! safetensors, keeping the PyTorch parameter names (`base_net.*`, `extras.*`,
+//! `classification_headers.*`, `regression_headers.*`).
+use anyhow::{Context, Result};
+use candle_core::{DType, Device, Module, Tensor, D};
+use candle_nn::{batch_norm, conv2d, conv2d_no_bias, Conv2dConfig, Func, VarBuilder};
+use image::DynamicImage;
+
+pub const DETECTOR_NAME: &str = "ultraface-slim-320";
+/// Bumped when box decoding changes, so templates cropped by an older decoder
+/// show up as stale.
+pub const DECODER_VERSION: u32 = 2;
+
+const INPUT_WIDTH: usize = 320;
+const INPUT_HEIGHT: usize = 240;
+const BASE_CHANNELS: usize = 16;
+const CENTER_VARIANCE: f32 = 0.1;
+const SIZE_VARIANCE: f32 = 0.2;
+// Prior box sizes in input pixels for each of the four detection heads
+const MIN_BOXES: [&[f32]; 4] = [&[10., 16., 24.], &[32., 48.], &[64., 96.], &[128., 192., 256.]];
+const FEATURE_MAPS: [(usize, usize); 4] = [(40, 30), (20, 15), (10, 8), (5, 4)];
+// Input pixels per feature map cell. Prior centres are spaced by these, as
+// upstream does: 240 / 32 = 7.5 rows rather than the 8 rows of the map.
+const SHRINKAGES: [usize; 4] = [8, 16, 32, 64];
+
+/// A detected face in frame pixel coordinates.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaceBox {
+    pub x1: f32,
+    pub y1: f32,
+    pub x2: f32,
+    pub y2: f32,
+    pub score: f32,
+}
+
+impl FaceBox {
+    pub fn width(&self) -> f32 {
+        (self.x2 - self.x1).max(0.)
+    }
+
+    pub fn height(&self) -> f32 {
+        (self.y2 - self.y1).max(0.)
+    }
+
+    pub fn area(&self) -> f32 {
+        self.width() * self.height()
+    }
+
+    fn iou(&self, other: &FaceBox) -> f32 {
+        let overlap = FaceBox {
+            x1: self.x1.max(other.x1),
+            y1: self.y1.max(other.y1),
+            x2: self.x2.min(other.x2),
+            y2: self.y2.min(other.y2),
+            score: 0.,
+        };
+        let intersection = overlap.area();
+        let union = self.area() + other.area() - intersection;
+        if union <= 0. { 0. } else { intersection / union }
+    }
+}
+
+pub struct FaceDetector {
+    model: Func<'static>,
+    priors: Vec<[f32; 4]>,
+    score_threshold: f32,
+    iou_threshold: f32,
+    margin: f32,
+}
+
+impl FaceDetector {
+    pub fn load(weights_path: &str, score_threshold: f32, iou_threshold: f32, margin: f32) -> Result<Self> {
+        if !std::path::Path::new(weights_path).is_file() {
+            anyhow::bail!("Face detector weights not found at '{weights_path}' (face_detection.weights_path)");
+        }
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights_path], DType::F32, &Device::Cpu)? };
+        let model = ultraface_slim(vb)
+            .with_context(|| format!("Failed to build {DETECTOR_NAME} from '{weights_path}'"))?;
+
+        Ok(FaceDetector { model, priors: generate_priors(), score_threshold, iou_threshold, margin })
+    }
+
+    /// Detects faces in `image`, best score first.
+    pub fn detect(&self, image: &DynamicImage) -> Result<Vec<FaceBox>> {
+        let input = detector_input(image)?;
+        let output = self.model.forward(&input)?.squeeze(0)?;
+        let output = output.to_vec2::<f32>()?;
+
+        let (width, height) = (image.width() as f32, image.height() as f32);
+        let mut candidates: Vec<FaceBox> = output
+            .iter()
+            .zip(&self.priors)
+            .filter(|(row, _)| row[1] > self.score_threshold)
+            .map(|(row, prior)| {
+                // SSD box decoding relative to the prior, normalised to [0, 1]
+                let cx = row[2] * CENTER_VARIANCE * prior[2] + prior[0];
+                let cy = row[3] * CENTER_VARIANCE * prior[3] + prior[1];
+                let w = (row[4] * SIZE_VARIANCE).exp() * prior[2];
+                let h = (row[5] * SIZE_VARIANCE).exp() * prior[3];
+                FaceBox {
+                    x1: ((cx - w / 2.) * width).clamp(0., width),
+                    y1: ((cy - h / 2.) * height).clamp(0., height),
+                    x2: ((cx + w / 2.) * width).clamp(0., width),
+                    y2: ((cy + h / 2.) * height).clamp(0., height),
+                    score: row[1],
+                }
+            })
+            .collect();
+
+        candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(non_max_suppression(candidates, self.iou_threshold))
+    }
+
+    pub fn largest_face(&self, image: &DynamicImage) -> Result<Option<FaceBox>> {
+        Ok(self
+            .detect(image)?
+            .into_iter()
+            .max_by(|a, b| a.area().total_cmp(&b.area())))
+    }
+
+    /// Crops `face` plus the configured margin.
+    pub fn crop(&self, image: &DynamicImage, face: &FaceBox) -> DynamicImage {
+        crop_face(image, face, self.margin)
+    }
+}
+
+/// Crops a square region around `face`, grown by `margin` times the face size on each side.
+pub fn crop_face(image: &DynamicImage, face: &FaceBox, margin: f32) -> DynamicImage {
+    let side = face.width().max(face.height()) * (1. + 2. * margin);
+    let cx = (face.x1 + face.x2) / 2.;
+    let cy = (face.y1 + face.y2) / 2.;
+
+    let x1 = (cx - side / 2.).max(0.) as u32;
+    let y1 = (cy - side / 2.).max(0.) as u32;
+    let x2 = ((cx + side / 2.) as u32).min(image.width());
+    let y2 = ((cy + side / 2.) as u32).min(image.height());
+
+    image.crop_imm(x1, y1, x2.saturating_sub(x1).max(1), y2.saturating_sub(y1).max(1))
+}
+
+fn detector_input(image: &DynamicImage) -> Result<Tensor> {
+    let img = image
+        .resize_exact(INPUT_WIDTH as u32, INPUT_HEIGHT as u32, image::imageops::FilterType::Triangle)
+        .to_rgb8();
+    let data = Tensor::from_vec(img.into_raw(), (INPUT_HEIGHT, INPUT_WIDTH, 3), &Device::Cpu)?
+        .permute((2, 0, 1))?
+        .to_dtype(DType::F32)?;
+    Ok(((data - 127.)? / 128.)?.unsqueeze(0)?)
+}
+
+fn generate_priors() -> Vec<[f32; 4]> {
+    let mut priors = Vec::new();
+    for (((feature_w, feature_h), shrinkage), min_boxes) in FEATURE_MAPS.iter().zip(SHRINKAGES).zip(MIN_BOXES) {
+        let scale_w = INPUT_WIDTH as f32 / shrinkage as f32;
+        let scale_h = INPUT_HEIGHT as f32 / shrinkage as f32;
+        for j in 0..*feature_h {
+            for i in 0..*feature_w {
+                let cx = (i as f32 + 0.5) / scale_w;
+                let cy = (j as f32 + 0.5) / scale_h;
+                for min_box in min_boxes {
+                    priors.push([
+                        cx.clamp(0., 1.),
+                        cy.clamp(0., 1.),
+                        (min_box / INPUT_WIDTH as f32).clamp(0., 1.),
+                        (min_box / INPUT_HEIGHT as f32).clamp(0., 1.),
+                    ]);
+                }
+            }
+        }
+    }
+    priors
+}
+
+fn non_max_suppression(candidates: Vec<FaceBox>, iou_threshold: f32) -> Vec<FaceBox> {
+    let mut kept: Vec<FaceBox> = Vec::new();
+    for candidate in candidates {
+        if kept.iter().all(|face| face.iou(&candidate) <= iou_threshold) {
+            kept.push(candidate);
+        }
+    }
+    kept
+}
+
+fn conv_bn(inp: usize, oup: usize, stride: usize, vb: VarBuilder) -> Result<Func<'static>> {
+    let cfg = Conv2dConfig { padding: 1, stride, ..Default::default() };
+    let conv = conv2d_no_bias(inp, oup, 3, cfg, vb.pp(0))?;
+    let bn = batch_norm(oup, 1e-5, vb.pp(1))?;
+    Ok(Func::new(move |xs| xs.apply(&conv)?.apply_t(&bn, false)?.relu()))
+}
+
+// Depthwise 3x3 followed by pointwise 1x1, each with batch norm and ReLU
+fn conv_dw(inp: usize, oup: usize, stride: usize, vb: VarBuilder) -> Result<Func<'static>> {
+    let cfg = Conv2dConfig { padding: 1, stride, groups: inp, ..Default::default() };
+    let depthwise = conv2d_no_bias(inp, inp, 3, cfg, vb.pp(0))?;
+    let bn1 = batch_norm(inp, 1e-5, vb.pp(1))?;
+    let pointwise = conv2d_no_bias(inp, oup, 1, Default::default(), vb.pp(3))?;
+    let bn2 = batch_norm(oup, 1e-5, vb.pp(4))?;
+    Ok(Func::new(move |xs| {
+        xs.apply(&depthwise)?
+            .apply_t(&bn1, false)?
+            .relu()?
+            .apply(&pointwise)?
+            .apply_t(&bn2, false)?
+            .relu()
+    }))
+}
+
+fn separable_conv(inp: usize, oup: usize, stride: usize, vb: VarBuilder) -> Result<Func<'static>> {
+    let cfg = Conv2dConfig { padding: 1, stride, groups: inp, ..Default::default() };
+    let depthwise = conv2d(inp, inp, 3, cfg, vb.pp(0))?;
+    let pointwise = conv2d(inp, oup, 1, Default::default(), vb.pp(2))?;
+    Ok(Func::new(move |xs| xs.apply(&depthwise)?.relu()?.apply(&pointwise)))
+}
+
+fn sequential(layers: Vec<Func<'static>>) -> Func<'static> {
+    Func::new(move |xs| layers.iter().try_fold(xs.clone(), |xs, layer| xs.apply(layer)))
+}
+
+// Flattens an (N, A*k, H, W) head output into (N, H*W*A, k), matching the prior order
+fn flatten_head(xs: &Tensor, k: usize) -> candle_core::Result<Tensor> {
+    let n = xs.dim(0)?;
+    xs.permute((0, 2, 3, 1))?.contiguous()?.reshape((n, (), k))
+}
+
+/// Builds the network; the output is (N, priors, 6) holding the two class
+/// probabilities followed by the four box offsets.
+fn ultraface_slim(vb: VarBuilder) -> Result<Func<'static>> {
+    let bc = BASE_CHANNELS;
+    let base = vb.pp("base_net");
+    let stage1 = sequential(vec![
+        conv_bn(3, bc, 2, base.pp(0))?,
+        conv_dw(bc, bc * 2, 1, base.pp(1))?,
+        conv_dw(bc * 2, bc * 2, 2, base.pp(2))?,
+        conv_dw(bc * 2, bc * 2, 1, base.pp(3))?,
+        conv_dw(bc * 2, bc * 4, 2, base.pp(4))?,
+        conv_dw(bc * 4, bc * 4, 1, base.pp(5))?,
+        conv_dw(bc * 4, bc * 4, 1, base.pp(6))?,
+        conv_dw(bc * 4, bc * 4, 1, base.pp(7))?,
+    ]);
+    let stage2 = sequential(vec![
+        conv_dw(bc * 4, bc * 8, 2, base.pp(8))?,
+        conv_dw(bc * 8, bc * 8, 1, base.pp(9))?,
+        conv_dw(bc * 8, bc * 8, 1, base.pp(10))?,
+    ]);
+    let stage3 = sequential(vec![
+        conv_dw(bc * 8, bc * 16, 2, base.pp(11))?,
+        conv_dw(bc * 16, bc * 16, 1, base.pp(12))?,
+    ]);
+
+    let extras_vb = vb.pp("extras").pp(0);
+    let extras_conv = conv2d(bc * 16, bc * 4, 1, Default::default(), extras_vb.pp(0))?;
+    let extras_separable = separable_conv(bc * 4, bc * 16, 2, extras_vb.pp(2))?;
+    let extras = Func::new(move |xs| xs.apply(&extras_conv)?.relu()?.apply(&extras_separable)?.relu());
+
+    // (input channels, priors per location) of each head
+    let heads = [(bc * 4, 3), (bc * 8, 2), (bc * 16, 2), (bc * 16, 3)];
+    let mut classifiers = Vec::new();
+    let mut regressors = Vec::new();
+    for (index, (channels, anchors)) in heads.into_iter().enumerate() {
+        let (cls_vb, reg_vb) = (vb.pp("classification_headers").pp(index), vb.pp("regression_headers").pp(index));
+        if index == heads.len() - 1 {
+            let cfg = Conv2dConfig { padding: 1, ..Default::default() };
+            let cls = conv2d(channels, anchors * 2, 3, cfg, cls_vb)?;
+            let reg = conv2d(channels, anchors * 4, 3, cfg, reg_vb)?;
+            classifiers.push(Func::new(move |xs| xs.apply(&cls)));
+            regressors.push(Func::new(move |xs| xs.apply(&reg)));
+        } else {
+            classifiers.push(separable_conv(channels, anchors * 2, 1, cls_vb)?);
+            regressors.push(separable_conv(channels, anchors * 4, 1, reg_vb)?);
+        }
+    }
+
+    Ok(Func::new(move |xs| {
+        let f1 = xs.apply(&stage1)?;
+        let f2 = f1.apply(&stage2)?;
+        let f3 = f2.apply(&stage3)?;
+        let f4 = f3.apply(&extras)?;
+
+        let mut scores = Vec::new();
+        let mut boxes = Vec::new();
+        for (features, (cls, reg)) in [f1, f2, f3, f4].iter().zip(classifiers.iter().zip(&regressors)) {
+            scores.push(flatten_head(&features.apply(cls)?, 2)?);
+            boxes.push(flatten_head(&features.apply(reg)?, 4)?);
+        }
+        let scores = candle_nn::ops::softmax(&Tensor::cat(&scores, 1)?, D::Minus1)?;
+        let boxes = Tensor::cat(&boxes, 1)?;
+        Tensor::cat(&[scores, boxes], D::Minus1)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_nn::VarMap;
+
+    fn face(x1: f32, y1: f32, x2: f32, y2: f32, score: f32) -> FaceBox {
+        FaceBox { x1, y1, x2, y2, score }
+    }
+
+    #[test]
+    fn network_outputs_one_row_per_prior() -> Result<()> {
+        let varmap = VarMap::new();
+        let model = ultraface_slim(VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu))?;
+
+        let input = detector_input(&DynamicImage::new_rgb8(640, 480))?;
+        let output = model.forward(&input)?;
+        assert_eq!(output.dims(), &[1, generate_priors().len(), 6]);
+        assert_eq!(generate_priors().len(), 4420);
+        Ok(())
+    }
+
+    #[test]
+    fn priors_match_upstream() {
+        // Values from upstream `define_img_size(320)`: first prior of each head and
+        // the last prior of the two coarse heads, whose row spacing is 7.5 and 3.75
+        let priors = generate_priors();
+        let expected = [
+            (0, [0.0125, 0.5 / 30., 0.03125, 10. / 240.]),
+            (3600, [0.025, 0.5 / 15., 0.1, 32. / 240.]),
+            (4200, [0.05, 0.5 / 7.5, 0.2, 64. / 240.]),
+            (4359, [0.95, 1., 0.3, 0.4]),
+            (4360, [0.1, 0.5 / 3.75, 0.4, 128. / 240.]),
+            (4419, [0.9, 3.5 / 3.75, 0.8, 1.]),
+        ];
+        for (index, prior) in expected {
+            for (actual, expected) in priors[index].iter().zip(prior) {
+                assert!((actual - expected).abs() < 1e-6, "prior {index}: {:?} != {prior:?}", priors[index]);
+            }
+        }
+    }
+
+    #[test]
+    fn nms_drops_overlapping_lower_scores() {
+        let kept = non_max_suppression(
+            vec![face(0., 0., 10., 10., 0.9), face(1., 1., 11., 11., 0.8), face(50., 50., 60., 60., 0.7)],
+            0.3,
+        );
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[0].score, 0.9);
+        assert_eq!(kept[1].score, 0.7);
+    }
+
+    #[test]
+    fn crop_face_adds_margin_and_clamps_to_image() {
+        let image = DynamicImage::new_rgb8(200, 100);
+
+        let crop = crop_face(&image, &face(80., 30., 120., 70., 1.), 0.25);
+        assert_eq!((crop.width(), crop.height()), (60, 60));
+
+        let edge = crop_face(&image, &face(0., 0., 40., 40., 1.), 0.25);
+        assert_eq!((edge.width(), edge.height()), (50, 50));
+    }
+}
@@ -0,0 +1,98 @@
+//! Exact and perceptual hashes of decoded frames, to spot frozen streams and
+//! replayed recordings.
+use super::face_detection::FaceBox;
+use image::DynamicImage;
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// The difference hash compares each cell of a (SIDE + 1) x SIDE grayscale
+// thumbnail with its right neighbour, giving SIDE * SIDE bits
+const SIDE: u32 = 16;
+
+/// A 256-bit difference hash. Re-encoding or rescaling a frame flips few bits,
+/// while a new frame of a live scene flips many.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PerceptualHash(pub [u64; 4]);
+
+impl PerceptualHash {
+    /// Number of differing bits, 0 to 256.
+    pub fn distance(&self, other: &PerceptualHash) -> u32 {
+        self.0.iter().zip(&other.0).map(|(a, b)| (a ^ b).count_ones()).sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameHash {
+    /// SHA-256 of the dimensions and RGB pixels.
+    pub exact: [u8; 32],
+    pub perceptual: PerceptualHash,
+}
+
+/// Hashes the `face` region of `image`, or all of it without a detected face.
+/// The background of a fixed camera looks the same in every attempt, so only
+/// the face tells two attempts apart.
+pub fn hash_face(image: &DynamicImage, face: Option<&FaceBox>) -> FrameHash {
+    match face {
+        Some(face) => hash_frame(&image.crop_imm(
+            face.x1 as u32,
+            face.y1 as u32,
+            (face.width() as u32).max(1),
+            (face.height() as u32).max(1),
+        )),
+        None => hash_frame(image),
+    }
+}
+
+pub fn hash_frame(image: &DynamicImage) -> FrameHash {
+    let rgb = image.to_rgb8();
+    let mut hasher = Sha256::new();
+    hasher.update(rgb.width().to_le_bytes());
+    hasher.update(rgb.height().to_le_bytes());
+    hasher.update(rgb.as_raw());
+
+    let thumbnail = image.resize_exact(SIDE + 1, SIDE, FilterType::Triangle).to_luma8();
+    let mut bits = [0u64; 4];
+    for y in 0..SIDE {
+        for x in 0..SIDE {
+            if thumbnail.get_pixel(x, y).0[0] > thumbnail.get_pixel(x + 1, y).0[0] {
+                let bit = (y * SIDE + x) as usize;
+                bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+    }
+
+    FrameHash { exact: hasher.finalize().into(), perceptual: PerceptualHash(bits) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, Rgb, RgbImage};
+    use std::io::Cursor;
+
+    fn scene(offset: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(320, 240, |x, y| {
+            let x = x + offset;
+            let v = ((x * 7 + y * 3) % 200 + (x / 40 + y / 30) % 3 * 20) as u8;
+            Rgb([v, v / 2, 255 - v])
+        }))
+    }
+
+    #[test]
+    fn reencoded_frames_stay_close_and_new_frames_do_not() -> anyhow::Result<()> {
+        let original = scene(0);
+        let mut jpeg = Vec::new();
+        original.write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)?;
+        let reencoded = image::load_from_memory(&jpeg)?;
+
+        let (original_hash, reencoded_hash) = (hash_frame(&original), hash_frame(&reencoded));
+        assert_eq!(hash_frame(&original), original_hash);
+        assert_ne!(original_hash.exact, reencoded_hash.exact);
+        assert!(original_hash.perceptual.distance(&reencoded_hash.perceptual) <= 8);
+
+        let moved = hash_frame(&scene(25));
+        assert!(original_hash.perceptual.distance(&moved.perceptual) > 32);
+        Ok(())
+    }
+}
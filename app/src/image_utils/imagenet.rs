@@ -2,6 +2,7 @@ use candle_core::{Device, Result, Tensor, DType};
 
 pub const IMAGENET_MEAN: [f32; 3] = [0.485f32, 0.456, 0.406];
 pub const IMAGENET_STD: [f32; 3] = [0.229f32, 0.224, 0.225];
+pub const IMAGE_SIZE: usize = 224;
 use image::{DynamicImage};
 
 pub fn image_with_std_mean(
@@ -24,4 +25,12 @@ pub fn image_with_std_mean(
     (data.to_dtype(DType::F32)? / 255.)?
         .broadcast_sub(&mean)?
         .broadcast_div(&std)
-}
\ No newline at end of file
+}
+
+/// Describes the preprocessing applied before inference, recorded with every template.
+pub fn preprocessing_descriptor() -> String {
+    format!(
+        "resize_to_fill={IMAGE_SIZE};mean={:?};std={:?}",
+        IMAGENET_MEAN, IMAGENET_STD
+    )
+}
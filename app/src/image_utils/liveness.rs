@@ -0,0 +1,272 @@
+//! Passive liveness cues over the frames of one capture, to reject printed
+//! photos and screens held up to the camera. No extra model is needed: the cues
+//! come from the face crops the capture already samples.
+use image::imageops::{self, FilterType};
+use image::GrayImage;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+// Cue ranges mapped onto 0 (spoof-like) .. 1 (live-like). They are starting
+// points for a webcam at arm's length; calibrate the threshold per camera.
+// Mean absolute luma change between consecutive face crops
+const FROZEN_MOTION: f32 = 0.5;
+const LIVE_MOTION: f32 = 3.;
+// Strongest high-frequency peak over the band's mean magnitude
+const NATURAL_PEAKINESS: f32 = 6.;
+const MOIRE_PEAKINESS: f32 = 15.;
+// Share of spectral energy above `HIGH_FREQUENCY` cycles per pixel
+const FLAT_TEXTURE: f32 = 0.02;
+const RICH_TEXTURE: f32 = 0.08;
+// Fraction of near-saturated pixels
+const GLARE_LEVEL: u8 = 250;
+const NATURAL_GLARE: f32 = 0.02;
+const HEAVY_GLARE: f32 = 0.1;
+
+const HIGH_FREQUENCY: f32 = 0.25;
+
+// Crops are compared for motion at this size, as the face box changes size
+// slightly between frames
+const MOTION_SIZE: u32 = 64;
+// The spectral cues look at a centred window of at most this many native
+// pixels per side, which bounds the cost of the DFT on large faces
+const SPECTRUM_WINDOW: u32 = 128;
+
+// How much each cue contributes to the combined score
+const MOTION_WEIGHT: f32 = 0.4;
+const MOIRE_WEIGHT: f32 = 0.25;
+const TEXTURE_WEIGHT: f32 = 0.2;
+const GLARE_WEIGHT: f32 = 0.15;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LivenessReport {
+    /// Weighted combination of the cues, 0 (spoof) to 1 (live).
+    pub score: f32,
+    /// Mean absolute luma change between consecutive face crops, 0-255; `None` for a single frame.
+    pub motion: Option<f32>,
+    /// Strongest high-frequency spectral peak relative to the band's mean; screens show sharp peaks.
+    pub moire: f32,
+    /// Share of spectral energy at high frequencies; recaptured prints lose fine skin texture.
+    pub texture: f32,
+    /// Fraction of near-saturated pixels; glossy prints and screens reflect glare patches.
+    pub glare: f32,
+}
+
+/// Scores the face crops of one capture, in capture order. The crops must be
+/// at the camera's resolution: resampling them would blur away the moiré and
+/// texture the spectral cues look for.
+pub fn assess(frames: &[GrayImage]) -> LivenessReport {
+    let motion = (frames.len() > 1).then(|| {
+        let resized: Vec<GrayImage> =
+            frames.iter().map(|frame| imageops::resize(frame, MOTION_SIZE, MOTION_SIZE, FilterType::Triangle)).collect();
+        let changes: Vec<f32> = resized.windows(2).map(|pair| mean_absolute_difference(&pair[0], &pair[1])).collect();
+        changes.iter().sum::<f32>() / changes.len() as f32
+    });
+
+    let spectra: Vec<SpectrumStatistics> = frames.iter().map(|frame| spectrum_statistics(&spectrum_window(frame))).collect();
+    let mean = |values: &mut dyn Iterator<Item = f32>| {
+        let (sum, count) = values.fold((0., 0), |(sum, count), value| (sum + value, count + 1));
+        if count == 0 { 0. } else { sum / count as f32 }
+    };
+    let moire = mean(&mut spectra.iter().map(|spectrum| spectrum.peakiness));
+    let texture = mean(&mut spectra.iter().map(|spectrum| spectrum.high_frequency_share));
+    let glare = mean(&mut frames.iter().map(glare_fraction));
+
+    // A single frame can't show motion; leave that cue undecided
+    let motion_score = motion.map_or(0.5, |motion| ramp(motion, FROZEN_MOTION, LIVE_MOTION));
+    let score = MOTION_WEIGHT * motion_score
+        + MOIRE_WEIGHT * (1. - ramp(moire, NATURAL_PEAKINESS, MOIRE_PEAKINESS))
+        + TEXTURE_WEIGHT * ramp(texture, FLAT_TEXTURE, RICH_TEXTURE)
+        + GLARE_WEIGHT * (1. - ramp(glare, NATURAL_GLARE, HEAVY_GLARE));
+
+    LivenessReport { score, motion, moire, texture, glare }
+}
+
+// 0 at or below `low`, 1 at or above `high`, linear in between
+fn ramp(value: f32, low: f32, high: f32) -> f32 {
+    ((value - low) / (high - low)).clamp(0., 1.)
+}
+
+fn mean_absolute_difference(a: &GrayImage, b: &GrayImage) -> f32 {
+    let total: u64 = a.pixels().zip(b.pixels()).map(|(p, q)| p.0[0].abs_diff(q.0[0]) as u64).sum();
+    total as f32 / a.pixels().len().max(1) as f32
+}
+
+fn glare_fraction(frame: &GrayImage) -> f32 {
+    let saturated = frame.pixels().filter(|pixel| pixel.0[0] >= GLARE_LEVEL).count();
+    saturated as f32 / frame.pixels().len().max(1) as f32
+}
+
+// The centre of `frame`, cropped rather than resampled
+fn spectrum_window(frame: &GrayImage) -> GrayImage {
+    let (width, height) = (frame.width().min(SPECTRUM_WINDOW), frame.height().min(SPECTRUM_WINDOW));
+    imageops::crop_imm(frame, (frame.width() - width) / 2, (frame.height() - height) / 2, width, height).to_image()
+}
+
+struct SpectrumStatistics {
+    peakiness: f32,
+    high_frequency_share: f32,
+}
+
+// Magnitude spectrum of the Hann-windowed, mean-free crop via a separable DFT
+fn spectrum_statistics(frame: &GrayImage) -> SpectrumStatistics {
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    if width < 4 || height < 4 {
+        return SpectrumStatistics { peakiness: 0., high_frequency_share: 0. };
+    }
+
+    let mean = frame.pixels().map(|pixel| pixel.0[0] as f32).sum::<f32>() / (width * height) as f32;
+    let hann = |i: usize, n: usize| 0.5 - 0.5 * (2. * PI * i as f32 / (n - 1) as f32).cos();
+    let mut re: Vec<f32> = frame
+        .enumerate_pixels()
+        .map(|(x, y, pixel)| (pixel.0[0] as f32 - mean) * hann(x as usize, width) * hann(y as usize, height))
+        .collect();
+    let mut im = vec![0f32; width * height];
+
+    dft_rows(&mut re, &mut im, width, height);
+    let (mut re_t, mut im_t) = (transpose(&re, width, height), transpose(&im, width, height));
+    dft_rows(&mut re_t, &mut im_t, height, width);
+
+    let mut total = 0.;
+    let mut high = 0.;
+    let mut band = Vec::new();
+    for v in 0..width {
+        for u in 0..height {
+            if u == 0 && v == 0 {
+                continue;
+            }
+            let index = v * height + u;
+            let power = re_t[index] * re_t[index] + im_t[index] * im_t[index];
+            total += power;
+
+            // Signed frequencies in cycles per pixel
+            let fu = if u <= height / 2 { u } else { height - u } as f32 / height as f32;
+            let fv = if v <= width / 2 { v } else { width - v } as f32 / width as f32;
+            if (fu * fu + fv * fv).sqrt() > HIGH_FREQUENCY {
+                high += power;
+                band.push(power.sqrt());
+            }
+        }
+    }
+
+    let band_mean = band.iter().sum::<f32>() / band.len().max(1) as f32;
+    let band_max = band.iter().copied().fold(0., f32::max);
+    SpectrumStatistics {
+        peakiness: if band_mean > 0. { band_max / band_mean } else { 0. },
+        high_frequency_share: if total > 0. { high / total } else { 0. },
+    }
+}
+
+// In-place DFT of every row of a row-major `width` x `height` complex image
+fn dft_rows(re: &mut [f32], im: &mut [f32], width: usize, height: usize) {
+    let twiddles: Vec<(f32, f32)> = (0..width)
+        .map(|k| {
+            let angle = -2. * PI * k as f32 / width as f32;
+            (angle.cos(), angle.sin())
+        })
+        .collect();
+    let mut out_re = vec![0f32; width];
+    let mut out_im = vec![0f32; width];
+
+    for row in 0..height {
+        let (row_re, row_im) = (&re[row * width..(row + 1) * width], &im[row * width..(row + 1) * width]);
+        for k in 0..width {
+            let (mut sum_re, mut sum_im) = (0., 0.);
+            for n in 0..width {
+                let (cos, sin) = twiddles[(k * n) % width];
+                sum_re += row_re[n] * cos - row_im[n] * sin;
+                sum_im += row_re[n] * sin + row_im[n] * cos;
+            }
+            out_re[k] = sum_re;
+            out_im[k] = sum_im;
+        }
+        re[row * width..(row + 1) * width].copy_from_slice(&out_re);
+        im[row * width..(row + 1) * width].copy_from_slice(&out_im);
+    }
+}
+
+fn transpose(values: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let mut transposed = vec![0f32; values.len()];
+    for y in 0..height {
+        for x in 0..width {
+            transposed[x * height + y] = values[y * width + x];
+        }
+    }
+    transposed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    const SIZE: u32 = 64;
+
+    // Deterministic speckle standing in for skin texture
+    fn textured(seed: u32) -> GrayImage {
+        textured_of(SIZE, seed)
+    }
+
+    fn textured_of(size: u32, seed: u32) -> GrayImage {
+        GrayImage::from_fn(size, size, |x, y| {
+            let mut h = x.wrapping_mul(374_761_393) ^ y.wrapping_mul(668_265_263) ^ seed.wrapping_mul(2_246_822_519);
+            h = (h ^ (h >> 13)).wrapping_mul(1_274_126_177);
+            Luma([100 + (h >> 24) as u8 % 60])
+        })
+    }
+
+    #[test]
+    fn live_like_frames_outscore_replays_and_prints() {
+        // Texture that changes between frames, like a face that blinks and breathes
+        let live = assess(&[textured(1), textured(2), textured(3)]);
+
+        // A screen: the same frame every time, overlaid with a fine grating
+        let screen_frame = GrayImage::from_fn(SIZE, SIZE, |x, y| {
+            let grating = (2. * PI * 0.4 * x as f32).cos() * 40.;
+            Luma([(128. + grating + (y % 2) as f32) as u8])
+        });
+        let screen = assess(&[screen_frame.clone(), screen_frame.clone(), screen_frame]);
+
+        // A print: a smooth, static image
+        let print_frame = GrayImage::from_fn(SIZE, SIZE, |x, y| Luma([(80 + x + y / 2) as u8]));
+        let print = assess(&[print_frame.clone(), print_frame.clone(), print_frame]);
+
+        assert!(live.motion.is_some_and(|motion| motion > LIVE_MOTION));
+        assert_eq!(screen.motion, Some(0.));
+        assert!(screen.moire > MOIRE_PEAKINESS, "moire {}", screen.moire);
+        assert!(live.moire < NATURAL_PEAKINESS, "moire {}", live.moire);
+        assert!(print.texture < FLAT_TEXTURE, "texture {}", print.texture);
+
+        assert!(live.score > 0.8, "live {}", live.score);
+        assert!(screen.score < 0.5, "screen {}", screen.score);
+        assert!(print.score < 0.5, "print {}", print.score);
+    }
+
+    #[test]
+    fn screen_patterns_of_large_faces_survive() {
+        // A face of 320 pixels filmed off a screen, whose faint pixel grid is
+        // finer than the 128 pixel analysis size can resolve
+        let screen_frame = |seed: u32| {
+            let speckle = textured_of(320, seed);
+            GrayImage::from_fn(320, 320, |x, y| {
+                let grating = (2. * PI * 0.35 * x as f32).cos() * 6.;
+                Luma([(speckle.get_pixel(x, y).0[0] as f32 * 0.2 + 100. + grating) as u8])
+            })
+        };
+        let frames = [screen_frame(1), screen_frame(2)];
+        let screen = assess(&frames);
+        assert!(screen.moire > MOIRE_PEAKINESS, "moire {}", screen.moire);
+
+        // Resampling the crop to the analysis size blurs the grid away
+        let resampled = imageops::resize(&frames[0], SPECTRUM_WINDOW, SPECTRUM_WINDOW, FilterType::Triangle);
+        let peakiness = spectrum_statistics(&resampled).peakiness;
+        assert!(peakiness < MOIRE_PEAKINESS, "resampled moire {peakiness}");
+    }
+
+    #[test]
+    fn glare_and_single_frames() {
+        let glossy = GrayImage::from_fn(SIZE, SIZE, |x, _| Luma([if x < SIZE / 4 { 255 } else { 120 }]));
+        let report = assess(&[glossy]);
+        assert!(report.glare > HEAVY_GLARE);
+        assert_eq!(report.motion, None);
+    }
+}
@@ -0,0 +1,220 @@
+//! Frame quality scores used to skip blurred, badly exposed or poorly framed samples.
+use image::{DynamicImage, GrayImage};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use super::face_detection::FaceBox;
+
+// Photometric scores are measured on the face (or frame) resampled to this size,
+// so thresholds don't depend on the camera resolution or distance
+const ANALYSIS_SIZE: u32 = 128;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrameQuality {
+    /// Variance of the Laplacian; low values mean blur.
+    pub sharpness: f32,
+    /// Mean luma, 0-255.
+    pub brightness: f32,
+    /// Standard deviation of luma, 0-255.
+    pub contrast: f32,
+    /// Larger face side as a fraction of the smaller frame side, if a face was detected.
+    pub face_size: Option<f32>,
+    /// Distance of the face centre from the frame centre, 0 (centred) to 1 (at the edge).
+    pub face_offset: Option<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityThresholds {
+    pub min_sharpness: f32,
+    pub min_brightness: f32,
+    pub max_brightness: f32,
+    pub min_contrast: f32,
+    pub min_face_size: f32,
+    pub max_face_offset: f32,
+}
+
+/// The first threshold a frame failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QualityIssue {
+    Blurry { sharpness: f32, min: f32 },
+    TooDark { brightness: f32, min: f32 },
+    TooBright { brightness: f32, max: f32 },
+    LowContrast { contrast: f32, min: f32 },
+    FaceTooSmall { size: f32, min: f32 },
+    FaceOffCentre { offset: f32, max: f32 },
+}
+
+impl fmt::Display for QualityIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QualityIssue::Blurry { sharpness, min } => write!(f, "blurry (sharpness {sharpness:.1} < {min:.1})"),
+            QualityIssue::TooDark { brightness, min } => write!(f, "too dark (brightness {brightness:.0} < {min:.0})"),
+            QualityIssue::TooBright { brightness, max } => write!(f, "too bright (brightness {brightness:.0} > {max:.0})"),
+            QualityIssue::LowContrast { contrast, min } => write!(f, "low contrast ({contrast:.1} < {min:.1})"),
+            QualityIssue::FaceTooSmall { size, min } => write!(f, "face too small ({size:.2} < {min:.2} of the frame)"),
+            QualityIssue::FaceOffCentre { offset, max } => write!(f, "face off centre (offset {offset:.2} > {max:.2})"),
+        }
+    }
+}
+
+impl QualityThresholds {
+    pub fn check(&self, quality: &FrameQuality) -> Option<QualityIssue> {
+        if quality.sharpness < self.min_sharpness {
+            return Some(QualityIssue::Blurry { sharpness: quality.sharpness, min: self.min_sharpness });
+        }
+        if quality.brightness < self.min_brightness {
+            return Some(QualityIssue::TooDark { brightness: quality.brightness, min: self.min_brightness });
+        }
+        if quality.brightness > self.max_brightness {
+            return Some(QualityIssue::TooBright { brightness: quality.brightness, max: self.max_brightness });
+        }
+        if quality.contrast < self.min_contrast {
+            return Some(QualityIssue::LowContrast { contrast: quality.contrast, min: self.min_contrast });
+        }
+        if let Some(size) = quality.face_size
+            && size < self.min_face_size
+        {
+            return Some(QualityIssue::FaceTooSmall { size, min: self.min_face_size });
+        }
+        if let Some(offset) = quality.face_offset
+            && offset > self.max_face_offset
+        {
+            return Some(QualityIssue::FaceOffCentre { offset, max: self.max_face_offset });
+        }
+        None
+    }
+}
+
+/// The `face` region of `image` (or all of it) as grayscale at the camera's resolution.
+pub fn face_region(image: &DynamicImage, face: Option<&FaceBox>) -> GrayImage {
+    match face {
+        Some(face) => image
+            .crop_imm(face.x1 as u32, face.y1 as u32, (face.width() as u32).max(1), (face.height() as u32).max(1))
+            .to_luma8(),
+        None => image.to_luma8(),
+    }
+}
+
+/// The `face` region of `image` (or all of it) as grayscale at the analysis size.
+pub fn analysis_region(image: &DynamicImage, face: Option<&FaceBox>) -> GrayImage {
+    image::imageops::resize(&face_region(image, face), ANALYSIS_SIZE, ANALYSIS_SIZE, image::imageops::FilterType::Triangle)
+}
+
+/// Scores `image`, or only the `face` region of it when one was detected.
+pub fn assess(image: &DynamicImage, face: Option<&FaceBox>) -> FrameQuality {
+    let gray = analysis_region(image, face);
+    let (brightness, contrast) = luma_statistics(&gray);
+
+    let (width, height) = (image.width() as f32, image.height() as f32);
+    FrameQuality {
+        sharpness: laplacian_variance(&gray),
+        brightness,
+        contrast,
+        face_size: face.map(|face| face.width().max(face.height()) / width.min(height)),
+        face_offset: face.map(|face| {
+            let dx = ((face.x1 + face.x2) / 2. - width / 2.).abs() / (width / 2.);
+            let dy = ((face.y1 + face.y2) / 2. - height / 2.).abs() / (height / 2.);
+            dx.max(dy).min(1.)
+        }),
+    }
+}
+
+// Mean and standard deviation from the luma histogram
+fn luma_statistics(gray: &GrayImage) -> (f32, f32) {
+    let mut histogram = [0u64; 256];
+    for pixel in gray.pixels() {
+        histogram[pixel.0[0] as usize] += 1;
+    }
+    let count = gray.pixels().len().max(1) as f64;
+
+    let mean = histogram.iter().enumerate().map(|(level, &n)| level as f64 * n as f64).sum::<f64>() / count;
+    let variance = histogram
+        .iter()
+        .enumerate()
+        .map(|(level, &n)| (level as f64 - mean).powi(2) * n as f64)
+        .sum::<f64>()
+        / count;
+    (mean as f32, variance.sqrt() as f32)
+}
+
+// Variance of the 4-neighbour Laplacian over the interior pixels
+fn laplacian_variance(gray: &GrayImage) -> f32 {
+    let (width, height) = gray.dimensions();
+    if width < 3 || height < 3 {
+        return 0.;
+    }
+    let at = |x: u32, y: u32| gray.get_pixel(x, y).0[0] as f64;
+
+    let mut responses = Vec::with_capacity(((width - 2) * (height - 2)) as usize);
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            responses.push(at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4. * at(x, y));
+        }
+    }
+    let mean = responses.iter().sum::<f64>() / responses.len() as f64;
+    let variance = responses.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / responses.len() as f64;
+    variance as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Luma, Rgb, RgbImage};
+
+    fn thresholds() -> QualityThresholds {
+        QualityThresholds {
+            min_sharpness: 30.,
+            min_brightness: 40.,
+            max_brightness: 215.,
+            min_contrast: 15.,
+            min_face_size: 0.15,
+            max_face_offset: 0.5,
+        }
+    }
+
+    fn checkerboard(size: u32, cell: u32, dark: u8, light: u8) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(size, size, |x, y| {
+            let v = if (x / cell + y / cell).is_multiple_of(2) { dark } else { light };
+            Rgb([v, v, v])
+        }))
+    }
+
+    #[test]
+    fn blur_lowers_sharpness() {
+        let sharp = checkerboard(256, 8, 40, 200);
+        let blurred = sharp.blur(6.);
+
+        let sharp_quality = assess(&sharp, None);
+        let blurred_quality = assess(&blurred, None);
+        assert!(sharp_quality.sharpness > 10. * blurred_quality.sharpness);
+        assert_eq!(thresholds().check(&sharp_quality), None);
+        assert!(matches!(thresholds().check(&blurred_quality), Some(QualityIssue::Blurry { .. })));
+    }
+
+    #[test]
+    fn exposure_and_contrast_come_from_the_histogram() {
+        let gray = GrayImage::from_fn(4, 4, |x, _| Luma([if x < 2 { 100 } else { 200 }]));
+        assert_eq!(luma_statistics(&gray), (150., 50.));
+
+        let dark = assess(&checkerboard(256, 8, 0, 30), None);
+        assert!(matches!(thresholds().check(&dark), Some(QualityIssue::TooDark { .. })));
+
+        let flat = assess(&checkerboard(256, 8, 120, 130), None);
+        assert!(matches!(thresholds().check(&flat), Some(QualityIssue::Blurry { .. } | QualityIssue::LowContrast { .. })));
+    }
+
+    #[test]
+    fn face_size_and_position_are_relative_to_the_frame() {
+        let frame = checkerboard(400, 8, 40, 200);
+        let face = |x1: f32, y1: f32, side: f32| FaceBox { x1, y1, x2: x1 + side, y2: y1 + side, score: 1. };
+
+        let centred = assess(&frame, Some(&face(150., 150., 100.)));
+        assert_eq!((centred.face_size, centred.face_offset), (Some(0.25), Some(0.)));
+        assert_eq!(thresholds().check(&centred), None);
+
+        let small = assess(&frame, Some(&face(190., 190., 20.)));
+        assert!(matches!(thresholds().check(&small), Some(QualityIssue::FaceTooSmall { .. })));
+
+        let corner = assess(&frame, Some(&face(0., 0., 100.)));
+        assert!(matches!(thresholds().check(&corner), Some(QualityIssue::FaceOffCentre { .. })));
+    }
+}
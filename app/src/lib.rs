@@ -0,0 +1,14 @@
+//! Face authentication: capture, embeddings, storage and matching, shared by the
+//! `face-auth` binary and the benchmarks.
+pub mod camera;
+pub mod config;
+pub mod embeddings;
+pub mod identify;
+pub mod image_utils;
+pub mod lockout;
+pub mod login;
+pub mod matching;
+pub mod register;
+pub mod replay;
+pub mod storage;
+pub mod users;
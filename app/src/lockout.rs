@@ -0,0 +1,137 @@
+//! Failed login tracking per user: too many failures within a window lock the
+//! user out, for a cooldown that doubles with every lockout in a row.
+use crate::storage::json_state;
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockoutPolicy {
+    /// Failures within `window` that lock the user out.
+    pub max_failures: usize,
+    pub window: Duration,
+    /// Length of the first lockout; every further one in a row doubles it.
+    pub cooldown: Duration,
+    pub max_cooldown: Duration,
+}
+
+/// Attempts of every user, in a JSON file next to the embeddings so lockouts
+/// survive restarts and apply to every process.
+pub struct LockoutTracker {
+    path: PathBuf,
+    policy: LockoutPolicy,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AttemptLog {
+    users: BTreeMap<String, UserAttempts>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct UserAttempts {
+    // Failures since the last lockout or successful login, oldest first
+    failures: Vec<DateTime<Utc>>,
+    // Lockouts since the last successful login or unlock
+    lockouts: u32,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+impl LockoutTracker {
+    pub fn new(path: PathBuf, policy: LockoutPolicy) -> Self {
+        LockoutTracker { path, policy }
+    }
+
+    /// The end of the user's lockout, if they are locked out at `now`.
+    pub fn locked_until(&self, user_name: &str, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>> {
+        let log: AttemptLog = json_state::read(&self.path)?;
+        Ok(log.users.get(user_name).and_then(|user| user.locked_until).filter(|until| *until > now))
+    }
+
+    /// Records a failed attempt at `now`. Returns the end of the lockout if this
+    /// failure triggered one.
+    pub fn record_failure(&self, user_name: &str, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>> {
+        let policy = &self.policy;
+        json_state::update(&self.path, |log: &mut AttemptLog| {
+            let user = log.users.entry(user_name.to_string()).or_default();
+            user.failures.retain(|failed_at| now - *failed_at < policy.window);
+            user.failures.push(now);
+            if user.failures.len() < policy.max_failures {
+                return None;
+            }
+
+            let cooldown = policy
+                .cooldown
+                .checked_mul(1 << user.lockouts.min(30))
+                .map_or(policy.max_cooldown, |cooldown| cooldown.min(policy.max_cooldown));
+            user.failures.clear();
+            user.lockouts += 1;
+            user.locked_until = Some(now + cooldown);
+            user.locked_until
+        })
+    }
+
+    /// Forgets the user's failures and lockouts after a successful login.
+    pub fn record_success(&self, user_name: &str) -> Result<()> {
+        self.unlock(user_name).map(|_| ())
+    }
+
+    /// Lifts the user's lockout and forgets their failures. Returns false if
+    /// nothing was recorded for them.
+    pub fn unlock(&self, user_name: &str) -> Result<bool> {
+        json_state::update(&self.path, |log: &mut AttemptLog| log.users.remove(user_name).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_lock_out_with_a_doubling_cooldown() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("face_auth_attempts_{}.json", uuid::Uuid::new_v4()));
+        let result = (|| {
+            let tracker = LockoutTracker::new(path.clone(), LockoutPolicy {
+                max_failures: 3,
+                window: Duration::minutes(10),
+                cooldown: Duration::minutes(1),
+                max_cooldown: Duration::minutes(3),
+            });
+            let start = Utc::now();
+            let at = |minutes: i64| start + Duration::minutes(minutes);
+
+            // Failures outside the window don't add up
+            assert_eq!(tracker.record_failure("alice", at(0))?, None);
+            assert_eq!(tracker.record_failure("alice", at(20))?, None);
+            assert_eq!(tracker.record_failure("alice", at(21))?, None);
+            assert_eq!(tracker.record_failure("alice", at(22))?, Some(at(23)));
+            assert_eq!(tracker.locked_until("alice", at(22))?, Some(at(23)));
+            assert_eq!(tracker.locked_until("alice", at(23))?, None);
+            assert_eq!(tracker.locked_until("bob", at(22))?, None);
+
+            // The next lockouts double the cooldown up to the maximum
+            for minute in [30, 31] {
+                tracker.record_failure("alice", at(minute))?;
+            }
+            assert_eq!(tracker.record_failure("alice", at(32))?, Some(at(34)));
+            for minute in [40, 41] {
+                tracker.record_failure("alice", at(minute))?;
+            }
+            assert_eq!(tracker.record_failure("alice", at(42))?, Some(at(45)));
+
+            // Unlocking (or logging in) starts over
+            assert!(tracker.unlock("alice")?);
+            assert!(!tracker.unlock("alice")?);
+            assert_eq!(tracker.locked_until("alice", at(42))?, None);
+            tracker.record_failure("alice", at(50))?;
+            tracker.record_success("alice")?;
+            tracker.record_failure("alice", at(51))?;
+            assert_eq!(tracker.record_failure("alice", at(52))?, None);
+            Ok(())
+        })();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}.lock", path.display()));
+        result
+    }
+}
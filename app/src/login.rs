@@ -1,66 +1,367 @@
-use candle_nn::Func;
+use crate::embeddings::utils::EmbeddingModel;
 use crate::storage::vector_storage::{EmbeddingStorage, EmbeddingRecord};
-use candle_core::Tensor;
+use crate::matching::TemplateMatrix;
 use anyhow::Result;
-use candle_core::Device;
-use crate::camera::camera_interactions::{capture_and_compute_average_embedding};
+use crate::camera::camera_interactions::{average_embedding, capture_samples, CaptureStats, CaptureTimeout, CapturedSample, ChallengeFailed, ChallengeFailure, FrameRejection};
+use crate::config::{get_liveness_threshold, get_lockout_tracker, get_login_threshold, get_replay_guard, get_threshold_overrides, is_challenge_enabled};
+use crate::image_utils::challenge::Challenge;
+use crate::lockout::LockoutTracker;
+use crate::replay::ReplayIssue;
+use crate::users::{threshold_override, ThresholdOverrides};
+use std::fmt;
+use std::time::{Duration, Instant};
 
-pub fn login(model: &Func, storage: &dyn EmbeddingStorage, user_name: &str) -> Result<bool> {
+/// Where the threshold of a login attempt came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThresholdSource {
+    Config,
+    User,
+}
+
+impl fmt::Display for ThresholdSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThresholdSource::Config => write!(f, "configured"),
+            ThresholdSource::User => write!(f, "per-user"),
+        }
+    }
+}
+
+/// Outcome of a login attempt.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    Accepted,
+    /// The best template scored below the threshold.
+    Rejected,
+    UnknownUser,
+    /// Every template of the user was enrolled with a different model.
+    StaleTemplates { count: usize },
+    /// The capture timed out because the frames showed no face.
+    NoFace,
+    /// The capture timed out for another reason, e.g. frame quality.
+    CaptureTimeout,
+    /// The camera or the embedding model failed.
+    CaptureFailed(String),
+    /// The capture looked like a photo or a screen rather than a live face.
+    LivenessFailed { score: f32, threshold: f32 },
+    /// The requested liveness action wasn't performed in time, or not by the sampled face.
+    ChallengeFailed { challenge: Challenge, reason: ChallengeFailure },
+    /// The frames were frozen or reused from an earlier attempt.
+    ReplayDetected(ReplayIssue),
+    /// Too many recent failures; no capture was attempted.
+    LockedOut { until: chrono::DateTime<chrono::Utc> },
+}
+
+impl Decision {
+    /// Whether the attempt counts towards a lockout: a face was captured and refused.
+    pub fn is_failed_attempt(&self) -> bool {
+        matches!(
+            self,
+            Decision::Rejected | Decision::LivenessFailed { .. } | Decision::ChallengeFailed { .. } | Decision::ReplayDetected(_)
+        )
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decision::Accepted => write!(f, "accepted"),
+            Decision::Rejected => write!(f, "rejected: similarity below threshold"),
+            Decision::UnknownUser => write!(f, "rejected: no registered templates"),
+            Decision::StaleTemplates { count } => {
+                write!(f, "all {count} template(s) were enrolled with a different model; re-enroll the user")
+            }
+            Decision::NoFace => write!(f, "no face detected before the capture timed out"),
+            Decision::CaptureTimeout => write!(f, "not enough usable frames before the capture timed out"),
+            Decision::CaptureFailed(reason) => write!(f, "capture failed: {reason}"),
+            Decision::LivenessFailed { score, threshold } => {
+                write!(f, "rejected: liveness score {score:.2} below {threshold:.2} (possible photo or screen)")
+            }
+            Decision::ChallengeFailed { challenge, reason } => match reason {
+                ChallengeFailure::Timeout { .. } => write!(f, "rejected: liveness challenge '{challenge}' not completed in time"),
+                ChallengeFailure::TrackLost => write!(f, "rejected: lost track of the face after the liveness challenge '{challenge}'"),
+                ChallengeFailure::DifferentFace { similarity, min } => write!(
+                    f,
+                    "rejected: the face that performed the liveness challenge '{challenge}' isn't the sampled one (similarity {similarity:.2} below {min:.2})"
+                ),
+            },
+            Decision::ReplayDetected(issue) => write!(f, "rejected: possible replay, {issue}"),
+            Decision::LockedOut { until } => {
+                write!(f, "locked out after too many failed attempts, try again after {}", until.to_rfc3339())
+            }
+        }
+    }
+}
+
+/// Similarity of the live capture to one stored template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateScore {
+    pub record_id: String,
+    pub similarity: f32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MatchTimings {
+    pub capture: Duration,
+    pub matching: Duration,
+    pub total: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchResult {
+    pub user_name: String,
+    pub decision: Decision,
+    /// Scores against every comparable template, best first.
+    pub scores: Vec<TemplateScore>,
+    /// The best template when the attempt was accepted.
+    pub matched_id: Option<String>,
+    pub threshold: f32,
+    pub threshold_source: ThresholdSource,
+    /// `None` when the attempt was decided before capturing.
+    pub capture: Option<CaptureStats>,
+    pub timings: MatchTimings,
+}
+
+impl MatchResult {
+    pub fn accepted(&self) -> bool {
+        self.decision == Decision::Accepted
+    }
+
+    pub fn best_score(&self) -> Option<&TemplateScore> {
+        self.scores.first()
+    }
+}
+
+/// Cosine similarity thresholds must lie in [-1, 1].
+pub fn validate_threshold(threshold: f32) -> Result<()> {
+    if !(-1.0..=1.0).contains(&threshold) {
+        anyhow::bail!("Login threshold must be between -1 and 1, got {threshold}");
+    }
+    Ok(())
+}
+
+/// The threshold for a user: their override wins over `default`. `records` are
+/// the user's templates, which may still carry an override of an older version.
+pub fn resolve_threshold(
+    overrides: &ThresholdOverrides,
+    user_name: &str,
+    records: &[EmbeddingRecord],
+    default: f32,
+) -> Result<(f32, ThresholdSource)> {
+    Ok(match threshold_override(overrides, user_name, records)? {
+        Some(threshold) => (threshold, ThresholdSource::User),
+        None => (default, ThresholdSource::Config),
+    })
+}
+
+/// Authenticates `user_name`. Errors are reserved for storage and configuration
+/// problems; everything that can go wrong with the attempt itself is a `Decision`.
+/// Failed attempts are counted towards a lockout, and a success resets the count.
+pub fn login(model: &EmbeddingModel, storage: &dyn EmbeddingStorage, user_name: &str) -> Result<MatchResult> {
+    let lockout = get_lockout_tracker()?;
+    let result = attempt_login(model, storage, user_name, lockout.as_ref())?;
+
+    if let Some(lockout) = &lockout {
+        if result.accepted() {
+            lockout.record_success(user_name)?;
+        } else if result.decision.is_failed_attempt()
+            && let Some(until) = lockout.record_failure(user_name, chrono::Utc::now())?
+        {
+            println!("[!] Too many failed attempts for '{user_name}', locked out until {}", until.to_rfc3339());
+        }
+    }
+    Ok(result)
+}
+
+fn attempt_login(
+    model: &EmbeddingModel,
+    storage: &dyn EmbeddingStorage,
+    user_name: &str,
+    lockout: Option<&LockoutTracker>,
+) -> Result<MatchResult> {
     println!("[*] Attempting to login user '{user_name}'");
+    let start_time = Instant::now();
 
-    // 1. Capture a new embedding from the camera
-    let live_embedding = capture_and_compute_average_embedding(model)?;
+    // 1. Retrieve all stored embeddings for the given user
+    let user_embeddings = storage.get_user_embeddings(user_name)?;
 
-    // 2. Retrieve all stored embeddings for the given user
-    let all_embeddings = storage.get_all_embeddings()?;
-    let user_embeddings: Vec<EmbeddingRecord> = all_embeddings
-        .into_iter()
-        .filter(|record| record.name == user_name)
-        .collect();
+    let (threshold, threshold_source) =
+        resolve_threshold(&get_threshold_overrides()?, user_name, &user_embeddings, get_login_threshold())?;
+
+    let mut result = MatchResult {
+        user_name: user_name.to_string(),
+        decision: Decision::UnknownUser,
+        scores: Vec::new(),
+        matched_id: None,
+        threshold,
+        threshold_source,
+        capture: None,
+        timings: MatchTimings::default(),
+    };
+
+    if let Some(lockout) = lockout
+        && let Some(until) = lockout.locked_until(user_name, chrono::Utc::now())?
+    {
+        result.decision = Decision::LockedOut { until };
+        println!("[!] Login refused for user '{user_name}': {}", result.decision);
+        result.timings.total = start_time.elapsed();
+        return Ok(result);
+    }
+
+    // 2. Capture a new embedding from the camera. This happens even for unknown
+    // names and stale templates, so neither the time taken nor a missing capture
+    // reveals which names are enrolled.
+    let capture_start = Instant::now();
+    let capture = capture_samples(model, liveness_challenge());
+    result.timings.capture = capture_start.elapsed();
+    let capture = match capture {
+        Ok(capture) => capture,
+        Err(e) => {
+            (result.decision, result.capture) = capture_failure(&e);
+            println!("[!] Login failed for user '{user_name}': {}", result.decision);
+            result.timings.total = start_time.elapsed();
+            return Ok(result);
+        }
+    };
+    let live_embedding = average_embedding(&capture.samples)?;
+    result.capture = Some(capture.stats);
+
+    // The replay guard runs first so every capture's frames are recorded
+    let rejection = match replay_failure(&capture.samples)? {
+        Some(decision) => Some(decision),
+        None => result.capture.as_ref().and_then(liveness_failure),
+    };
+    if let Some(decision) = rejection {
+        result.decision = decision;
+        println!("[!] Login failed for user '{user_name}': {}", result.decision);
+        result.timings.total = start_time.elapsed();
+        return Ok(result);
+    }
 
     if user_embeddings.is_empty() {
         println!("[!] No registered embeddings found for user '{user_name}'");
-        return Ok(false);
+        result.timings.total = start_time.elapsed();
+        return Ok(result);
     }
 
-    // 3. Compare the live embedding with each stored embedding
-    let mut best_match_similarity = 0.0;
-
-    let live_tensor = Tensor::new(live_embedding, &Device::Cpu)?.unsqueeze(0)?;
-    for record in user_embeddings {   
-        let stored_tensor = Tensor::new(record.embedding.as_slice(), &Device::Cpu)?.unsqueeze(0)?;
-        let similarity = cosine_similarity(&live_tensor, &stored_tensor)?;
-        println!("[*] Comparing with stored embedding (ID: {0}), similarity: {similarity:.4}", record.id);
-        if similarity > best_match_similarity {
-            best_match_similarity = similarity;
+    // Only templates produced by the current model and preprocessing are comparable
+    let current = model.fingerprint();
+    let (user_embeddings, stale): (Vec<EmbeddingRecord>, Vec<EmbeddingRecord>) = user_embeddings
+        .into_iter()
+        .partition(|record| record.stale_reason(current).is_none());
+
+    for record in &stale {
+        if let Some(reason) = record.stale_reason(current) {
+            println!("[!] Skipping stale embedding (ID: {}): {reason}", record.id);
         }
     }
 
+    if user_embeddings.is_empty() {
+        result.decision = Decision::StaleTemplates { count: stale.len() };
+        result.timings.total = start_time.elapsed();
+        return Ok(result);
+    }
 
-    let login_threshold = 0.7; 
+    // 3. Compare the live embedding with each stored embedding
+    let matching_start = Instant::now();
+    result.scores = score_templates(&live_embedding, &user_embeddings)?;
+    result.timings.matching = matching_start.elapsed();
 
-    if best_match_similarity > login_threshold {
-        println!("[+] Login successful for user '{user_name}' with similarity: {best_match_similarity:.4}");
-        Ok(true)
+    let best = result.best_score().cloned();
+    if let Some(best) = best.filter(|best| best.similarity > threshold) {
+        println!("[+] Login successful for user '{user_name}' with similarity: {:.4} (threshold {threshold:.4}, {threshold_source})",
+                 best.similarity);
+        result.decision = Decision::Accepted;
+        result.matched_id = Some(best.record_id);
     } else {
-        println!("[!] Login failed for user '{user_name}'. Best similarity: {best_match_similarity:.4}");
-        Ok(false)
+        println!("[!] Login failed for user '{user_name}'. Best similarity: {:.4} (threshold {threshold:.4}, {threshold_source})",
+                 result.best_score().map_or(f32::NAN, |best| best.similarity));
+        result.decision = Decision::Rejected;
     }
-}
 
+    result.timings.total = start_time.elapsed();
+    Ok(result)
+}
 
-fn normalize_l2(v: &Tensor) -> Result<Tensor> {
-    Ok(v.broadcast_div(&v.sqr()?.sum_keepdim(1)?.sqrt()?)?)
+/// Maps a failed capture to the decision it leads to, with the stats of a timed out capture.
+pub fn capture_failure(error: &anyhow::Error) -> (Decision, Option<CaptureStats>) {
+    match error.downcast_ref::<CaptureTimeout>() {
+        Some(timeout) => {
+            let decision = if timeout.last_rejection == Some(FrameRejection::NoFace) {
+                Decision::NoFace
+            } else {
+                Decision::CaptureTimeout
+            };
+            (decision, Some(CaptureStats::from(timeout)))
+        }
+        None => match error.downcast_ref::<ChallengeFailed>() {
+            Some(failed) => (Decision::ChallengeFailed { challenge: failed.challenge, reason: failed.reason.clone() }, None),
+            None => (Decision::CaptureFailed(format!("{error:#}")), None),
+        },
+    }
 }
 
+/// A fresh random challenge when the active liveness check is enabled.
+pub fn liveness_challenge() -> Option<Challenge> {
+    is_challenge_enabled().then(Challenge::random)
+}
 
+/// `LivenessFailed` when liveness checking is enabled and the capture scored below its threshold.
+pub fn liveness_failure(stats: &CaptureStats) -> Option<Decision> {
+    let threshold = get_liveness_threshold()?;
+    let report = stats.liveness.as_ref()?;
+    (report.score < threshold).then_some(Decision::LivenessFailed { score: report.score, threshold })
+}
 
+/// `ReplayDetected` when the replay guard is enabled and flags the capture's
+/// frames. The frames are recorded either way.
+pub fn replay_failure(samples: &[CapturedSample]) -> Result<Option<Decision>> {
+    let Some(guard) = get_replay_guard()? else {
+        return Ok(None);
+    };
+    let hashes: Vec<_> = samples.iter().filter_map(|sample| sample.frame_hash.clone()).collect();
+    Ok(guard.check_and_record(&hashes)?.map(Decision::ReplayDetected))
+}
 
-pub fn cosine_similarity(emb_a: &Tensor, emb_b: &Tensor) -> Result<f32> {
-    let emb_a = normalize_l2(emb_a)?;
-    let emb_b = normalize_l2(emb_b)?;
-    let similarity = emb_a.matmul(&emb_b.transpose(0, 1)?)?;
-    let similarity_value = similarity.squeeze(0)?.squeeze(0)?.to_vec0::<f32>()?;
-    Ok(similarity_value)
+// Scores the live embedding against every record, best first
+fn score_templates(live_embedding: &[f32], records: &[EmbeddingRecord]) -> Result<Vec<TemplateScore>> {
+    let templates = TemplateMatrix::build(records, live_embedding.len())?;
+    let scores: Vec<TemplateScore> = templates
+        .ranked(live_embedding)?
+        .into_iter()
+        .map(|(index, similarity)| TemplateScore { record_id: templates.record_id(index).to_string(), similarity })
+        .collect();
+    for score in &scores {
+        println!("[*] Compared with stored embedding (ID: {}), similarity: {:.4}", score.record_id, score.similarity);
+    }
+    Ok(scores)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn record(id: &str, embedding: Vec<f32>) -> EmbeddingRecord {
+        EmbeddingRecord {
+            id: id.to_string(),
+            name: "alice".to_string(),
+            embedding,
+            created_at: chrono::Utc::now(),
+            metadata: HashMap::new(),
+            model: None,
+        }
+    }
+
+    #[test]
+    fn templates_are_scored_best_first() -> Result<()> {
+        let records = [record("orthogonal", vec![0., 1.]), record("same", vec![2., 0.]), record("opposite", vec![-1., 0.])];
+
+        let scores = score_templates(&[1., 0.], &records)?;
+        let ids: Vec<&str> = scores.iter().map(|score| score.record_id.as_str()).collect();
+        assert_eq!(ids, ["same", "orthogonal", "opposite"]);
+        assert!((scores[0].similarity - 1.).abs() < 1e-6);
+        assert!((scores[2].similarity + 1.).abs() < 1e-6);
+        Ok(())
+    }
+}
@@ -1,32 +1,154 @@
+use anyhow::Context;
 use candle_core::Device;
+use clap::{Parser, Subcommand};
 use std::io::{self, Write};
+use std::process::ExitCode;
 
-mod image_utils;
-
-mod embeddings;
-mod config;
-mod camera;
-use embeddings::utils::build_model;
-mod login;
-use login::login;
-mod register;
+use face_auth::{camera, config, embeddings, identify, login, register, users};
+use embeddings::utils::{build_model, current_fingerprint, EmbeddingModel};
+use identify::{identify, IdentifyResult};
+use login::{login, Decision, MatchResult};
 use register::register;
-mod storage;
-use candle_nn::Func;
+use camera::camera_interactions::{CaptureStats, CaptureTimeout};
+
+// Process exit codes so shell scripts and service units can act on the outcome.
+const EXIT_AUTH_FAILED: u8 = 1;
+const EXIT_ERROR: u8 = 2;
+const EXIT_CAPTURE_TIMEOUT: u8 = 3;
+const EXIT_LOCKED_OUT: u8 = 4;
+
+#[derive(Parser)]
+#[command(name = "face-auth", version, about = "Face Authentication System")]
+struct Cli {
+    /// Path to the configuration file
+    #[arg(long, global = true, default_value = "config.yaml")]
+    config: String,
+
+    /// Capture without opening the preview window (overrides ui.preview)
+    #[arg(long, global = true)]
+    headless: bool,
+
+    // Runs the interactive prompt when no command is given
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Register a new user by capturing face embeddings
+    Register {
+        #[arg(long)]
+        name: String,
+    },
+    /// Authenticate an existing user (exit code 0 on success, 1 on rejection, 2 on error, 3 on capture timeout, 4 when locked out)
+    Login {
+        #[arg(long)]
+        name: String,
+    },
+    /// Identify who is in front of the camera without a name (same exit codes as login)
+    Identify {
+        /// Number of candidate users to list (defaults to identify.top_k)
+        #[arg(long)]
+        top_k: Option<usize>,
+    },
+    /// Manage registered users
+    Users {
+        #[command(subcommand)]
+        command: UsersCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum UsersCommand {
+    /// List registered users
+    List,
+    /// Delete every stored template of a user
+    Delete {
+        #[arg(long)]
+        name: String,
+    },
+    /// List users whose templates must be re-enrolled for the configured model
+    Stale,
+    /// Override the login threshold for one user
+    SetThreshold {
+        #[arg(long)]
+        name: String,
+        /// Minimum cosine similarity for this user
+        #[arg(long, required_unless_present = "clear", allow_negative_numbers = true)]
+        threshold: Option<f32>,
+        /// Remove the override and use auth.login_threshold again
+        #[arg(long, conflicts_with = "threshold")]
+        clear: bool,
+    },
+    /// Re-encrypt every template with the current key, after rotating keys or enabling encryption
+    Reencrypt,
+    /// Lift a user's login lockout and forget their failed attempts
+    Unlock {
+        #[arg(long)]
+        name: String,
+    },
+}
+
+pub fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(cli) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            if e.downcast_ref::<CaptureTimeout>().is_some() {
+                ExitCode::from(EXIT_CAPTURE_TIMEOUT)
+            } else {
+                ExitCode::from(EXIT_ERROR)
+            }
+        }
+    }
+}
+
+fn run(cli: Cli) -> anyhow::Result<ExitCode> {
+    config::init(&cli.config)?;
+    if cli.headless {
+        config::force_headless();
+    }
 
+    match cli.command {
+        None => {
+            run_interactive()?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Register { name }) => {
+            let model = build_model(config::get_model_name())?;
+            handle_register(&model, &name)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Login { name }) => {
+            let model = build_model(config::get_model_name())?;
+            let result = handle_login(&model, &name)?;
+            Ok(login_exit_code(&result.decision))
+        }
+        Some(Command::Identify { top_k }) => {
+            let model = build_model(config::get_model_name())?;
+            let result = handle_identify(&model, top_k.unwrap_or_else(config::get_identify_top_k))?;
+            Ok(login_exit_code(&result.decision))
+        }
+        Some(Command::Users { command }) => {
+            handle_users(command)?;
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
 
-pub fn main() -> anyhow::Result<()> {
+fn run_interactive() -> anyhow::Result<()> {
     println!("Face Authentication System");
     println!("Available commands:");
-    println!("  /register - Register a new user");
-    println!("  /login - Login with existing user");
-    println!("  /quit - Exit the application");
+    println!("  register - Register a new user");
+    println!("  login - Login with existing user");
+    println!("  identify - Identify who is in front of the camera");
+    println!("  quit - Exit the application");
     println!("Enter a command:");
 
     let _device = Device::Cpu;
     let model = build_model(config::get_model_name())?;
-    
-
 
     loop {
         // Print prompt
@@ -35,7 +157,10 @@ pub fn main() -> anyhow::Result<()> {
 
         // Read user input
         let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+        if io::stdin().read_line(&mut input)? == 0 {
+            // EOF, nothing more to read
+            break;
+        }
 
         // Trim whitespace and newlines
         let command = input.trim();
@@ -44,12 +169,24 @@ pub fn main() -> anyhow::Result<()> {
         match command {
             "register" => {
                 println!("Register command detected!");
-                // TODO: Implement registration logic
-                handle_register(&model)?;
+                if let Some(user_name) = prompt_user_name()?
+                    && let Err(e) = handle_register(&model, &user_name)
+                {
+                    eprintln!("{e:#}");
+                }
             }
             "login" => {
                 println!("Login command detected!");
-                handle_login(&model)?;
+                if let Some(user_name) = prompt_user_name()?
+                    && let Err(e) = handle_login(&model, &user_name)
+                {
+                    eprintln!("An error occurred during login: {e:#}");
+                }
+            }
+            "identify" => {
+                if let Err(e) = handle_identify(&model, config::get_identify_top_k()) {
+                    eprintln!("An error occurred during identification: {e:#}");
+                }
             }
             "quit" | "exit" => {
                 println!("Goodbye!");
@@ -61,7 +198,7 @@ pub fn main() -> anyhow::Result<()> {
             }
             _ => {
                 println!("Unknown command: {command}");
-                println!("Available commands: /register, /login, /quit");
+                println!("Available commands: register, login, identify, quit");
             }
         }
     }
@@ -69,54 +206,212 @@ pub fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn handle_register(model: &Func) -> anyhow::Result<()> {
-    println!("Registration process started...");
-    
-    // Get user name
+fn prompt_user_name() -> anyhow::Result<Option<String>> {
     print!("Enter user name: ");
     io::stdout().flush()?;
     let mut user_name = String::new();
     io::stdin().read_line(&mut user_name)?;
     let user_name = user_name.trim();
-    
+
     if user_name.is_empty() {
         println!("User name cannot be empty");
-        return Ok(());
+        return Ok(None);
+    }
+
+    Ok(Some(user_name.to_string()))
+}
+
+fn handle_register(model: &EmbeddingModel, user_name: &str) -> anyhow::Result<()> {
+    println!("Registration process started...");
+
+    if user_name.trim().is_empty() {
+        anyhow::bail!("User name cannot be empty");
     }
-    
+
     // Initialize storage
-    let storage_config = config::get_storage_config();
+    let storage_config = config::get_storage_config()?;
     let mut storage = storage_config.create_storage()?;
-    
-    register(model, &mut storage, user_name).map_err(|e| anyhow::anyhow!("Registration failed: {}", e))?;
+
+    register(model, &mut storage, user_name).context("Registration failed")?;
     println!("Registration completed successfully!");
     Ok(())
 }
 
-fn handle_login(model: &Func) -> anyhow::Result<()> {
+fn handle_login(model: &EmbeddingModel, user_name: &str) -> anyhow::Result<MatchResult> {
     println!("Login process started...");
-    
-    // Get user name
-    print!("Enter user name: ");
-    io::stdout().flush()?;
-    let mut user_name = String::new();
-    io::stdin().read_line(&mut user_name)?;
-    let user_name = user_name.trim();
-    
-    if user_name.is_empty() {
-        println!("User name cannot be empty");
-        return Ok(());
+
+    if user_name.trim().is_empty() {
+        anyhow::bail!("User name cannot be empty");
     }
-    
+
     // Initialize storage
-    let storage_config = config::get_storage_config();
+    let storage_config = config::get_storage_config()?;
+    let storage = storage_config.create_storage()?;
+
+    let result = login(model, &*storage, user_name)?;
+    print_match_result(&result);
+    Ok(result)
+}
+
+fn print_match_result(result: &MatchResult) {
+    let score = match result.best_score() {
+        Some(best) => format!("similarity {:.4}, ", best.similarity),
+        None => String::new(),
+    };
+    let threshold = format!("{} threshold {:.4}", result.threshold_source, result.threshold);
+
+    if result.accepted() {
+        println!("Login successful for '{}'! ({score}{threshold}, template {})",
+                 result.user_name, result.matched_id.as_deref().unwrap_or("-"));
+    } else {
+        println!("Login failed for '{}': {} ({score}{threshold})", result.user_name, result.decision);
+    }
+
+    if let Some(capture) = &result.capture {
+        let last_rejection = match &capture.last_rejection {
+            Some(rejection) => format!(", last: {rejection}"),
+            None => String::new(),
+        };
+        println!("Capture: {} sample(s), {} frame(s) rejected{last_rejection}, {:.2}s (inference {:.2}s); matching {:.3}s",
+                 capture.samples, capture.rejected_frames, capture.duration.as_secs_f32(),
+                 capture.inference.as_secs_f32(), result.timings.matching.as_secs_f32());
+        print_liveness(capture);
+    }
+}
+
+fn print_liveness(capture: &CaptureStats) {
+    if let Some(challenge) = &capture.challenge {
+        println!("Liveness challenge: {challenge} (completed)");
+    }
+    if let Some(liveness) = &capture.liveness {
+        let motion = liveness.motion.map_or("-".to_string(), |motion| format!("{motion:.2}"));
+        println!("Liveness: score {:.2} (motion {motion}, moire {:.1}, texture {:.3}, glare {:.3})",
+                 liveness.score, liveness.moire, liveness.texture, liveness.glare);
+    }
+}
+
+fn handle_identify(model: &EmbeddingModel, top_k: usize) -> anyhow::Result<IdentifyResult> {
+    if top_k == 0 {
+        anyhow::bail!("--top-k must be at least 1");
+    }
+
+    let storage_config = config::get_storage_config()?;
     let storage = storage_config.create_storage()?;
-    
-    match login(model, &*storage, user_name) {
-        Ok(true) => println!("Login successful!"),
-        Ok(false) => println!("Login failed."),
-        Err(e) => eprintln!("An error occurred during login: {e}"),
+
+    let result = identify(model, &*storage, top_k)?;
+    match result.identified() {
+        Some(best) => println!("Identified '{}' (similarity {:.4}, {} threshold {:.4})",
+                               best.user_name, best.similarity, best.threshold_source, best.threshold),
+        None if matches!(result.decision, Decision::LockedOut { .. }) => println!("Refused: {}", result.decision),
+        None => println!("Unknown: {}", result.decision),
     }
-    
+    for (rank, candidate) in result.candidates.iter().enumerate() {
+        let verdict = if candidate.passes_threshold() { "match" } else { "below threshold" };
+        println!("  {}. {}\t{:.4}\t{} threshold {:.4}\t{verdict}",
+                 rank + 1, candidate.user_name, candidate.similarity, candidate.threshold_source, candidate.threshold);
+    }
+    if let Some(capture) = &result.capture {
+        println!("Capture: {} sample(s), {} frame(s) rejected, {:.2}s; matching {:.3}s",
+                 capture.samples, capture.rejected_frames, capture.duration.as_secs_f32(),
+                 result.timings.matching.as_secs_f32());
+        print_liveness(capture);
+    }
+    Ok(result)
+}
+
+fn login_exit_code(decision: &Decision) -> ExitCode {
+    match decision {
+        Decision::Accepted => ExitCode::SUCCESS,
+        Decision::Rejected
+        | Decision::UnknownUser
+        | Decision::LivenessFailed { .. }
+        | Decision::ChallengeFailed { .. }
+        | Decision::ReplayDetected(_) => ExitCode::from(EXIT_AUTH_FAILED),
+        Decision::NoFace | Decision::CaptureTimeout => ExitCode::from(EXIT_CAPTURE_TIMEOUT),
+        Decision::StaleTemplates { .. } | Decision::CaptureFailed(_) => ExitCode::from(EXIT_ERROR),
+        Decision::LockedOut { .. } => ExitCode::from(EXIT_LOCKED_OUT),
+    }
+}
+
+fn handle_users(command: UsersCommand) -> anyhow::Result<()> {
+    let storage_config = config::get_storage_config()?;
+    // Runs before the storage is opened, which refuses records it can't decrypt
+    if let UsersCommand::Reencrypt = command {
+        let rewritten = storage_config.reencrypt()?;
+        println!("Re-encrypted {rewritten} template(s) with the current key");
+        return Ok(());
+    }
+    // Only touches the attempts file, so it works for names that are no longer registered
+    if let UsersCommand::Unlock { name } = &command {
+        let lockout = config::get_lockout_tracker()?.context("lockout.enabled is false; no attempts are tracked")?;
+        if lockout.unlock(name)? {
+            println!("Unlocked '{name}' and cleared their failed attempts");
+        } else {
+            println!("No failed attempts recorded for '{name}'");
+        }
+        return Ok(());
+    }
+    let mut storage = storage_config.create_storage()?;
+
+    match command {
+        UsersCommand::List => {
+            let users = users::list_users(&*storage, &config::get_threshold_overrides()?)?;
+            if users.is_empty() {
+                println!("No registered users");
+            }
+            let lockout = config::get_lockout_tracker()?;
+            let now = chrono::Utc::now();
+            for user in users {
+                let threshold = user
+                    .login_threshold
+                    .map_or_else(|| "default threshold".to_string(), |threshold| format!("threshold {threshold}"));
+                let locked = match &lockout {
+                    Some(lockout) => lockout.locked_until(&user.name, now)?,
+                    None => None,
+                };
+                let locked = locked.map_or_else(String::new, |until| format!("\tlocked until {}", until.to_rfc3339()));
+                println!("{}\t{} template(s)\tlast enrolled {}\t{threshold}{locked}",
+                         user.name, user.template_count, user.last_enrolled.to_rfc3339());
+            }
+        }
+        UsersCommand::Delete { name } => {
+            let deleted = users::delete_user(storage.as_mut(), &name)?;
+            if deleted == 0 {
+                anyhow::bail!("No registered user named '{name}'");
+            }
+            println!("Deleted {deleted} template(s) for user '{name}'");
+            if let Some(threshold) = config::get_threshold_overrides()?.get(&name)? {
+                println!("Their login threshold override ({threshold}) is kept for a new registration; \
+                          remove it with `face-auth users set-threshold --name {name} --clear`");
+            }
+        }
+        UsersCommand::Stale => {
+            let current = current_fingerprint(config::get_model_name())?;
+            let stale = users::stale_users(&*storage, &current)?;
+            if stale.is_empty() {
+                println!("All templates match model '{}'", current.model_id);
+            }
+            for user in stale {
+                println!("{}\t{}/{} stale template(s)\t{}",
+                         user.name, user.stale_count, user.template_count, user.reasons.join("; "));
+            }
+        }
+        UsersCommand::SetThreshold { name, threshold, clear: _ } => {
+            // Without --threshold, clap guarantees --clear was given
+            let overrides = config::get_threshold_overrides()?;
+            if !users::set_threshold(storage.as_mut(), &overrides, &name, threshold)? {
+                match threshold {
+                    Some(_) => anyhow::bail!("No registered user named '{name}'"),
+                    None => anyhow::bail!("No login threshold override set for '{name}'"),
+                }
+            }
+            match threshold {
+                Some(threshold) => println!("Login threshold for '{name}' set to {threshold}"),
+                None => println!("Login threshold override for '{name}' removed"),
+            }
+        }
+        UsersCommand::Reencrypt | UsersCommand::Unlock { .. } => unreachable!("handled before the storage is opened"),
+    }
+
     Ok(())
-}
\ No newline at end of file
+}
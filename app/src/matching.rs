@@ -0,0 +1,210 @@
+use crate::storage::vector_storage::{EmbeddingRecord, EmbeddingStorage, ModelFingerprint};
+use anyhow::Result;
+use candle_core::{Device, Tensor};
+use std::sync::Mutex;
+
+/// L2-normalised templates of one storage snapshot, stacked into an (N, D)
+/// matrix so a query is scored against all of them with a single matmul.
+pub struct TemplateMatrix {
+    record_ids: Vec<String>,
+    matrix: Option<Tensor>,
+    dim: usize,
+}
+
+impl TemplateMatrix {
+    /// Stacks `records`, which must all have `dim`-d embeddings.
+    pub fn build<'a>(records: impl IntoIterator<Item = &'a EmbeddingRecord>, dim: usize) -> Result<Self> {
+        let mut record_ids = Vec::new();
+        let mut data = Vec::new();
+
+        for record in records {
+            if record.embedding.len() != dim {
+                anyhow::bail!("Embedding '{}' has {} values, expected {dim}", record.id, record.embedding.len());
+            }
+            data.extend(normalized(&record.embedding));
+            record_ids.push(record.id.clone());
+        }
+
+        let matrix = if record_ids.is_empty() {
+            None
+        } else {
+            Some(Tensor::from_vec(data, (record_ids.len(), dim), &Device::Cpu)?)
+        };
+        Ok(TemplateMatrix { record_ids, matrix, dim })
+    }
+
+    pub fn record_id(&self, index: usize) -> &str {
+        &self.record_ids[index]
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    /// Cosine similarity of `query` to every template, in insertion order.
+    pub fn score(&self, query: &[f32]) -> Result<Vec<f32>> {
+        if query.len() != self.dim {
+            anyhow::bail!("Query has {} values, templates have {}", query.len(), self.dim);
+        }
+        let Some(matrix) = &self.matrix else {
+            return Ok(Vec::new());
+        };
+
+        let query = Tensor::from_vec(normalized(query), (self.dim, 1), &Device::Cpu)?;
+        Ok(matrix.matmul(&query)?.squeeze(1)?.to_vec1::<f32>()?)
+    }
+
+    /// Indices and scores of all templates, best first.
+    pub fn ranked(&self, query: &[f32]) -> Result<Vec<(usize, f32)>> {
+        let mut ranked: Vec<(usize, f32)> = self.score(query)?.into_iter().enumerate().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(ranked)
+    }
+}
+
+/// The template matrix of a storage, built on the first search and reused until
+/// the storage's generation changes, i.e. until records are stored or deleted.
+#[derive(Default)]
+pub struct TemplateCache {
+    cached: Mutex<Option<CachedTemplates>>,
+}
+
+struct CachedTemplates {
+    generation: u64,
+    // Only templates comparable with this fingerprint are in the matrix
+    fingerprint: ModelFingerprint,
+    matrix: TemplateMatrix,
+}
+
+impl TemplateCache {
+    /// Exact top-k search over the records of `storage` comparable with
+    /// `current`, best first.
+    pub fn nearest(
+        &self,
+        storage: &dyn EmbeddingStorage,
+        query: &[f32],
+        k: usize,
+        current: &ModelFingerprint,
+    ) -> Result<Vec<(EmbeddingRecord, f32)>> {
+        let generation = storage.generation()?;
+        let mut cached = self.cached.lock().map_err(|_| anyhow::anyhow!("Template cache lock poisoned"))?;
+        let templates = match cached.take() {
+            Some(templates)
+                if templates.generation == generation
+                    && templates.fingerprint == *current
+                    && templates.matrix.dim() == query.len() =>
+            {
+                cached.insert(templates)
+            }
+            _ => {
+                let records = storage.get_all_embeddings()?;
+                let comparable = records
+                    .iter()
+                    .filter(|record| record.embedding.len() == query.len() && record.stale_reason(current).is_none());
+                let matrix = TemplateMatrix::build(comparable, query.len())?;
+                cached.insert(CachedTemplates { generation, fingerprint: current.clone(), matrix })
+            }
+        };
+        let matrix = &templates.matrix;
+
+        // Only the hits are fetched again, so the cache holds no copy of the records
+        let mut hits = Vec::with_capacity(k);
+        for (index, similarity) in matrix.ranked(query)?.into_iter().take(k) {
+            if let Some(record) = storage.get_embedding(matrix.record_id(index))? {
+                hits.push((record, similarity));
+            }
+        }
+        Ok(hits)
+    }
+}
+
+// Zero vectors stay zero instead of turning into NaNs
+fn normalized(embedding: &[f32]) -> Vec<f32> {
+    let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt().max(f32::EPSILON);
+    embedding.iter().map(|v| v / norm).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::local_file_vector_storage::LocalFileVectorStorage;
+    use std::collections::HashMap;
+
+    fn fingerprint(dim: usize) -> ModelFingerprint {
+        ModelFingerprint {
+            model_id: "test".to_string(),
+            weights_sha256: "0".to_string(),
+            preprocessing: "none".to_string(),
+            embedding_dim: dim,
+        }
+    }
+
+    fn record(name: &str, embedding: Vec<f32>) -> EmbeddingRecord {
+        EmbeddingRecord {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            model: Some(fingerprint(embedding.len())),
+            embedding,
+            created_at: chrono::Utc::now(),
+            metadata: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn scores_match_pairwise_cosine_similarity() -> Result<()> {
+        let records = vec![record("a", vec![3., 4.]), record("b", vec![0., 2.]), record("c", vec![-1., 0.]), record("z", vec![0., 0.])];
+        let matrix = TemplateMatrix::build(&records, 2)?;
+
+        let scores = matrix.score(&[1., 0.])?;
+        let expected = [0.6, 0., -1., 0.];
+        for (score, expected) in scores.iter().zip(expected) {
+            assert!((score - expected).abs() < 1e-6, "{score} != {expected}");
+        }
+
+        let ranked = matrix.ranked(&[1., 0.])?;
+        assert_eq!(matrix.record_id(ranked[0].0), records[0].id);
+        assert_eq!(matrix.record_id(ranked[3].0), records[2].id);
+
+        assert!(TemplateMatrix::build(&records, 3).is_err());
+        assert!(TemplateMatrix::build([], 2)?.score(&[1., 0.])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn cached_matrix_follows_writes_of_every_process() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("face_auth_{}.json", uuid::Uuid::new_v4()));
+        let current = fingerprint(2);
+        let result = (|| {
+            let open = || LocalFileVectorStorage::new(path.to_string_lossy().into_owned(), false);
+            let (mut storage, mut other) = (open()?, open()?);
+            storage.store_embeddings(vec![record("a", vec![1., 0.]), record("b", vec![0., 1.]), record("c", vec![1., 0., 0.])])?;
+
+            // Unchanged storage keeps its generation, so the matrix is reused
+            let generation = storage.generation()?;
+            assert_eq!(storage.nearest(&[1., 0.1], 5, &current)?.len(), 2);
+            assert_eq!(storage.generation()?, generation);
+
+            // Writes through this handle and through another one both show up
+            storage.store_embedding(record("d", vec![1., 0.05]))?;
+            assert_eq!(storage.nearest(&[1., 0.05], 1, &current)?[0].0.name, "d");
+            let e = record("e", vec![1., 0.06]);
+            other.store_embedding(e.clone())?;
+            assert_eq!(storage.nearest(&[1., 0.06], 1, &current)?[0].0.name, "e");
+            other.delete_embedding(&e.id)?;
+            assert_eq!(storage.nearest(&[1., 0.06], 5, &current)?.len(), 3);
+
+            // Stale templates closer to the query don't crowd out comparable ones
+            let mut legacy = record("f", vec![1., 0.]);
+            legacy.model = None;
+            let mut retrained = record("g", vec![1., 0.]);
+            retrained.model = Some(ModelFingerprint { weights_sha256: "1".to_string(), ..fingerprint(2) });
+            storage.store_embeddings(vec![legacy, retrained])?;
+            let names: Vec<String> = storage.nearest(&[1., 0.], 2, &current)?.into_iter().map(|(record, _)| record.name).collect();
+            assert_eq!(names, ["a", "d"]);
+            Ok(())
+        })();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}.lock", path.display()));
+        result
+    }
+}
@@ -1,35 +1,74 @@
 use crate::storage::vector_storage::{EmbeddingRecord, EmbeddingStorage};
-use candle_nn::Func;
+use crate::embeddings::utils::EmbeddingModel;
+use crate::config::is_sample_storage_enabled;
 use anyhow::Result;
+use std::collections::HashMap;
 use uuid::Uuid;
-use crate::camera::camera_interactions::{capture_and_compute_average_embedding};
+use crate::camera::camera_interactions::{average_embedding, capture_samples};
+use crate::login::liveness_failure;
 
-pub fn register(model: &Func, storage: &mut Box<dyn EmbeddingStorage>, user_name: &str) -> Result<()> {
+pub fn register(model: &EmbeddingModel, storage: &mut Box<dyn EmbeddingStorage>, user_name: &str) -> Result<()> {
     println!("[*] Registering user '{user_name}'");
 
-    // Capture frames and compute the average embedding using the new camera module
-    let avg_embedding = capture_and_compute_average_embedding(model)?;
+    // Capture frames and keep every sample embedding
+    let capture = capture_samples(model, None)?;
+    // Enrolling a photo would let the same photo log in later
+    if let Some(decision) = liveness_failure(&capture.stats) {
+        anyhow::bail!("Registration of '{user_name}' {decision}");
+    }
+    let samples = capture.samples;
+    let avg_embedding = average_embedding(&samples)?;
+
+    // All records of one enrollment share its ID
+    let enrollment_id = Uuid::new_v4().to_string();
+    let enrolled_at = chrono::Utc::now();
+    let enrollment_metadata = |record_type: &str| {
+        let mut meta = HashMap::new();
+        meta.insert("type".to_string(), record_type.to_string());
+        meta.insert("enrollment_id".to_string(), enrollment_id.clone());
+        meta
+    };
 
-    // Store the average embedding record
-    let avg_record = EmbeddingRecord {
+    let mut records = Vec::new();
+    if is_sample_storage_enabled() {
+        for (index, sample) in samples.iter().enumerate() {
+            let mut metadata = enrollment_metadata("sample");
+            metadata.insert("sample_index".to_string(), index.to_string());
+            metadata.insert("captured_at".to_string(), sample.captured_at.to_rfc3339());
+            metadata.insert("quality".to_string(), serde_json::to_string(&sample.quality)?);
+            records.push(EmbeddingRecord {
+                id: Uuid::new_v4().to_string(),
+                name: user_name.to_string(),
+                embedding: sample.embedding.clone(),
+                created_at: enrolled_at,
+                metadata,
+                model: Some(model.fingerprint().clone()),
+            });
+        }
+    }
+
+    // The centroid of the enrollment
+    let mut metadata = enrollment_metadata("average");
+    metadata.insert("sample_count".to_string(), samples.len().to_string());
+    let frame_quality: Vec<_> = samples.iter().map(|sample| &sample.quality).collect();
+    metadata.insert("frame_quality".to_string(), serde_json::to_string(&frame_quality)?);
+    records.push(EmbeddingRecord {
         id: Uuid::new_v4().to_string(),
         name: user_name.to_string(),
-        embedding: avg_embedding.clone(),
-        created_at: chrono::Utc::now(),
-        metadata: {
-            let mut meta = std::collections::HashMap::new();
-            meta.insert("type".to_string(), "average".to_string());
-            meta.insert("sample_count".to_string(), "unknown".to_string()); // This info is now in the camera module
-            meta
-        },
-    };
+        embedding: avg_embedding,
+        created_at: enrolled_at,
+        metadata,
+        model: Some(model.fingerprint().clone()),
+    });
 
-    if let Err(e) = storage.store_embedding(avg_record) {
-        eprintln!("Failed to store average embedding: {e}");
-        Err(e)
-    } else {
-        println!("[*] Stored average embedding for user '{}' (length: {})", 
-                user_name, avg_embedding.len());
-        Ok(())
+    // Stored as one batch, so a failure never leaves a partial enrollment
+    let record_count = records.len();
+    if let Err(e) = storage.store_embeddings(records) {
+        eprintln!("Failed to store embeddings: {e}");
+        return Err(e);
     }
+
+    println!("[*] Stored {record_count} template(s) for user '{user_name}' (enrollment {enrollment_id}, {} samples)",
+             samples.len());
+    Ok(())
 }
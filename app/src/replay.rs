@@ -0,0 +1,213 @@
+//! Replay guard: rejects captures whose frames are frozen, or were already seen
+//! in an earlier attempt, e.g. a looped recording fed into the camera server.
+use crate::image_utils::frame_hash::{FrameHash, PerceptualHash};
+use crate::storage::json_state;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayIssue {
+    /// Byte-identical frames within one capture; a live camera never repeats exactly.
+    FrozenFrames { duplicates: usize },
+    /// Consecutive frames replaying the frames of an earlier attempt in order.
+    SeenBefore { frames: usize, first_seen: DateTime<Utc> },
+}
+
+impl fmt::Display for ReplayIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayIssue::FrozenFrames { duplicates } => {
+                write!(f, "{duplicates} frame(s) repeated exactly within the capture (frozen or looped stream)")
+            }
+            ReplayIssue::SeenBefore { frames, first_seen } => {
+                write!(f, "{frames} consecutive frame(s) replay an earlier attempt at {}", first_seen.to_rfc3339())
+            }
+        }
+    }
+}
+
+/// The recent frames of login and identify attempts, in a JSON file shared by
+/// every process.
+pub struct ReplayGuard {
+    path: PathBuf,
+    history_size: usize,
+    max_distance: u32,
+    min_sequence: usize,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ReplayHistory {
+    // Oldest first
+    frames: VecDeque<SeenFrame>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SeenFrame {
+    sha256: String,
+    perceptual: PerceptualHash,
+    // Shared by every frame of one attempt
+    seen_at: DateTime<Utc>,
+}
+
+impl ReplayGuard {
+    /// Keeps the last `history_size` frames. Frames within `max_distance` bits
+    /// of a recorded perceptual hash match it; 0 only matches exact copies. A
+    /// capture is a replay once `min_sequence` consecutive frames (or all of a
+    /// shorter capture) match an earlier attempt's frames in their recorded order.
+    pub fn new(path: PathBuf, history_size: usize, max_distance: u32, min_sequence: usize) -> Self {
+        ReplayGuard { path, history_size, max_distance, min_sequence }
+    }
+
+    /// Checks the frames of a capture and records them, so reusing them in a
+    /// later attempt is rejected too.
+    pub fn check_and_record(&self, frames: &[FrameHash]) -> Result<Option<ReplayIssue>> {
+        let mut unique = HashSet::new();
+        let duplicates = frames.iter().filter(|frame| !unique.insert(frame.exact)).count();
+
+        json_state::update(&self.path, |history: &mut ReplayHistory| {
+            let issue = if duplicates > 0 {
+                Some(ReplayIssue::FrozenFrames { duplicates })
+            } else {
+                self.find_seen(history, frames)
+            };
+
+            let now = Utc::now();
+            for frame in frames {
+                let sha256 = hex::encode(frame.exact);
+                if !history.frames.iter().any(|seen| seen.sha256 == sha256) {
+                    history.frames.push_back(SeenFrame { sha256, perceptual: frame.perceptual, seen_at: now });
+                }
+            }
+            while history.frames.len() > self.history_size {
+                history.frames.pop_front();
+            }
+            issue
+        })
+    }
+
+    // The longest run of consecutive frames that replays one earlier attempt.
+    // Every step of the run must move on to a later recorded frame that is
+    // clearly different from the previous one: the frames of a still face all
+    // look alike, so matching them in any order says nothing about a replay.
+    fn find_seen(&self, history: &ReplayHistory, frames: &[FrameHash]) -> Option<ReplayIssue> {
+        let required = self.min_sequence.clamp(1, frames.len().max(1));
+        let mut attempts: BTreeMap<DateTime<Utc>, Vec<&SeenFrame>> = BTreeMap::new();
+        for seen in &history.frames {
+            attempts.entry(seen.seen_at).or_default().push(seen);
+        }
+
+        let mut longest: Option<(usize, DateTime<Utc>)> = None;
+        for (seen_at, recorded) in &attempts {
+            // Length of the run ending at the previous frame, per recorded frame it matched
+            let mut previous = vec![0usize; recorded.len()];
+            for frame in frames {
+                let sha256 = hex::encode(frame.exact);
+                let current: Vec<usize> = recorded
+                    .iter()
+                    .enumerate()
+                    .map(|(position, seen)| {
+                        let exact = seen.sha256 == sha256;
+                        if !exact && seen.perceptual.distance(&frame.perceptual) > self.max_distance {
+                            return 0;
+                        }
+                        let run = (0..position)
+                            .filter(|&earlier| {
+                                exact || recorded[earlier].perceptual.distance(&seen.perceptual) > self.max_distance
+                            })
+                            .map(|earlier| previous[earlier])
+                            .max()
+                            .unwrap_or(0);
+                        run + 1
+                    })
+                    .collect();
+
+                let run = current.iter().copied().max().unwrap_or(0);
+                if run >= required && longest.is_none_or(|(frames, _)| run > frames) {
+                    longest = Some((run, *seen_at));
+                }
+                previous = current;
+            }
+        }
+        longest.map(|(frames, first_seen)| ReplayIssue::SeenBefore { frames, first_seen })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_utils::frame_hash::hash_frame;
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    const A: [u64; 4] = [1, 0, 0, 0];
+    const B: [u64; 4] = [u64::MAX, 0, 0, 0];
+    const C: [u64; 4] = [0, u64::MAX, 0, 0];
+
+    fn frame(id: u8, perceptual: [u64; 4]) -> FrameHash {
+        FrameHash { exact: [id; 32], perceptual: PerceptualHash(perceptual) }
+    }
+
+    fn with_guard(guard: impl FnOnce(&ReplayGuard) -> Result<()>, history_size: usize, min_sequence: usize) -> Result<()> {
+        let path = std::env::temp_dir().join(format!("face_auth_replay_{}.json", uuid::Uuid::new_v4()));
+        let result = guard(&ReplayGuard::new(path.clone(), history_size, 8, min_sequence));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}.lock", path.display()));
+        result
+    }
+
+    #[test]
+    fn frozen_and_replayed_sequences_are_rejected() -> Result<()> {
+        with_guard(|guard| {
+            assert_eq!(guard.check_and_record(&[frame(1, A), frame(2, B), frame(3, C)])?, None);
+
+            // A byte-identical repeat within one capture
+            let frozen = guard.check_and_record(&[frame(10, [0, 0, 1, 0]), frame(10, [0, 0, 1, 0])])?;
+            assert_eq!(frozen, Some(ReplayIssue::FrozenFrames { duplicates: 1 }));
+
+            // A re-encoded copy of earlier frames differs in a few bits only
+            let replayed = guard.check_and_record(&[frame(11, [3, 0, 0, 0]), frame(12, [u64::MAX, 1, 0, 0])])?;
+            assert!(matches!(replayed, Some(ReplayIssue::SeenBefore { frames: 2, .. })));
+
+            // The same frames out of their recorded order aren't a replay
+            assert_eq!(guard.check_and_record(&[frame(13, C), frame(14, A)])?, None);
+
+            // The history only keeps the most recent frames
+            assert_eq!(guard.check_and_record(&[frame(2, B), frame(3, C)])?, None);
+            Ok(())
+        }, 5, 2)
+    }
+
+    // A still scene as a camera films it: shifted by a pixel or two as the
+    // user sways, with fresh sensor noise in every frame
+    fn scene(shift: u32, seed: u32) -> FrameHash {
+        hash_frame(&DynamicImage::ImageRgb8(RgbImage::from_fn(160, 160, |x, y| {
+            let (x, y) = (x + shift, y + shift / 2);
+            let mut noise = x.wrapping_mul(374_761_393) ^ y.wrapping_mul(668_265_263) ^ seed.wrapping_mul(2_246_822_519);
+            noise = (noise ^ (noise >> 13)).wrapping_mul(1_274_126_177) >> 30;
+            let v = (((x as f32 / 9.).sin() + (y as f32 / 13.).cos()) * 50. + 120.) as u32 + noise;
+            Rgb([v as u8, (v * 3 / 4) as u8, (v / 2) as u8])
+        })))
+    }
+
+    #[test]
+    fn still_scenes_are_no_replays_but_recordings_are() -> Result<()> {
+        with_guard(|guard| {
+            // Two logins in front of the same background, a few minutes apart
+            let first: Vec<FrameHash> = [0, 1, 0, 2, 1].iter().enumerate().map(|(i, &shift)| scene(shift, i as u32)).collect();
+            let second: Vec<FrameHash> = [1, 0, 2, 1, 0].iter().enumerate().map(|(i, &shift)| scene(shift, 10 + i as u32)).collect();
+            assert!(first.windows(2).all(|pair| pair[0].perceptual.distance(&pair[1].perceptual) <= 8));
+            assert_eq!(guard.check_and_record(&first)?, None);
+            assert_eq!(guard.check_and_record(&second)?, None);
+
+            // A recording of a moving face, then the same recording played again
+            let recording: Vec<FrameHash> = [0, 12, 24, 36, 48].iter().map(|&shift| scene(shift, 20)).collect();
+            assert_eq!(guard.check_and_record(&recording)?, None);
+            let replay: Vec<FrameHash> = [12, 24, 36].iter().map(|&shift| scene(shift, 30)).collect();
+            assert!(matches!(guard.check_and_record(&replay)?, Some(ReplayIssue::SeenBefore { frames: 3, .. })));
+            Ok(())
+        }, 100, 3)
+    }
+}
@@ -1,2 +1,7 @@
 pub mod vector_storage;
-pub mod local_file_vector_storage;
\ No newline at end of file
+pub mod local_file_vector_storage;
+pub mod hnsw_index;
+pub mod hnsw_indexed_storage;
+pub mod sqlite_vector_storage;
+pub mod encrypted_storage;
+pub mod json_state;
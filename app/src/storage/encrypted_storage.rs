@@ -0,0 +1,364 @@
+use super::vector_storage::{EmbeddingRecord, EmbeddingStorage, ModelFingerprint};
+use crate::matching::TemplateCache;
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+
+// Metadata keys of a sealed record as the inner storage sees it
+const SEALED_KEY: &str = "sealed";
+const KEY_ID_KEY: &str = "key_id";
+const NONCE_LEN: usize = 12;
+
+/// A 256-bit master key and the subkeys derived from it.
+pub struct StorageKey {
+    id: String,
+    cipher: ChaCha20Poly1305,
+    name_key: [u8; 32],
+}
+
+impl StorageKey {
+    pub fn from_bytes(master: &[u8]) -> Result<Self> {
+        if master.len() != 32 {
+            anyhow::bail!("Storage keys must be 32 bytes, got {}", master.len());
+        }
+        Ok(StorageKey {
+            id: hex::encode(&derive(master, "face-auth key id")[..8]),
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&derive(master, "face-auth record encryption"))),
+            name_key: derive(master, "face-auth name index"),
+        })
+    }
+
+    /// Reads a key written as 64 hex characters, e.g. by `openssl rand -hex 32`.
+    pub fn parse_hex(text: &str) -> Result<Self> {
+        let bytes = hex::decode(text.trim()).context("Storage key is not valid hex")?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read storage key file '{path}'"))?;
+        warn_if_readable_by_others(path);
+        Self::parse_hex(&text).with_context(|| format!("Invalid storage key in '{path}'"))
+    }
+
+    pub fn from_env(variable: &str) -> Result<Self> {
+        let text = std::env::var(variable).with_context(|| format!("Storage key variable {variable} is not set"))?;
+        Self::parse_hex(&text).with_context(|| format!("Invalid storage key in {variable}"))
+    }
+
+    // Deterministic stand-in for the user name, so per-user lookups keep working
+    fn name_tag(&self, name: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.name_key).expect("HMAC accepts any key length");
+        mac.update(name.as_bytes());
+        format!("user-{}", hex::encode(mac.finalize().into_bytes()))
+    }
+}
+
+fn derive(master: &[u8], purpose: &str) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(master).expect("HMAC accepts any key length");
+    mac.update(purpose.as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(unix)]
+fn warn_if_readable_by_others(path: &str) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(metadata) = fs::metadata(path)
+        && metadata.permissions().mode() & 0o077 != 0
+    {
+        eprintln!("Warning: storage key file '{path}' is accessible by other users; chmod 600 it");
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_readable_by_others(_path: &str) {}
+
+/// The key new records are sealed with, plus older keys that can still open
+/// records until they are re-encrypted.
+pub struct KeyRing {
+    current: StorageKey,
+    previous: Vec<StorageKey>,
+}
+
+impl KeyRing {
+    pub fn new(current: StorageKey, previous: Vec<StorageKey>) -> Self {
+        KeyRing { current, previous }
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &StorageKey> {
+        std::iter::once(&self.current).chain(&self.previous)
+    }
+
+    fn find(&self, id: &str) -> Option<&StorageKey> {
+        self.keys().find(|key| key.id == id)
+    }
+}
+
+// Everything about a record except its ID is encrypted. The creation time is
+// also kept in the clear for the backend, but only the sealed copy is trusted.
+#[derive(Serialize, Deserialize)]
+struct SealedFields {
+    name: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+    embedding: Vec<f32>,
+    metadata: HashMap<String, String>,
+    model: Option<ModelFingerprint>,
+}
+
+/// Encrypts templates before handing them to another storage. Names, vectors,
+/// metadata and fingerprints are sealed with ChaCha20-Poly1305; the record ID
+/// and key ID are authenticated too, so a sealed value can't be moved to
+/// another record. A record that fails authentication is an error.
+pub struct EncryptedStorage {
+    inner: Box<dyn EmbeddingStorage>,
+    keys: KeyRing,
+    templates: TemplateCache,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Box<dyn EmbeddingStorage>, keys: KeyRing) -> Self {
+        EncryptedStorage { inner, keys, templates: TemplateCache::default() }
+    }
+
+    fn seal(&self, record: EmbeddingRecord) -> Result<EmbeddingRecord> {
+        let key = &self.keys.current;
+        let fields = SealedFields {
+            name: record.name.clone(),
+            created_at: record.created_at,
+            embedding: record.embedding,
+            metadata: record.metadata,
+            model: record.model,
+        };
+        let plaintext = serde_json::to_vec(&fields)?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(&record.id, &key.id);
+        let ciphertext = key
+            .cipher
+            .encrypt(&nonce, Payload { msg: &plaintext, aad: &aad })
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt embedding '{}'", record.id))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(EmbeddingRecord {
+            name: key.name_tag(&record.name),
+            embedding: Vec::new(),
+            created_at: record.created_at,
+            metadata: HashMap::from([
+                (KEY_ID_KEY.to_string(), key.id.clone()),
+                (SEALED_KEY.to_string(), BASE64.encode(sealed)),
+            ]),
+            model: None,
+            id: record.id,
+        })
+    }
+
+    fn open(&self, record: EmbeddingRecord) -> Result<EmbeddingRecord> {
+        let id = &record.id;
+        let (Some(key_id), Some(sealed)) = (record.metadata.get(KEY_ID_KEY), record.metadata.get(SEALED_KEY)) else {
+            anyhow::bail!("Embedding '{id}' is stored unencrypted; run `face-auth users reencrypt` to encrypt it");
+        };
+        let key = self.keys.find(key_id).with_context(|| {
+            format!("Embedding '{id}' was encrypted with unknown key '{key_id}'; add that key to storage.encryption.previous_key_files")
+        })?;
+
+        let tampered = || format!("Embedding '{id}' failed authentication: the storage was tampered with or corrupted");
+        let sealed = BASE64.decode(sealed).with_context(tampered)?;
+        if sealed.len() < NONCE_LEN {
+            anyhow::bail!(tampered());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let aad = associated_data(id, key_id);
+        let plaintext = key
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| anyhow::anyhow!(tampered()))?;
+        let fields: SealedFields = serde_json::from_slice(&plaintext).with_context(tampered)?;
+
+        // The name tag sits outside the ciphertext; it must belong to the sealed name
+        if record.name != key.name_tag(&fields.name) {
+            anyhow::bail!(tampered());
+        }
+
+        Ok(EmbeddingRecord {
+            id: record.id,
+            name: fields.name,
+            embedding: fields.embedding,
+            created_at: fields.created_at,
+            metadata: fields.metadata,
+            model: fields.model,
+        })
+    }
+
+    /// Seals every record with the current key: records of previous keys after
+    /// a rotation, and plaintext records when encryption was just enabled.
+    /// Returns the number of records rewritten.
+    pub fn reencrypt(&mut self) -> Result<usize> {
+        let mut rewritten = 0;
+        for record in self.inner.get_all_embeddings()? {
+            let plaintext = match record.metadata.get(KEY_ID_KEY) {
+                Some(key_id) if *key_id == self.keys.current.id => continue,
+                Some(_) => self.open(record)?,
+                None if !record.metadata.contains_key(SEALED_KEY) => record,
+                None => anyhow::bail!("Embedding '{}' has a sealed value but no key ID", record.id),
+            };
+            plaintext.validate()?;
+            let sealed = self.seal(plaintext)?;
+            self.inner.store_embedding(sealed)?;
+            rewritten += 1;
+        }
+        Ok(rewritten)
+    }
+}
+
+fn associated_data(id: &str, key_id: &str) -> Vec<u8> {
+    format!("face-auth-record-v1\0{id}\0{key_id}").into_bytes()
+}
+
+impl EmbeddingStorage for EncryptedStorage {
+    fn store_embeddings(&mut self, records: Vec<EmbeddingRecord>) -> Result<()> {
+        let mut sealed = Vec::with_capacity(records.len());
+        for record in records {
+            record.validate()?;
+            sealed.push(self.seal(record)?);
+        }
+        self.inner.store_embeddings(sealed)
+    }
+
+    fn get_embedding(&self, id: &str) -> Result<Option<EmbeddingRecord>> {
+        self.inner.get_embedding(id)?.map(|record| self.open(record)).transpose()
+    }
+
+    fn get_all_embeddings(&self) -> Result<Vec<EmbeddingRecord>> {
+        self.inner.get_all_embeddings()?.into_iter().map(|record| self.open(record)).collect()
+    }
+
+    fn delete_embedding(&mut self, id: &str) -> Result<bool> {
+        self.inner.delete_embedding(id)
+    }
+
+    fn generation(&self) -> Result<u64> {
+        self.inner.generation()
+    }
+
+    fn nearest(&self, query: &[f32], k: usize, current: &ModelFingerprint) -> Result<Vec<(EmbeddingRecord, f32)>> {
+        // The vectors are sealed in the inner storage, so the matrix is built here
+        self.templates.nearest(self, query, k, current)
+    }
+
+    fn get_user_embeddings(&self, name: &str) -> Result<Vec<EmbeddingRecord>> {
+        // Each key tags the name differently
+        let mut records = Vec::new();
+        for key in self.keys.keys() {
+            for record in self.inner.get_user_embeddings(&key.name_tag(name))? {
+                records.push(self.open(record)?);
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::local_file_vector_storage::LocalFileVectorStorage;
+    use std::path::Path;
+
+    const KEY_A: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_B: &str = "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff000102030405060708090a0b0c0d0e0f";
+
+    fn record(id: &str, name: &str) -> EmbeddingRecord {
+        EmbeddingRecord {
+            id: id.to_string(),
+            name: name.to_string(),
+            embedding: vec![0.125, -0.5, 0.75],
+            created_at: chrono::Utc::now(),
+            metadata: HashMap::from([("type".to_string(), "average".to_string())]),
+            model: None,
+        }
+    }
+
+    fn open(path: &Path, current: &str, previous: &[&str]) -> Result<EncryptedStorage> {
+        let inner = LocalFileVectorStorage::new(path.to_string_lossy().into_owned(), false)?;
+        let previous = previous.iter().map(|key| StorageKey::parse_hex(key)).collect::<Result<_>>()?;
+        Ok(EncryptedStorage::new(Box::new(inner), KeyRing::new(StorageKey::parse_hex(current)?, previous)))
+    }
+
+    fn with_temp_file(test: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+        let path = std::env::temp_dir().join(format!("face_auth_{}.json", uuid::Uuid::new_v4()));
+        let result = test(&path);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.lock", path.display()));
+        result
+    }
+
+    #[test]
+    fn records_are_sealed_at_rest() -> Result<()> {
+        with_temp_file(|path| {
+            let mut storage = open(path, KEY_A, &[])?;
+            storage.store_embedding(record("a1", "alice"))?;
+            storage.store_embedding(record("b1", "bob"))?;
+
+            let raw = fs::read_to_string(path)?;
+            assert!(!raw.contains("alice") && !raw.contains("0.125") && !raw.contains("average"));
+
+            let alice = storage.get_user_embeddings("alice")?;
+            assert_eq!(alice.len(), 1);
+            assert_eq!(alice[0].embedding, [0.125, -0.5, 0.75]);
+            assert_eq!(alice[0].metadata["type"], "average");
+            assert_eq!(storage.get_all_embeddings()?.len(), 2);
+
+            // A different key can't read them
+            assert!(open(path, KEY_B, &[])?.get_all_embeddings().is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn tampering_fails_loudly() -> Result<()> {
+        with_temp_file(|path| {
+            open(path, KEY_A, &[])?.store_embedding(record("a1", "alice"))?;
+            open(path, KEY_A, &[])?.store_embedding(record("b1", "bob"))?;
+            let raw = fs::read_to_string(path)?;
+            let mut data: HashMap<String, EmbeddingRecord> = serde_json::from_str(&raw)?;
+
+            // Swapping sealed values between records breaks the associated data
+            let sealed_a = data["a1"].metadata[SEALED_KEY].clone();
+            let sealed_b = data["b1"].metadata[SEALED_KEY].clone();
+            data.get_mut("a1").unwrap().metadata.insert(SEALED_KEY.to_string(), sealed_b);
+            data.get_mut("b1").unwrap().metadata.insert(SEALED_KEY.to_string(), sealed_a);
+            fs::write(path, serde_json::to_string(&data)?)?;
+
+            let error = open(path, KEY_A, &[])?.get_all_embeddings().expect_err("tampered store");
+            assert!(format!("{error:#}").contains("failed authentication"));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn rotation_reencrypts_old_and_plaintext_records() -> Result<()> {
+        with_temp_file(|path| {
+            open(path, KEY_A, &[])?.store_embedding(record("a1", "alice"))?;
+            LocalFileVectorStorage::new(path.to_string_lossy().into_owned(), false)?.store_embedding(record("p1", "pat"))?;
+
+            // Plaintext records are refused until they are re-encrypted
+            assert!(open(path, KEY_B, &[KEY_A])?.get_all_embeddings().is_err());
+
+            let mut rotated = open(path, KEY_B, &[KEY_A])?;
+            assert_eq!(rotated.get_user_embeddings("alice")?.len(), 1);
+            assert_eq!(rotated.reencrypt()?, 2);
+            assert_eq!(rotated.reencrypt()?, 0);
+
+            // The old key is no longer needed
+            let storage = open(path, KEY_B, &[])?;
+            assert_eq!(storage.get_all_embeddings()?.len(), 2);
+            assert_eq!(storage.get_user_embeddings("pat")?[0].embedding, [0.125, -0.5, 0.75]);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,532 @@
+//! Hierarchical navigable small world graph (Malkov & Yashunin) for approximate
+//! cosine search. Only the graph is persisted; vectors come from the storage.
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+// Upper bound on the number of layers, reached with negligible probability
+const MAX_LEVEL: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct HnswParams {
+    /// Neighbours per node on the upper layers; layer 0 keeps twice as many.
+    pub m: usize,
+    /// Candidate list size while inserting.
+    pub ef_construction: usize,
+    /// Candidate list size while searching; raised to `k` when smaller.
+    pub ef_search: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Node {
+    id: String,
+    // One neighbour list per layer the node lives on
+    neighbors: Vec<Vec<usize>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Graph {
+    params: HnswParams,
+    dim: usize,
+    entry_point: Option<usize>,
+    // Deleted nodes leave a hole that the next insert reuses
+    nodes: Vec<Option<Node>>,
+    rng_state: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Scored {
+    similarity: f32,
+    node: usize,
+}
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.similarity.total_cmp(&other.similarity).then(self.node.cmp(&other.node))
+    }
+}
+
+pub struct HnswIndex {
+    graph: Graph,
+    // L2-normalised vectors, empty for deleted nodes
+    vectors: Vec<Vec<f32>>,
+    slots: HashMap<String, usize>,
+}
+
+impl HnswIndex {
+    pub fn new(params: HnswParams, dim: usize) -> Self {
+        HnswIndex {
+            graph: Graph {
+                params,
+                dim,
+                entry_point: None,
+                nodes: Vec::new(),
+                rng_state: 0x9e37_79b9_7f4a_7c15,
+            },
+            vectors: Vec::new(),
+            slots: HashMap::new(),
+        }
+    }
+
+    pub fn dim(&self) -> usize {
+        self.graph.dim
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Adds `vector` under `id`, replacing an existing entry with the same ID.
+    pub fn insert(&mut self, id: &str, vector: &[f32]) -> Result<()> {
+        if vector.len() != self.graph.dim {
+            anyhow::bail!("Cannot index a {}-d vector in a {}-d index", vector.len(), self.graph.dim);
+        }
+        self.remove(id);
+
+        let vector = normalized(vector);
+        let level = self.random_level();
+        let slot = match self.graph.nodes.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => {
+                self.graph.nodes.push(None);
+                self.vectors.push(Vec::new());
+                self.graph.nodes.len() - 1
+            }
+        };
+        self.graph.nodes[slot] = Some(Node { id: id.to_string(), neighbors: vec![Vec::new(); level + 1] });
+        self.vectors[slot] = vector;
+        self.slots.insert(id.to_string(), slot);
+
+        let Some(entry_point) = self.graph.entry_point else {
+            self.graph.entry_point = Some(slot);
+            return Ok(());
+        };
+
+        // Greedy descent through the layers above the new node
+        let top = self.level(entry_point);
+        let query = self.vectors[slot].clone();
+        let mut entry = vec![entry_point];
+        for layer in (level + 1..=top).rev() {
+            entry = vec![self.search_layer(&query, &entry, 1, layer)[0].node];
+        }
+
+        for layer in (0..=level.min(top)).rev() {
+            let candidates = self.search_layer(&query, &entry, self.graph.params.ef_construction, layer);
+            let max_degree = self.max_degree(layer);
+            let neighbors = self.select_neighbors(&candidates, max_degree);
+            self.node_mut(slot).neighbors[layer] = neighbors.clone();
+
+            for neighbor in neighbors {
+                let links = &mut self.node_mut(neighbor).neighbors[layer];
+                links.push(slot);
+                if links.len() > max_degree {
+                    let links = links.clone();
+                    self.node_mut(neighbor).neighbors[layer] = self.prune(neighbor, links, max_degree);
+                }
+            }
+            entry = candidates.iter().map(|candidate| candidate.node).collect();
+        }
+
+        if level > top {
+            self.graph.entry_point = Some(slot);
+        }
+        Ok(())
+    }
+
+    /// Removes `id` and reconnects its former neighbours. Returns whether it was indexed.
+    pub fn remove(&mut self, id: &str) -> bool {
+        let Some(slot) = self.slots.remove(id) else {
+            return false;
+        };
+        let removed = self.graph.nodes[slot].take().expect("indexed node");
+        self.vectors[slot].clear();
+
+        for (layer, removed_links) in removed.neighbors.iter().enumerate() {
+            let max_degree = self.max_degree(layer);
+            for other in 0..self.graph.nodes.len() {
+                let Some(node) = &self.graph.nodes[other] else { continue };
+                if node.neighbors.len() <= layer || !node.neighbors[layer].contains(&slot) {
+                    continue;
+                }
+                // Replace the link with the best of both neighbourhoods
+                let mut links: Vec<usize> = node.neighbors[layer].iter().copied().filter(|&n| n != slot).collect();
+                for &candidate in removed_links {
+                    if candidate != other && !links.contains(&candidate) {
+                        links.push(candidate);
+                    }
+                }
+                self.node_mut(other).neighbors[layer] = self.prune(other, links, max_degree);
+            }
+        }
+
+        if self.graph.entry_point == Some(slot) {
+            self.graph.entry_point = self
+                .graph
+                .nodes
+                .iter()
+                .enumerate()
+                .filter_map(|(index, node)| node.as_ref().map(|node| (index, node.neighbors.len())))
+                .max_by_key(|&(_, levels)| levels)
+                .map(|(index, _)| index);
+        }
+        true
+    }
+
+    /// The `k` indexed IDs most similar to `query`, best first.
+    pub fn search(&self, query: &[f32], k: usize) -> Result<Vec<(String, f32)>> {
+        if query.len() != self.graph.dim {
+            anyhow::bail!("Cannot search a {}-d index with a {}-d query", self.graph.dim, query.len());
+        }
+        let Some(entry_point) = self.graph.entry_point else {
+            return Ok(Vec::new());
+        };
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+
+        let query = normalized(query);
+        let mut entry = vec![entry_point];
+        for layer in (1..=self.level(entry_point)).rev() {
+            entry = vec![self.search_layer(&query, &entry, 1, layer)[0].node];
+        }
+
+        let ef = self.graph.params.ef_search.max(k);
+        Ok(self
+            .search_layer(&query, &entry, ef, 0)
+            .into_iter()
+            .take(k)
+            .map(|scored| (self.node(scored.node).id.clone(), scored.similarity))
+            .collect())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec(&self.graph)?;
+        fs::write(path, data).with_context(|| format!("Failed to write index '{}'", path.display()))
+    }
+
+    /// Loads a saved graph. Returns `None` when the file is missing or doesn't
+    /// describe exactly the vectors in `vectors`, so the caller can rebuild.
+    pub fn load(path: &Path, params: HnswParams, vectors: &HashMap<String, Vec<f32>>) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = fs::read(path).with_context(|| format!("Failed to read index '{}'", path.display()))?;
+        let Ok(graph) = serde_json::from_slice::<Graph>(&data) else {
+            return Ok(None);
+        };
+
+        let indexed = graph.nodes.iter().flatten().count();
+        if graph.params != params || indexed != vectors.len() {
+            return Ok(None);
+        }
+
+        let mut slot_vectors = Vec::with_capacity(graph.nodes.len());
+        let mut slots = HashMap::new();
+        for (slot, node) in graph.nodes.iter().enumerate() {
+            match node {
+                Some(node) => match vectors.get(&node.id) {
+                    Some(vector) if vector.len() == graph.dim => {
+                        slot_vectors.push(normalized(vector));
+                        slots.insert(node.id.clone(), slot);
+                    }
+                    _ => return Ok(None),
+                },
+                None => slot_vectors.push(Vec::new()),
+            }
+        }
+
+        Ok(Some(HnswIndex { graph, vectors: slot_vectors, slots }))
+    }
+
+    fn node(&self, slot: usize) -> &Node {
+        self.graph.nodes[slot].as_ref().expect("live node")
+    }
+
+    fn node_mut(&mut self, slot: usize) -> &mut Node {
+        self.graph.nodes[slot].as_mut().expect("live node")
+    }
+
+    fn level(&self, slot: usize) -> usize {
+        self.node(slot).neighbors.len() - 1
+    }
+
+    fn max_degree(&self, layer: usize) -> usize {
+        if layer == 0 { self.graph.params.m * 2 } else { self.graph.params.m }
+    }
+
+    fn similarity(&self, a: usize, b: usize) -> f32 {
+        dot(&self.vectors[a], &self.vectors[b])
+    }
+
+    // Exponentially distributed level with normalisation factor 1 / ln(M)
+    fn random_level(&mut self) -> usize {
+        let state = &mut self.graph.rng_state;
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        let uniform = ((*state >> 11) as f64 + 1.) / (1u64 << 53) as f64;
+        let level = -uniform.ln() / (self.graph.params.m.max(2) as f64).ln();
+        (level as usize).min(MAX_LEVEL)
+    }
+
+    // Best-first search of one layer, returning up to `ef` nodes, best first
+    fn search_layer(&self, query: &[f32], entry: &[usize], ef: usize, layer: usize) -> Vec<Scored> {
+        let mut visited: HashSet<usize> = entry.iter().copied().collect();
+        let mut candidates = BinaryHeap::new();
+        let mut results = BinaryHeap::new();
+        for &node in entry {
+            let scored = Scored { similarity: dot(query, &self.vectors[node]), node };
+            candidates.push(scored);
+            results.push(Reverse(scored));
+        }
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(candidate) = candidates.pop() {
+            let worst = results.peek().map_or(f32::MIN, |Reverse(worst)| worst.similarity);
+            if candidate.similarity < worst && results.len() >= ef {
+                break;
+            }
+            let Some(links) = self.node(candidate.node).neighbors.get(layer) else { continue };
+            for &neighbor in links {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let similarity = dot(query, &self.vectors[neighbor]);
+                let worst = results.peek().map_or(f32::MIN, |Reverse(worst)| worst.similarity);
+                if results.len() < ef || similarity > worst {
+                    let scored = Scored { similarity, node: neighbor };
+                    candidates.push(scored);
+                    results.push(Reverse(scored));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        let mut results: Vec<Scored> = results.into_iter().map(|Reverse(scored)| scored).collect();
+        results.sort_by(|a, b| b.cmp(a));
+        results
+    }
+
+    // Neighbour selection heuristic: keep a candidate only if it is closer to the
+    // base node than to every neighbour kept so far, then top up with the rest
+    fn select_neighbors(&self, candidates: &[Scored], max_degree: usize) -> Vec<usize> {
+        let mut selected: Vec<usize> = Vec::with_capacity(max_degree);
+        let mut discarded = Vec::new();
+        for candidate in candidates {
+            if selected.len() >= max_degree {
+                break;
+            }
+            if selected.iter().all(|&kept| self.similarity(candidate.node, kept) < candidate.similarity) {
+                selected.push(candidate.node);
+            } else {
+                discarded.push(candidate.node);
+            }
+        }
+        for node in discarded {
+            if selected.len() >= max_degree {
+                break;
+            }
+            selected.push(node);
+        }
+        selected
+    }
+
+    fn prune(&self, base: usize, links: Vec<usize>, max_degree: usize) -> Vec<usize> {
+        let mut candidates: Vec<Scored> = links
+            .into_iter()
+            .map(|node| Scored { similarity: self.similarity(base, node), node })
+            .collect();
+        candidates.sort_by(|a, b| b.cmp(a));
+        self.select_neighbors(&candidates, max_degree)
+    }
+}
+
+// Eight independent sums so the compiler can vectorise the loop
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let mut lanes = [0f32; 8];
+    let (a_chunks, b_chunks) = (a.chunks_exact(8), b.chunks_exact(8));
+    let tail: f32 = a_chunks.remainder().iter().zip(b_chunks.remainder()).map(|(x, y)| x * y).sum();
+    for (a, b) in a_chunks.zip(b_chunks) {
+        for lane in 0..8 {
+            lanes[lane] += a[lane] * b[lane];
+        }
+    }
+    lanes.iter().sum::<f32>() + tail
+}
+
+fn normalized(vector: &[f32]) -> Vec<f32> {
+    let norm = dot(vector, vector).sqrt().max(f32::EPSILON);
+    vector.iter().map(|v| v / norm).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: HnswParams = HnswParams { m: 12, ef_construction: 64, ef_search: 50 };
+    const DIM: usize = 32;
+
+    fn random_vectors(count: usize, seed: u64) -> Vec<Vec<f32>> {
+        random_vectors_of(count, DIM, seed)
+    }
+
+    fn random_vectors_of(count: usize, dim: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut state = seed;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+        };
+        (0..count).map(|_| (0..dim).map(|_| next()).collect()).collect()
+    }
+
+    fn brute_force(vectors: &HashMap<String, Vec<f32>>, query: &[f32], k: usize) -> Vec<String> {
+        let query = normalized(query);
+        let mut scored: Vec<(&String, f32)> =
+            vectors.iter().map(|(id, vector)| (id, dot(&query, &normalized(vector)))).collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.into_iter().take(k).map(|(id, _)| id.clone()).collect()
+    }
+
+    fn recall(index: &HnswIndex, vectors: &HashMap<String, Vec<f32>>, queries: &[Vec<f32>], k: usize) -> Result<f32> {
+        let mut found = 0;
+        for query in queries {
+            let expected: HashSet<String> = brute_force(vectors, query, k).into_iter().collect();
+            found += index.search(query, k)?.iter().filter(|(id, _)| expected.contains(id)).count();
+        }
+        Ok(found as f32 / (queries.len() * k) as f32)
+    }
+
+    fn build(count: usize) -> Result<(HnswIndex, HashMap<String, Vec<f32>>)> {
+        let mut index = HnswIndex::new(PARAMS, DIM);
+        let mut vectors = HashMap::new();
+        for (i, vector) in random_vectors(count, 7).into_iter().enumerate() {
+            index.insert(&format!("r{i}"), &vector)?;
+            vectors.insert(format!("r{i}"), vector);
+        }
+        Ok((index, vectors))
+    }
+
+    #[test]
+    fn recall_against_brute_force() -> Result<()> {
+        let (index, vectors) = build(1000)?;
+        let queries = random_vectors(50, 99);
+
+        let recall = recall(&index, &vectors, &queries, 10)?;
+        assert!(recall >= 0.9, "recall@10 {recall}");
+
+        // A stored vector finds itself
+        let (id, similarity) = &index.search(&vectors["r42"], 1)?[0];
+        assert_eq!(id, "r42");
+        assert!((similarity - 1.).abs() < 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn deleted_vectors_are_never_returned() -> Result<()> {
+        let (mut index, mut vectors) = build(600)?;
+        for i in (0..600).step_by(2) {
+            assert!(index.remove(&format!("r{i}")));
+            vectors.remove(&format!("r{i}"));
+        }
+        assert!(!index.remove("r0"));
+        assert_eq!(index.slots.len(), 300);
+
+        let queries = random_vectors(50, 99);
+        for query in &queries {
+            assert!(index.search(query, 10)?.iter().all(|(id, _)| vectors.contains_key(id)));
+        }
+        let recall = recall(&index, &vectors, &queries, 10)?;
+        assert!(recall >= 0.9, "recall@10 after deletes {recall}");
+
+        // Freed slots are reused by new inserts
+        index.insert("new", &queries[0])?;
+        assert_eq!(index.graph.nodes.len(), 600);
+        assert_eq!(index.search(&queries[0], 1)?[0].0, "new");
+        Ok(())
+    }
+
+    #[test]
+    fn saved_graph_reloads_against_the_same_vectors() -> Result<()> {
+        let (index, mut vectors) = build(200)?;
+        let path = std::env::temp_dir().join(format!("face_auth_{}.hnsw", uuid::Uuid::new_v4()));
+        index.save(&path)?;
+
+        let loaded = HnswIndex::load(&path, PARAMS, &vectors)?.expect("consistent index");
+        let query = &vectors["r7"];
+        assert_eq!(loaded.search(query, 5)?, index.search(query, 5)?);
+
+        // A vector the graph doesn't know about forces a rebuild
+        vectors.insert("extra".to_string(), vec![0.; DIM]);
+        let stale = HnswIndex::load(&path, PARAMS, &vectors);
+        let _ = fs::remove_file(&path);
+        assert!(stale?.is_none());
+        Ok(())
+    }
+
+    // Gallery-like data: a few noisy templates around each user's centre
+    fn clustered_vectors(users: usize, per_user: usize, dim: usize, seed: u64) -> Vec<Vec<f32>> {
+        let centres = random_vectors_of(users, dim, seed);
+        let noise = random_vectors_of(users * per_user, dim, seed + 1);
+        noise
+            .into_iter()
+            .enumerate()
+            .map(|(i, noise)| centres[i % users].iter().zip(noise).map(|(c, n)| c + 0.5 * n).collect())
+            .collect()
+    }
+
+    // cargo test --release -- --ignored --nocapture bench_
+    #[test]
+    #[ignore]
+    fn bench_hnsw_10k_templates() -> Result<()> {
+        const COUNT: usize = 10_000;
+        let params = HnswParams { m: 16, ef_construction: 200, ef_search: 64 };
+        let vectors: HashMap<String, Vec<f32>> = clustered_vectors(COUNT / 4, 4, 768, 3)
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (format!("r{i}"), v))
+            .collect();
+
+        let start = std::time::Instant::now();
+        let mut index = HnswIndex::new(params, 768);
+        for (id, vector) in &vectors {
+            index.insert(id, vector)?;
+        }
+        let build_time = start.elapsed();
+
+        // Fresh captures of enrolled users
+        let queries: Vec<Vec<f32>> = clustered_vectors(COUNT / 4, 5, 768, 3).into_iter().skip(COUNT).step_by(25).collect();
+        let start = std::time::Instant::now();
+        for query in &queries {
+            index.search(query, 10)?;
+        }
+        let search_time = start.elapsed() / queries.len() as u32;
+
+        let recall = recall(&index, &vectors, &queries, 10)?;
+        println!("{COUNT} templates x 768-d: build {:.1}s, search {:.3}ms per query, recall@10 {recall:.3}",
+                 build_time.as_secs_f64(), search_time.as_secs_f64() * 1e3);
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+use super::hnsw_index::{HnswIndex, HnswParams};
+use super::vector_storage::{EmbeddingRecord, EmbeddingStorage, ModelFingerprint};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+/// Wraps another storage with an HNSW index over its embeddings. The index is
+/// loaded on the first search or write, follows every store and delete and is
+/// saved next to the storage when the storage is dropped; a missing or out of
+/// date index file is rebuilt.
+pub struct HnswIndexedStorage {
+    inner: Box<dyn EmbeddingStorage>,
+    path: PathBuf,
+    params: HnswParams,
+    // `None` until needed, so commands that never search don't read and
+    // normalise the whole gallery
+    index: Mutex<Option<LoadedIndex>>,
+}
+
+struct LoadedIndex {
+    // The storage generation the graph reflects; another process's write changes it
+    generation: u64,
+    index: HnswIndex,
+    // Changed since the index file was last written
+    dirty: bool,
+}
+
+impl HnswIndexedStorage {
+    pub fn open(inner: Box<dyn EmbeddingStorage>, path: String, params: HnswParams) -> Result<Self> {
+        Ok(HnswIndexedStorage { inner, path: PathBuf::from(path), params, index: Mutex::new(None) })
+    }
+
+    // The index of the storage's current generation, (re)loaded when it is
+    // missing or another process wrote to the storage
+    fn current_index(&self) -> Result<MutexGuard<'_, Option<LoadedIndex>>> {
+        let generation = self.inner.generation()?;
+        let mut index = self.index.lock().map_err(|_| anyhow::anyhow!("HNSW index lock poisoned"))?;
+        if index.as_ref().is_none_or(|loaded| loaded.generation != generation) {
+            *index = Some(self.load_index(generation)?);
+        }
+        Ok(index)
+    }
+
+    fn load_index(&self, generation: u64) -> Result<LoadedIndex> {
+        let records = self.inner.get_all_embeddings()?;
+        // Only one dimension is indexed: the one of the most recent enrollment
+        let dim = records.iter().max_by_key(|record| record.created_at).map_or(0, |record| record.embedding.len());
+        let vectors: HashMap<String, Vec<f32>> = records
+            .into_iter()
+            .filter(|record| record.embedding.len() == dim)
+            .map(|record| (record.id, record.embedding))
+            .collect();
+
+        if let Some(index) = HnswIndex::load(&self.path, self.params, &vectors)?
+            && index.dim() == dim
+        {
+            return Ok(LoadedIndex { generation, index, dirty: false });
+        }
+        if !vectors.is_empty() {
+            println!("[*] Building HNSW index over {} embedding(s)", vectors.len());
+        }
+        Ok(LoadedIndex { generation, index: build_index(self.params, dim, vectors)?, dirty: true })
+    }
+
+    // The index loaded by `current_index` before a write, now reflecting it
+    fn written_index(&mut self) -> Result<&mut LoadedIndex> {
+        let generation = self.inner.generation()?;
+        let loaded = self
+            .index
+            .get_mut()
+            .map_err(|_| anyhow::anyhow!("HNSW index lock poisoned"))?
+            .as_mut()
+            .context("HNSW index not loaded")?;
+        loaded.generation = generation;
+        Ok(loaded)
+    }
+}
+
+fn build_index(params: HnswParams, dim: usize, vectors: HashMap<String, Vec<f32>>) -> Result<HnswIndex> {
+    let mut index = HnswIndex::new(params, dim);
+    // Sorted so rebuilding the same records yields the same graph
+    let mut vectors: Vec<(String, Vec<f32>)> = vectors.into_iter().collect();
+    vectors.sort_by(|a, b| a.0.cmp(&b.0));
+    for (id, vector) in vectors {
+        index.insert(&id, &vector)?;
+    }
+    Ok(index)
+}
+
+impl Drop for HnswIndexedStorage {
+    // Saving once here instead of after every write keeps bulk deletes cheap. If
+    // the process dies first, the next load finds the file out of date and rebuilds.
+    fn drop(&mut self) {
+        if let Ok(Some(loaded)) = self.index.get_mut()
+            && loaded.dirty
+            && let Err(e) = loaded.index.save(&self.path)
+        {
+            eprintln!("Warning: Failed to save the HNSW index: {e:#}");
+        }
+    }
+}
+
+impl EmbeddingStorage for HnswIndexedStorage {
+    fn store_embeddings(&mut self, records: Vec<EmbeddingRecord>) -> Result<()> {
+        let entries: Vec<(String, Vec<f32>)> = records.iter().map(|record| (record.id.clone(), record.embedding.clone())).collect();
+        drop(self.current_index()?);
+        self.inner.store_embeddings(records)?;
+
+        let params = self.params;
+        let loaded = self.written_index()?;
+        loaded.dirty = true;
+        let mut new_dim = None;
+        for (id, vector) in entries {
+            if loaded.index.is_empty() && vector.len() != loaded.index.dim() {
+                loaded.index = HnswIndex::new(params, vector.len());
+            }
+            if vector.len() == loaded.index.dim() {
+                loaded.index.insert(&id, &vector)?;
+            } else {
+                loaded.index.remove(&id);
+                new_dim = Some(vector.len());
+            }
+        }
+
+        let Some(dim) = new_dim else {
+            return Ok(());
+        };
+        // A new model took over: index its embeddings instead of the old ones
+        println!("[*] Rebuilding HNSW index for {dim}-d embeddings");
+        let vectors = self
+            .inner
+            .get_all_embeddings()?
+            .into_iter()
+            .filter(|record| record.embedding.len() == dim)
+            .map(|record| (record.id, record.embedding))
+            .collect();
+        self.written_index()?.index = build_index(params, dim, vectors)?;
+        Ok(())
+    }
+
+    fn get_embedding(&self, id: &str) -> Result<Option<EmbeddingRecord>> {
+        self.inner.get_embedding(id)
+    }
+
+    fn get_all_embeddings(&self) -> Result<Vec<EmbeddingRecord>> {
+        self.inner.get_all_embeddings()
+    }
+
+    fn delete_embedding(&mut self, id: &str) -> Result<bool> {
+        drop(self.current_index()?);
+        let deleted = self.inner.delete_embedding(id)?;
+        let loaded = self.written_index()?;
+        if loaded.index.remove(id) {
+            loaded.dirty = true;
+        }
+        Ok(deleted)
+    }
+
+    fn generation(&self) -> Result<u64> {
+        self.inner.generation()
+    }
+
+    // Login reads one user's records without touching the index
+    fn get_user_embeddings(&self, name: &str) -> Result<Vec<EmbeddingRecord>> {
+        self.inner.get_user_embeddings(name)
+    }
+
+    fn nearest(&self, query: &[f32], k: usize, current: &ModelFingerprint) -> Result<Vec<(EmbeddingRecord, f32)>> {
+        let loaded = self.current_index()?;
+        let index = &loaded.as_ref().context("HNSW index not loaded")?.index;
+        if query.len() != index.dim() {
+            return self.inner.nearest(query, k, current);
+        }
+        // The index holds stale templates of the same dimension too, so the search
+        // widens until it finds k comparable ones or runs out of templates
+        let mut width = k;
+        loop {
+            let found = index.search(query, width)?;
+            let exhausted = found.len() < width;
+            let mut hits = Vec::with_capacity(k);
+            for (id, similarity) in found {
+                if let Some(record) = self.inner.get_embedding(&id)?
+                    && record.stale_reason(current).is_none()
+                {
+                    hits.push((record, similarity));
+                    if hits.len() == k {
+                        return Ok(hits);
+                    }
+                }
+            }
+            if exhausted {
+                return Ok(hits);
+            }
+            width = width.saturating_mul(4);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::local_file_vector_storage::LocalFileVectorStorage;
+    use std::fs;
+    use std::path::Path;
+
+    const PARAMS: HnswParams = HnswParams { m: 8, ef_construction: 64, ef_search: 32 };
+
+    fn record(id: &str, name: &str, embedding: Vec<f32>) -> EmbeddingRecord {
+        EmbeddingRecord {
+            id: id.to_string(),
+            name: name.to_string(),
+            created_at: chrono::Utc::now(),
+            metadata: HashMap::new(),
+            model: Some(fingerprint(embedding.len())),
+            embedding,
+        }
+    }
+
+    fn fingerprint(dim: usize) -> ModelFingerprint {
+        ModelFingerprint {
+            model_id: "test".to_string(),
+            weights_sha256: "0".to_string(),
+            preprocessing: "none".to_string(),
+            embedding_dim: dim,
+        }
+    }
+
+    fn open(json: &Path) -> Result<HnswIndexedStorage> {
+        let inner = LocalFileVectorStorage::new(json.to_string_lossy().into_owned(), false)?;
+        HnswIndexedStorage::open(Box::new(inner), format!("{}.hnsw", json.display()), PARAMS)
+    }
+
+    #[test]
+    fn index_follows_store_and_delete_across_reopen() -> Result<()> {
+        let json = std::env::temp_dir().join(format!("face_auth_{}.json", uuid::Uuid::new_v4()));
+        let result = (|| {
+            let mut storage = open(&json)?;
+            storage.store_embedding(record("a1", "alice", vec![1., 0., 0.]))?;
+            storage.store_embedding(record("a2", "alice", vec![0.9, 0.1, 0.]))?;
+            storage.store_embedding(record("b1", "bob", vec![0., 1., 0.]))?;
+            storage.store_embedding(record("c1", "carol", vec![0., 0., 1.]))?;
+            assert!(storage.delete_embedding("c1")?);
+            // The graph is written once, when the storage is dropped
+            assert!(!Path::new(&format!("{}.hnsw", json.display())).exists());
+            drop(storage);
+
+            // Opening reads nothing; the first search loads the saved graph
+            // without rebuilding it, and it no longer knows the deleted record
+            let mut storage = open(&json)?;
+            assert!(storage.index.lock().unwrap().is_none());
+            let current = fingerprint(3);
+            assert_eq!(storage.nearest(&[0., 0., 1.], 5, &current)?.len(), 3);
+            assert!(!storage.index.lock().unwrap().as_ref().expect("loaded index").dirty);
+            let ids: Vec<String> = storage.nearest(&[1., 0., 0.], 2, &current)?.into_iter().map(|(record, _)| record.id).collect();
+            assert_eq!(ids, ["a1", "a2"]);
+
+            // Stale templates closest to the query don't crowd out comparable ones
+            let mut legacy = record("l1", "lena", vec![1., 0., 0.]);
+            legacy.model = None;
+            storage.store_embedding(legacy)?;
+            let ids: Vec<String> = storage.nearest(&[1., 0., 0.], 2, &current)?.into_iter().map(|(record, _)| record.id).collect();
+            assert_eq!(ids, ["a1", "a2"]);
+            assert!(storage.delete_embedding("l1")?);
+            assert_eq!(storage.get_user_embeddings("alice")?.len(), 2);
+            assert!(storage.get_user_embeddings("carol")?.is_empty());
+
+            // Re-storing a record under another name moves it
+            storage.store_embedding(record("a2", "bob", vec![0.9, 0.1, 0.]))?;
+            assert_eq!(storage.get_user_embeddings("bob")?.len(), 2);
+
+            // Another handle's writes show up in the next search
+            let mut other = open(&json)?;
+            other.store_embedding(record("e1", "erin", vec![0., 0., 1.]))?;
+            drop(other);
+            assert_eq!(storage.nearest(&[0., 0., 1.], 1, &current)?[0].0.id, "e1");
+
+            // A new dimension replaces the indexed one; old vectors fall back to brute force
+            storage.store_embedding(record("d1", "dave", vec![1., 0.]))?;
+            assert_eq!(storage.index.lock().unwrap().as_ref().expect("loaded index").index.dim(), 2);
+            assert_eq!(storage.nearest(&[0., 1., 0.], 1, &current)?[0].0.id, "b1");
+            Ok(())
+        })();
+        let _ = fs::remove_file(&json);
+        let _ = fs::remove_file(format!("{}.hnsw", json.display()));
+        let _ = fs::remove_file(format!("{}.lock", json.display()));
+        result
+    }
+}
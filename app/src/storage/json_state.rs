@@ -0,0 +1,69 @@
+//! Small JSON state files kept next to the embeddings store, shared by every
+//! process using it. Updates reload the file under an exclusive lock and replace
+//! it atomically, like the local file store.
+use super::local_file_vector_storage::{lock_file, write_atomically};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Reads the state at `path`; a missing file is the default state. A file that
+/// can't be parsed is an error rather than silently reset.
+pub fn read<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    let _lock = lock_file(path, false)?;
+    load(path)
+}
+
+/// Applies `change` to the current state at `path` and writes the result back.
+/// Other processes can't change the file in between.
+pub fn update<T, R>(path: &Path, change: impl FnOnce(&mut T) -> R) -> Result<R>
+where
+    T: DeserializeOwned + Serialize + Default,
+{
+    let _lock = lock_file(path, true)?;
+    let mut state = load(path)?;
+    let result = change(&mut state);
+    write_atomically(path, |writer| Ok(serde_json::to_writer_pretty(writer, &state)?))
+        .with_context(|| format!("Failed to write '{}'", path.display()))?;
+    Ok(result)
+}
+
+fn load<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    match fs::read(path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .with_context(|| format!("'{}' is corrupt; fix or delete it", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read '{}'", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates_start_from_the_stored_state() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("face_auth_state_{}.json", uuid::Uuid::new_v4()));
+        let result = (|| {
+            assert_eq!(read::<Vec<u32>>(&path)?, Vec::<u32>::new());
+            update(&path, |values: &mut Vec<u32>| values.push(1))?;
+            let values = update(&path, |values: &mut Vec<u32>| {
+                values.push(2);
+                values.clone()
+            })?;
+            assert_eq!(values, vec![1, 2]);
+            assert_eq!(read::<Vec<u32>>(&path)?, values);
+
+            fs::write(&path, "not json")?;
+            assert!(read::<Vec<u32>>(&path).is_err());
+            assert!(update(&path, |values: &mut Vec<u32>| values.clear()).is_err());
+            assert_eq!(fs::read_to_string(&path)?, "not json");
+            Ok(())
+        })();
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.lock", path.display()));
+        result
+    }
+}
@@ -1,95 +1,269 @@
-use super::vector_storage::{EmbeddingRecord, EmbeddingStorage};
-use anyhow::Result;
+use super::vector_storage::{EmbeddingRecord, EmbeddingStorage, ModelFingerprint};
+use crate::matching::TemplateCache;
+use anyhow::{Context, Result};
 use serde_json;
 use std::collections::HashMap;
 use std::fs::{self, File, OpenOptions};
-use std::io::{BufReader, BufWriter};
-use std::path::Path;
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 
+// Modification time, length and inode of the file a snapshot was read from
+type FileStamp = Option<(SystemTime, u64, u64)>;
+
+/// Embeddings in one JSON file. Processes sharing the file coordinate through
+/// advisory locks on `<path>.lock`: shared while reading, exclusive while
+/// writing. Writes reload the file first so other processes' records survive.
 pub struct LocalFileVectorStorage {
     file_path: String,
+    recover_corrupt: bool,
     data: Mutex<HashMap<String, EmbeddingRecord>>,
+    // Which version of the file `data` reflects
+    loaded: Mutex<FileStamp>,
+    // Bumped whenever `data` is reloaded or saved
+    generation: AtomicU64,
+    templates: TemplateCache,
 }
 
 impl LocalFileVectorStorage {
-    pub fn new(file_path: String) -> Result<Self> {
+    /// Opens the store at `file_path`. A file that can't be parsed is kept as a
+    /// `.corrupt` backup; it is an error unless `recover_corrupt` is set, in
+    /// which case the store starts empty.
+    pub fn new(file_path: String, recover_corrupt: bool) -> Result<Self> {
         let storage = LocalFileVectorStorage {
             file_path,
+            recover_corrupt,
             data: Mutex::new(HashMap::new()),
+            loaded: Mutex::new(None),
+            generation: AtomicU64::new(0),
+            templates: TemplateCache::default(),
         };
-        
+
         // Load existing data if file exists
+        let _lock = storage.lock(false)?;
         storage.load_data()?;
         Ok(storage)
     }
 
+    // Locks the store until the returned file is dropped
+    fn lock(&self, exclusive: bool) -> Result<File> {
+        lock_file(Path::new(&self.file_path), exclusive)
+    }
+
+    // Re-reads the file if another process replaced it since it was last read
+    fn refresh(&self) -> Result<()> {
+        let _lock = self.lock(false)?;
+        let current = file_stamp(Path::new(&self.file_path))?;
+        if self.loaded.lock().is_ok_and(|loaded| *loaded == current) {
+            return Ok(());
+        }
+        self.load_data()
+    }
+
     fn load_data(&self) -> Result<()> {
         let path = Path::new(&self.file_path);
+        let stamp = file_stamp(path)?;
+        let data = self.read_file(path)?;
+
+        if let Ok(mut guard) = self.data.lock() {
+            *guard = data;
+        }
+        if let Ok(mut loaded) = self.loaded.lock() {
+            *loaded = stamp;
+        }
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn read_file(&self, path: &Path) -> Result<HashMap<String, EmbeddingRecord>> {
         if !path.exists() {
-            return Ok(());
+            return Ok(HashMap::new());
         }
 
         // Check if file is empty
         let metadata = fs::metadata(path)?;
         if metadata.len() == 0 {
             // File exists but is empty, this is fine - just use empty HashMap
-            return Ok(());
+            return Ok(HashMap::new());
         }
 
         let file = File::open(path)?;
         let reader = BufReader::new(file);
-        
-        // Try to parse JSON, if it fails due to empty/invalid content, start fresh
-        let data: HashMap<String, EmbeddingRecord> = match serde_json::from_reader(reader) {
-            Ok(data) => data,
+
+        match serde_json::from_reader(reader) {
+            Ok(data) => Ok(data),
             Err(e) => {
-                eprintln!("Warning: Could not parse existing embeddings file ({e}), starting fresh");
-                HashMap::new()
+                let backup = backup_corrupt_file(path)?;
+                if !self.recover_corrupt {
+                    anyhow::bail!(
+                        "Could not parse embeddings file '{}' ({e}). A copy was kept at '{}'; restore the file \
+                         or set storage.local_file.recover_corrupt to start with an empty store",
+                        self.file_path, backup.display()
+                    );
+                }
+                eprintln!("Warning: Could not parse embeddings file '{}' ({e}), starting fresh. The old file was kept at '{}'",
+                          self.file_path, backup.display());
+                Ok(HashMap::new())
             }
-        };
-        
-        if let Ok(mut guard) = self.data.lock() {
-            *guard = data;
         }
-        
-        Ok(())
     }
 
-    fn save_data(&self) -> Result<()> {
+    // Reloads, applies `change` and saves if it reports a change, all under the
+    // exclusive lock. Always reloads: timestamps are too coarse to prove that no
+    // other process wrote in between.
+    fn modify(&self, change: impl FnOnce(&mut HashMap<String, EmbeddingRecord>) -> bool) -> Result<bool> {
+        let _lock = self.lock(true)?;
+        self.load_data()?;
+
+        let mut guard = self.data.lock().map_err(|_| anyhow::anyhow!("Embedding storage lock poisoned"))?;
+        let changed = change(&mut guard);
+        if changed {
+            self.save_data(&guard)?;
+        }
+        Ok(changed)
+    }
+
+    fn save_data(&self, data: &HashMap<String, EmbeddingRecord>) -> Result<()> {
         let path = Path::new(&self.file_path);
-        
+
         // Create directory if it doesn't exist
-        if let Some(parent) = path.parent() {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
             fs::create_dir_all(parent)?;
         }
 
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(path)?;
-        
-        let writer = BufWriter::new(file);
-        
-        if let Ok(guard) = self.data.lock() {
-            serde_json::to_writer_pretty(writer, &*guard)?;
+        write_atomically(path, |writer| Ok(serde_json::to_writer_pretty(writer, data)?))
+            .with_context(|| format!("Failed to save embeddings to '{}'", self.file_path))?;
+
+        if let Ok(mut loaded) = self.loaded.lock() {
+            *loaded = file_stamp(path)?;
         }
-        
+        self.generation.fetch_add(1, Ordering::SeqCst);
         Ok(())
     }
 }
 
+fn file_stamp(path: &Path) -> Result<FileStamp> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some((metadata.modified()?, metadata.len(), inode(&metadata)))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(metadata)
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+/// Takes an advisory lock on `<path>.lock`, held until the returned file is
+/// dropped: shared for readers, exclusive for writers.
+pub fn lock_file(path: &Path, exclusive: bool) -> Result<File> {
+    let lock_path = format!("{}.lock", path.display());
+    if let Some(parent) = Path::new(&lock_path).parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .with_context(|| format!("Failed to open lock file '{lock_path}'"))?;
+    if exclusive {
+        file.lock()
+    } else {
+        file.lock_shared()
+    }
+    .with_context(|| format!("Failed to lock '{lock_path}'"))?;
+    Ok(file)
+}
+
+/// Writes to a temporary file next to `path`, syncs it and renames it over
+/// `path`, so a crash leaves either the old or the new contents, never a mix.
+pub fn write_atomically(path: &Path, write: impl FnOnce(&mut BufWriter<&File>) -> Result<()>) -> Result<()> {
+    let file_name = path.file_name().context("Storage path has no file name")?.to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{file_name}.{}.tmp", std::process::id()));
+
+    let result = (|| {
+        let file = File::create(&temp_path)?;
+        let mut writer = BufWriter::new(&file);
+        write(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        sync_parent_dir(path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+// Makes the rename itself durable
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+// Copies an unreadable store to `<path>.corrupt` (or `.corrupt.N` if that is
+// taken by a different file) and returns where it went
+fn backup_corrupt_file(path: &Path) -> Result<PathBuf> {
+    let contents = fs::read(path)?;
+    for attempt in 0.. {
+        let suffix = if attempt == 0 { "corrupt".to_string() } else { format!("corrupt.{attempt}") };
+        let backup = PathBuf::from(format!("{}.{suffix}", path.display()));
+        if !backup.exists() {
+            fs::write(&backup, &contents)
+                .with_context(|| format!("Failed to back up the corrupt embeddings file to '{}'", backup.display()))?;
+            return Ok(backup);
+        }
+        // Opening the same broken file twice doesn't pile up copies
+        if fs::read(&backup)? == contents {
+            return Ok(backup);
+        }
+    }
+    unreachable!()
+}
+
 impl EmbeddingStorage for LocalFileVectorStorage {
-    fn store_embedding(&mut self, record: EmbeddingRecord) -> Result<()> {
-        if let Ok(mut guard) = self.data.lock() {
-            guard.insert(record.id.clone(), record);
+    fn store_embeddings(&mut self, records: Vec<EmbeddingRecord>) -> Result<()> {
+        for record in &records {
+            record.validate()?;
         }
-        self.save_data()?;
+        // One save for the whole batch, so it lands entirely or not at all
+        self.modify(|data| {
+            for record in records {
+                data.insert(record.id.clone(), record);
+            }
+            true
+        })?;
         Ok(())
     }
 
     fn get_embedding(&self, id: &str) -> Result<Option<EmbeddingRecord>> {
+        self.refresh()?;
         if let Ok(guard) = self.data.lock() {
             Ok(guard.get(id).cloned())
         } else {
@@ -98,6 +272,7 @@ impl EmbeddingStorage for LocalFileVectorStorage {
     }
 
     fn get_all_embeddings(&self) -> Result<Vec<EmbeddingRecord>> {
+        self.refresh()?;
         if let Ok(guard) = self.data.lock() {
             Ok(guard.values().cloned().collect())
         } else {
@@ -106,16 +281,146 @@ impl EmbeddingStorage for LocalFileVectorStorage {
     }
 
     fn delete_embedding(&mut self, id: &str) -> Result<bool> {
-        let deleted = if let Ok(mut guard) = self.data.lock() {
-            guard.remove(id).is_some()
-        } else {
-            false
-        };
-        
-        if deleted {
-            self.save_data()?;
+        self.modify(|data| data.remove(id).is_some())
+    }
+
+    fn generation(&self) -> Result<u64> {
+        // Picks up records written by other processes
+        self.refresh()?;
+        Ok(self.generation.load(Ordering::SeqCst))
+    }
+
+    fn nearest(&self, query: &[f32], k: usize, current: &ModelFingerprint) -> Result<Vec<(EmbeddingRecord, f32)>> {
+        self.templates.nearest(self, query, k, current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &str) -> EmbeddingRecord {
+        EmbeddingRecord {
+            id: id.to_string(),
+            name: "alice".to_string(),
+            embedding: vec![1., 0.],
+            created_at: chrono::Utc::now(),
+            metadata: HashMap::new(),
+            model: None,
         }
-        
-        Ok(deleted)
+    }
+
+    fn temp_dir() -> Result<PathBuf> {
+        let dir = std::env::temp_dir().join(format!("face_auth_{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    fn file_names(dir: &Path) -> Result<Vec<String>> {
+        let mut names: Vec<String> = fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<_>>()?;
+        names.sort();
+        Ok(names)
+    }
+
+    #[test]
+    fn saves_replace_the_file_without_leaving_temp_files() -> Result<()> {
+        let dir = temp_dir()?;
+        let path = dir.join("embeddings.json").to_string_lossy().into_owned();
+        let result = (|| {
+            let mut storage = LocalFileVectorStorage::new(path.clone(), false)?;
+            storage.store_embedding(record("a"))?;
+            storage.store_embedding(record("b"))?;
+            assert!(storage.delete_embedding("a")?);
+
+            // A batch with one bad record isn't saved at all
+            let mut invalid = record("d");
+            invalid.model = Some(crate::storage::vector_storage::ModelFingerprint {
+                model_id: "test".to_string(),
+                weights_sha256: "abc".to_string(),
+                preprocessing: "none".to_string(),
+                embedding_dim: 3,
+            });
+            assert!(storage.store_embeddings(vec![record("c"), invalid]).is_err());
+            assert!(storage.get_embedding("c")?.is_none());
+
+            assert_eq!(file_names(&dir)?, ["embeddings.json", "embeddings.json.lock"]);
+            let reopened = LocalFileVectorStorage::new(path.clone(), false)?;
+            assert!(reopened.get_embedding("b")?.is_some());
+            assert!(reopened.get_embedding("a")?.is_none());
+            Ok(())
+        })();
+        let _ = fs::remove_dir_all(&dir);
+        result
+    }
+
+    #[test]
+    fn corrupt_files_are_backed_up_and_only_replaced_on_request() -> Result<()> {
+        let dir = temp_dir()?;
+        let path = dir.join("embeddings.json");
+        let result = (|| {
+            // What a crash during a truncating write used to leave behind
+            let truncated = b"{\n  \"a\": {\n    \"id\": \"a\",\n    \"na";
+            fs::write(&path, truncated)?;
+            let path_string = path.to_string_lossy().into_owned();
+
+            let error = LocalFileVectorStorage::new(path_string.clone(), false).err().expect("corrupt file is an error");
+            assert!(format!("{error:#}").contains("embeddings.json.corrupt"));
+            assert_eq!(fs::read(&path)?, truncated);
+            assert!(LocalFileVectorStorage::new(path_string.clone(), false).is_err());
+            assert_eq!(file_names(&dir)?, ["embeddings.json", "embeddings.json.corrupt", "embeddings.json.lock"]);
+
+            let mut storage = LocalFileVectorStorage::new(path_string.clone(), true)?;
+            assert!(storage.get_all_embeddings()?.is_empty());
+            storage.store_embedding(record("b"))?;
+            assert_eq!(fs::read(dir.join("embeddings.json.corrupt"))?, truncated);
+            assert_eq!(LocalFileVectorStorage::new(path_string, false)?.get_all_embeddings()?.len(), 1);
+            Ok(())
+        })();
+        let _ = fs::remove_dir_all(&dir);
+        result
+    }
+
+    #[test]
+    fn concurrent_writers_never_lose_records() -> Result<()> {
+        const WRITERS: usize = 6;
+        const RECORDS: usize = 8;
+        let dir = temp_dir()?;
+        let path = dir.join("embeddings.json").to_string_lossy().into_owned();
+        let result = (|| {
+            // Every writer has its own storage, like separate processes: each
+            // opens before the others write, so its snapshot is out of date
+            let storages = (0..WRITERS)
+                .map(|_| LocalFileVectorStorage::new(path.clone(), false))
+                .collect::<Result<Vec<_>>>()?;
+            let writers: Vec<_> = storages
+                .into_iter()
+                .enumerate()
+                .map(|(writer, mut storage)| {
+                    std::thread::spawn(move || -> Result<()> {
+                        for i in 0..RECORDS {
+                            storage.store_embedding(record(&format!("{writer}-{i}")))?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.join().expect("writer thread panicked")?;
+            }
+
+            let storage = LocalFileVectorStorage::new(path.clone(), false)?;
+            assert_eq!(storage.get_all_embeddings()?.len(), WRITERS * RECORDS);
+
+            // Readers pick up records written through another instance
+            let mut other = LocalFileVectorStorage::new(path.clone(), false)?;
+            assert!(other.delete_embedding("0-0")?);
+            assert!(!other.delete_embedding("0-0")?);
+            assert!(storage.get_embedding("0-0")?.is_none());
+            Ok(())
+        })();
+        let _ = fs::remove_dir_all(&dir);
+        result
     }
 }
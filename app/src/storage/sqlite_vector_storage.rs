@@ -0,0 +1,252 @@
+use super::vector_storage::{EmbeddingRecord, EmbeddingStorage, ModelFingerprint};
+use crate::matching::TemplateCache;
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, Row, Transaction};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Writers wait this long for a lock held by another process before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS embeddings (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        embedding BLOB NOT NULL,
+        created_at TEXT NOT NULL,
+        model TEXT
+    );
+    CREATE INDEX IF NOT EXISTS embeddings_by_name ON embeddings (name);
+    CREATE TABLE IF NOT EXISTS embedding_metadata (
+        embedding_id TEXT NOT NULL REFERENCES embeddings (id) ON DELETE CASCADE,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (embedding_id, key)
+    );
+";
+
+/// Embeddings in a SQLite database: vectors as little-endian f32 BLOBs, metadata
+/// in a side table. WAL mode lets readers run while another process writes.
+pub struct SqliteVectorStorage {
+    connection: Connection,
+    // The last `PRAGMA data_version` seen, which changes when another connection
+    // commits, and the generation derived from it and our own writes
+    generation: Mutex<(i64, u64)>,
+    templates: TemplateCache,
+}
+
+impl SqliteVectorStorage {
+    pub fn new(path: String) -> Result<Self> {
+        if let Some(parent) = Path::new(&path).parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent)?;
+        }
+
+        let connection = Connection::open(&path).with_context(|| format!("Failed to open SQLite database '{path}'"))?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection
+            .execute_batch(SCHEMA)
+            .with_context(|| format!("Failed to create the schema in '{path}'"))?;
+        Ok(SqliteVectorStorage { connection, generation: Mutex::new((0, 0)), templates: TemplateCache::default() })
+    }
+
+    // Our own commits don't change `data_version`
+    fn bump_generation(&self) {
+        if let Ok(mut generation) = self.generation.lock() {
+            generation.1 += 1;
+        }
+    }
+
+    // Loads the records selected by `where_clause` together with their metadata
+    fn query_records(&self, where_clause: &str, params: impl rusqlite::Params) -> Result<Vec<EmbeddingRecord>> {
+        let sql = format!(
+            "SELECT e.id, e.name, e.embedding, e.created_at, e.model, m.key, m.value
+             FROM embeddings e LEFT JOIN embedding_metadata m ON m.embedding_id = e.id
+             {where_clause} ORDER BY e.id"
+        );
+        let mut statement = self.connection.prepare(&sql)?;
+        let mut rows = statement.query(params)?;
+
+        let mut records: Vec<EmbeddingRecord> = Vec::new();
+        while let Some(row) = rows.next()? {
+            let id: String = row.get(0)?;
+            if records.last().is_none_or(|record| record.id != id) {
+                records.push(record_from_row(row)?);
+            }
+            let entry: Option<(String, String)> = match row.get::<_, Option<String>>(5)? {
+                Some(key) => Some((key, row.get(6)?)),
+                None => None,
+            };
+            if let (Some((key, value)), Some(record)) = (entry, records.last_mut()) {
+                record.metadata.insert(key, value);
+            }
+        }
+        Ok(records)
+    }
+}
+
+fn record_from_row(row: &Row) -> Result<EmbeddingRecord> {
+    let id: String = row.get(0)?;
+    let blob: Vec<u8> = row.get(2)?;
+    if !blob.len().is_multiple_of(4) {
+        anyhow::bail!("Embedding '{id}' is stored as {} bytes, not a whole number of f32 values", blob.len());
+    }
+    let created_at: String = row.get(3)?;
+    let model: Option<String> = row.get(4)?;
+
+    Ok(EmbeddingRecord {
+        name: row.get(1)?,
+        embedding: blob.chunks_exact(4).map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect(),
+        created_at: chrono::DateTime::parse_from_rfc3339(&created_at)
+            .with_context(|| format!("Embedding '{id}' has an invalid creation time '{created_at}'"))?
+            .to_utc(),
+        metadata: HashMap::new(),
+        model: model
+            .map(|model| serde_json::from_str::<ModelFingerprint>(&model))
+            .transpose()
+            .with_context(|| format!("Embedding '{id}' has an invalid model fingerprint"))?,
+        id,
+    })
+}
+
+// Inserts or replaces one record and its metadata within `transaction`
+fn insert_record(transaction: &Transaction, record: &EmbeddingRecord) -> Result<()> {
+    record.validate()?;
+    let blob: Vec<u8> = record.embedding.iter().flat_map(|value| value.to_le_bytes()).collect();
+    let model = record.model.as_ref().map(serde_json::to_string).transpose()?;
+
+    transaction.execute(
+        "INSERT INTO embeddings (id, name, embedding, created_at, model) VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT (id) DO UPDATE SET name = ?2, embedding = ?3, created_at = ?4, model = ?5",
+        params![record.id, record.name, blob, record.created_at.to_rfc3339(), model],
+    )?;
+    transaction.execute("DELETE FROM embedding_metadata WHERE embedding_id = ?1", [&record.id])?;
+    let mut insert = transaction.prepare("INSERT INTO embedding_metadata (embedding_id, key, value) VALUES (?1, ?2, ?3)")?;
+    for (key, value) in &record.metadata {
+        insert.execute(params![record.id, key, value])?;
+    }
+    Ok(())
+}
+
+impl EmbeddingStorage for SqliteVectorStorage {
+    fn store_embeddings(&mut self, records: Vec<EmbeddingRecord>) -> Result<()> {
+        // The records and their metadata are replaced together or not at all
+        let transaction = self.connection.transaction()?;
+        for record in &records {
+            insert_record(&transaction, record)?;
+        }
+        transaction.commit()?;
+        self.bump_generation();
+        Ok(())
+    }
+
+    fn get_embedding(&self, id: &str) -> Result<Option<EmbeddingRecord>> {
+        Ok(self.query_records("WHERE e.id = ?1", [id])?.pop())
+    }
+
+    fn get_all_embeddings(&self) -> Result<Vec<EmbeddingRecord>> {
+        self.query_records("", [])
+    }
+
+    fn delete_embedding(&mut self, id: &str) -> Result<bool> {
+        // Metadata goes with the record through the foreign key cascade
+        let deleted = self.connection.execute("DELETE FROM embeddings WHERE id = ?1", [id])?;
+        self.bump_generation();
+        Ok(deleted > 0)
+    }
+
+    fn generation(&self) -> Result<u64> {
+        let data_version: i64 = self.connection.query_row("PRAGMA data_version", [], |row| row.get(0))?;
+        let mut generation = self.generation.lock().map_err(|_| anyhow::anyhow!("Generation lock poisoned"))?;
+        if generation.0 != data_version {
+            *generation = (data_version, generation.1 + 1);
+        }
+        Ok(generation.1)
+    }
+
+    fn nearest(&self, query: &[f32], k: usize, current: &ModelFingerprint) -> Result<Vec<(EmbeddingRecord, f32)>> {
+        self.templates.nearest(self, query, k, current)
+    }
+
+    fn get_user_embeddings(&self, name: &str) -> Result<Vec<EmbeddingRecord>> {
+        self.query_records("WHERE e.name = ?1", [name])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &str, name: &str, metadata: &[(&str, &str)]) -> EmbeddingRecord {
+        EmbeddingRecord {
+            id: id.to_string(),
+            name: name.to_string(),
+            embedding: vec![0.25, -1.5, f32::MIN_POSITIVE],
+            created_at: chrono::Utc::now(),
+            metadata: metadata.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+            model: Some(ModelFingerprint {
+                model_id: "test".to_string(),
+                weights_sha256: "abc".to_string(),
+                preprocessing: "none".to_string(),
+                embedding_dim: 3,
+            }),
+        }
+    }
+
+    #[test]
+    fn records_round_trip_and_replace_their_metadata() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("face_auth_{}.db", uuid::Uuid::new_v4()));
+        let result = (|| {
+            let mut storage = SqliteVectorStorage::new(path.to_string_lossy().into_owned())?;
+            let alice = record("a1", "alice", &[("type", "average"), ("sample_count", "3")]);
+            storage.store_embedding(alice.clone())?;
+            storage.store_embedding(record("a2", "alice", &[]))?;
+            storage.store_embedding(record("b1", "bob", &[("type", "sample")]))?;
+
+            // A second connection sees the committed records
+            let reader = SqliteVectorStorage::new(path.to_string_lossy().into_owned())?;
+            let generation = reader.generation()?;
+            assert_eq!(reader.generation()?, generation);
+            let loaded = reader.get_embedding("a1")?.expect("stored record");
+            assert_eq!(loaded.embedding, alice.embedding);
+            assert_eq!(loaded.metadata, alice.metadata);
+            assert_eq!(loaded.model, alice.model);
+            assert_eq!(loaded.created_at, alice.created_at);
+            assert_eq!(reader.get_user_embeddings("alice")?.len(), 2);
+            assert_eq!(reader.get_all_embeddings()?.len(), 3);
+
+            // Re-storing replaces the metadata instead of merging it, and other
+            // connections notice the change
+            storage.store_embedding(record("a1", "alice", &[("login_threshold", "0.8")]))?;
+            assert_ne!(reader.generation()?, generation);
+            let metadata = reader.get_embedding("a1")?.expect("stored record").metadata;
+            assert_eq!(metadata.len(), 1);
+            assert_eq!(metadata["login_threshold"], "0.8");
+
+            assert!(storage.delete_embedding("b1")?);
+            assert!(!storage.delete_embedding("b1")?);
+            assert!(reader.get_embedding("b1")?.is_none());
+            assert!(reader.get_user_embeddings("bob")?.is_empty());
+
+            let mut invalid = record("c1", "carol", &[]);
+            invalid.embedding.pop();
+            assert!(storage.store_embedding(invalid.clone()).is_err());
+
+            // A batch with one bad record leaves nothing behind
+            let batch = vec![record("c0", "carol", &[("type", "sample")]), invalid];
+            assert!(storage.store_embeddings(batch).is_err());
+            assert!(reader.get_user_embeddings("carol")?.is_empty());
+            Ok(())
+        })();
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+        result
+    }
+}
@@ -1,7 +1,38 @@
+use crate::matching::TemplateMatrix;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use super::encrypted_storage::{EncryptedStorage, KeyRing};
+use super::hnsw_index::HnswParams;
+use super::hnsw_indexed_storage::HnswIndexedStorage;
 use super::local_file_vector_storage::LocalFileVectorStorage;
+use super::sqlite_vector_storage::SqliteVectorStorage;
+
+/// Identifies the model and preprocessing that produced an embedding.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelFingerprint {
+    pub model_id: String,
+    pub weights_sha256: String,
+    pub preprocessing: String,
+    pub embedding_dim: usize,
+}
+
+impl ModelFingerprint {
+    /// Describes why templates produced by `self` can't be compared with `current`, if they can't.
+    pub fn mismatch(&self, current: &ModelFingerprint) -> Option<String> {
+        if self.model_id != current.model_id {
+            Some(format!("model '{}' (current: '{}')", self.model_id, current.model_id))
+        } else if self.weights_sha256 != current.weights_sha256 {
+            Some("different model weights".to_string())
+        } else if self.preprocessing != current.preprocessing {
+            Some(format!("preprocessing '{}' (current: '{}')", self.preprocessing, current.preprocessing))
+        } else if self.embedding_dim != current.embedding_dim {
+            Some(format!("{}-d embeddings (current: {}-d)", self.embedding_dim, current.embedding_dim))
+        } else {
+            None
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EmbeddingRecord {
@@ -10,28 +41,172 @@ pub struct EmbeddingRecord {
     pub embedding: Vec<f32>,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub metadata: HashMap<String, String>,
+    // Missing on templates enrolled before fingerprints were recorded
+    #[serde(default)]
+    pub model: Option<ModelFingerprint>,
+}
+
+impl EmbeddingRecord {
+    /// Returns the reason this template is stale for `current`, or `None` if it is usable.
+    pub fn stale_reason(&self, current: &ModelFingerprint) -> Option<String> {
+        match &self.model {
+            None => Some("no model fingerprint (enrolled by an older version)".to_string()),
+            Some(_) if self.embedding.len() != current.embedding_dim => Some(format!(
+                "{}-d embedding (current: {}-d)", self.embedding.len(), current.embedding_dim
+            )),
+            Some(fingerprint) => fingerprint.mismatch(current),
+        }
+    }
+
+    /// Rejects records whose vector doesn't match the dimension they claim to have.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(fingerprint) = &self.model
+            && fingerprint.embedding_dim != self.embedding.len()
+        {
+            anyhow::bail!(
+                "Embedding '{}' has {} values but its model '{}' produces {}-d embeddings",
+                self.id, self.embedding.len(), fingerprint.model_id, fingerprint.embedding_dim
+            );
+        }
+        Ok(())
+    }
 }
 
 pub trait EmbeddingStorage {
-    fn store_embedding(&mut self, record: EmbeddingRecord) -> Result<()>;
-    #[allow(dead_code)]
+    /// Stores all `records`, or none of them if one can't be stored.
+    fn store_embeddings(&mut self, records: Vec<EmbeddingRecord>) -> Result<()>;
     fn get_embedding(&self, id: &str) -> Result<Option<EmbeddingRecord>>;
     fn get_all_embeddings(&self) -> Result<Vec<EmbeddingRecord>>;
-    #[allow(dead_code)]
     fn delete_embedding(&mut self, id: &str) -> Result<bool>;
+
+    /// Changes whenever the records may have changed, including through another
+    /// process, so data derived from them (the template matrix) can be cached.
+    fn generation(&self) -> Result<u64>;
+
+    fn store_embedding(&mut self, record: EmbeddingRecord) -> Result<()> {
+        self.store_embeddings(vec![record])
+    }
+
+    /// All records enrolled under `name`.
+    fn get_user_embeddings(&self, name: &str) -> Result<Vec<EmbeddingRecord>> {
+        let mut records = self.get_all_embeddings()?;
+        records.retain(|record| record.name == name);
+        Ok(records)
+    }
+
+    /// The `k` records most cosine-similar to `query`, best first, among those
+    /// comparable with `current`: stale records never take the place of a usable
+    /// one. Exhaustive unless the storage is indexed.
+    fn nearest(&self, query: &[f32], k: usize, current: &ModelFingerprint) -> Result<Vec<(EmbeddingRecord, f32)>> {
+        brute_force_nearest(self.get_all_embeddings()?, query, k, current)
+    }
+}
+
+/// Exact top-k search over the `records` comparable with `current`, the
+/// reference for approximate indexes.
+pub fn brute_force_nearest(
+    records: Vec<EmbeddingRecord>,
+    query: &[f32],
+    k: usize,
+    current: &ModelFingerprint,
+) -> Result<Vec<(EmbeddingRecord, f32)>> {
+    let mut records: Vec<Option<EmbeddingRecord>> = records
+        .into_iter()
+        .filter(|record| record.embedding.len() == query.len() && record.stale_reason(current).is_none())
+        .map(Some)
+        .collect();
+    let templates = TemplateMatrix::build(records.iter().flatten(), query.len())?;
+    Ok(templates
+        .ranked(query)?
+        .into_iter()
+        .take(k)
+        .filter_map(|(index, similarity)| records[index].take().map(|record| (record, similarity)))
+        .collect())
 }
 
 pub enum StorageType {
-    LocalFile(String),
+    /// A JSON file; `recover_corrupt` starts over when it can't be parsed.
+    LocalFile { path: String, recover_corrupt: bool },
+    Sqlite(String),
+    /// Another backend whose records are encrypted with `keys`.
+    Encrypted { storage: Box<StorageType>, keys: KeyRing },
+    /// Another backend with an HNSW index persisted at `path`.
+    Indexed { storage: Box<StorageType>, path: String, params: HnswParams },
 }
 
 impl StorageType {
     pub fn create_storage(self) -> Result<Box<dyn EmbeddingStorage>> {
         match self {
-            StorageType::LocalFile(path) => {
-                let storage = LocalFileVectorStorage::new(path)?;
+            StorageType::LocalFile { path, recover_corrupt } => {
+                let storage = LocalFileVectorStorage::new(path, recover_corrupt)?;
+                Ok(Box::new(storage))
+            }
+            StorageType::Sqlite(path) => {
+                let storage = SqliteVectorStorage::new(path)?;
+                Ok(Box::new(storage))
+            }
+            StorageType::Encrypted { storage, keys } => {
+                let storage = EncryptedStorage::new(storage.create_storage()?, keys);
                 Ok(Box::new(storage))
             }
+            StorageType::Indexed { storage, path, params } => {
+                let storage = HnswIndexedStorage::open(storage.create_storage()?, path, params)?;
+                Ok(Box::new(storage))
+            }
+        }
+    }
+
+    /// Re-encrypts every record with the current key. Returns how many were rewritten.
+    pub fn reencrypt(self) -> Result<usize> {
+        match self {
+            StorageType::Encrypted { storage, keys } => EncryptedStorage::new(storage.create_storage()?, keys).reencrypt(),
+            // Re-encrypting changes neither IDs nor vectors, so the index stays valid
+            StorageType::Indexed { storage, .. } => storage.reencrypt(),
+            StorageType::LocalFile { .. } | StorageType::Sqlite(_) => {
+                anyhow::bail!("storage.encryption.enabled is false; there is no key to encrypt with")
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint(weights: &str, dim: usize) -> ModelFingerprint {
+        ModelFingerprint {
+            model_id: "timm/convnext_atto.d2_in1k".to_string(),
+            weights_sha256: weights.to_string(),
+            preprocessing: "resize_to_fill=224".to_string(),
+            embedding_dim: dim,
+        }
+    }
+
+    fn record(embedding: Vec<f32>, model: Option<ModelFingerprint>) -> EmbeddingRecord {
+        EmbeddingRecord {
+            id: "id".to_string(),
+            name: "alice".to_string(),
+            embedding,
+            created_at: chrono::Utc::now(),
+            metadata: HashMap::new(),
+            model,
+        }
+    }
+
+    #[test]
+    fn stale_reason_flags_legacy_and_mismatched_templates() {
+        let current = fingerprint("abc", 3);
+
+        assert!(record(vec![0.0; 3], Some(current.clone())).stale_reason(&current).is_none());
+        assert!(record(vec![0.0; 3], None).stale_reason(&current).is_some());
+        assert!(record(vec![0.0; 3], Some(fingerprint("def", 3))).stale_reason(&current).is_some());
+        assert!(record(vec![0.0; 4], Some(fingerprint("abc", 4))).stale_reason(&current).is_some());
+    }
+
+    #[test]
+    fn validate_rejects_dimension_mismatch() {
+        assert!(record(vec![0.0; 3], Some(fingerprint("abc", 3))).validate().is_ok());
+        assert!(record(vec![0.0; 2], Some(fingerprint("abc", 3))).validate().is_err());
+        assert!(record(vec![0.0; 2], None).validate().is_ok());
+    }
+}
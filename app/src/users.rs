@@ -0,0 +1,259 @@
+use crate::login::validate_threshold;
+use crate::storage::json_state;
+use crate::storage::vector_storage::{EmbeddingRecord, EmbeddingStorage, ModelFingerprint};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+// Metadata key under which older versions copied the override onto every template
+const THRESHOLD_KEY: &str = "login_threshold";
+
+/// Per-user login threshold overrides, one entry per user in a JSON file next
+/// to the embeddings. They are kept apart from the templates, so deleting and
+/// re-registering a user keeps their threshold.
+pub struct ThresholdOverrides {
+    path: PathBuf,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct OverrideFile {
+    // Raw JSON values, so a bad hand-edited value only affects its own user
+    users: BTreeMap<String, serde_json::Value>,
+}
+
+impl ThresholdOverrides {
+    pub fn new(path: PathBuf) -> Self {
+        ThresholdOverrides { path }
+    }
+
+    /// The override of `user_name`. An invalid value is skipped with a warning.
+    pub fn get(&self, user_name: &str) -> Result<Option<f32>> {
+        let file: OverrideFile = json_state::read(&self.path)?;
+        Ok(file.users.get(user_name).and_then(|value| {
+            let threshold = value.as_f64().map(|threshold| threshold as f32).filter(|t| validate_threshold(*t).is_ok());
+            if threshold.is_none() {
+                eprintln!("Warning: Ignoring invalid login threshold {value} for '{user_name}' in '{}'",
+                          self.path.display());
+            }
+            threshold
+        }))
+    }
+
+    /// Sets (or with `None` removes) the override. Returns whether the user had one.
+    fn set(&self, user_name: &str, threshold: Option<f32>) -> Result<bool> {
+        json_state::update(&self.path, |file: &mut OverrideFile| match threshold {
+            // Via the shortest decimal form, so 0.8 isn't written as 0.800000011920929
+            Some(threshold) => {
+                let value = threshold.to_string().parse::<f64>().map_or(serde_json::Value::Null, serde_json::Value::from);
+                file.users.insert(user_name.to_string(), value).is_some()
+            }
+            None => file.users.remove(user_name).is_some(),
+        })
+    }
+}
+
+pub struct UserSummary {
+    pub name: String,
+    pub template_count: usize,
+    pub last_enrolled: chrono::DateTime<chrono::Utc>,
+    pub login_threshold: Option<f32>,
+}
+
+pub fn list_users(storage: &dyn EmbeddingStorage, overrides: &ThresholdOverrides) -> Result<Vec<UserSummary>> {
+    let mut users: BTreeMap<String, Vec<EmbeddingRecord>> = BTreeMap::new();
+    for record in storage.get_all_embeddings()? {
+        users.entry(record.name.clone()).or_default().push(record);
+    }
+
+    users
+        .into_iter()
+        .map(|(name, records)| {
+            Ok(UserSummary {
+                template_count: records.len(),
+                last_enrolled: records.iter().map(|record| record.created_at).max().unwrap_or_default(),
+                login_threshold: threshold_override(overrides, &name, &records)?,
+                name,
+            })
+        })
+        .collect()
+}
+
+pub struct StaleUser {
+    pub name: String,
+    pub stale_count: usize,
+    pub template_count: usize,
+    pub reasons: Vec<String>,
+}
+
+/// Lists users with templates that can't be compared against embeddings from `current`.
+pub fn stale_users(storage: &dyn EmbeddingStorage, current: &ModelFingerprint) -> Result<Vec<StaleUser>> {
+    let mut users: BTreeMap<String, StaleUser> = BTreeMap::new();
+
+    for record in storage.get_all_embeddings()? {
+        let user = users.entry(record.name.clone()).or_insert_with(|| StaleUser {
+            name: record.name.clone(),
+            stale_count: 0,
+            template_count: 0,
+            reasons: Vec::new(),
+        });
+        user.template_count += 1;
+        if let Some(reason) = record.stale_reason(current) {
+            user.stale_count += 1;
+            if !user.reasons.contains(&reason) {
+                user.reasons.push(reason);
+            }
+        }
+    }
+
+    Ok(users.into_values().filter(|user| user.stale_count > 0).collect())
+}
+
+/// Deletes every stored template belonging to `user_name` and returns how many were removed.
+pub fn delete_user(storage: &mut dyn EmbeddingStorage, user_name: &str) -> Result<usize> {
+    let ids: Vec<String> = storage
+        .get_all_embeddings()?
+        .into_iter()
+        .filter(|record| record.name == user_name)
+        .map(|record| record.id)
+        .collect();
+
+    let mut deleted = 0;
+    for id in ids {
+        if storage.delete_embedding(&id)? {
+            deleted += 1;
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// The login threshold override of `user_name`. Overrides set by older versions
+/// on the user's templates (`records`) still apply until the threshold is set again.
+pub fn threshold_override(
+    overrides: &ThresholdOverrides,
+    user_name: &str,
+    records: &[EmbeddingRecord],
+) -> Result<Option<f32>> {
+    Ok(overrides.get(user_name)?.or_else(|| legacy_threshold(records)))
+}
+
+// The override on the most recent template that carries a valid one
+fn legacy_threshold(records: &[EmbeddingRecord]) -> Option<f32> {
+    let mut records: Vec<&EmbeddingRecord> =
+        records.iter().filter(|record| record.metadata.contains_key(THRESHOLD_KEY)).collect();
+    records.sort_by_key(|record| std::cmp::Reverse(record.created_at));
+    records.into_iter().find_map(|record| {
+        let value = &record.metadata[THRESHOLD_KEY];
+        let threshold = value.parse::<f32>().ok().filter(|threshold| validate_threshold(*threshold).is_ok());
+        if threshold.is_none() {
+            eprintln!("Warning: Ignoring invalid {THRESHOLD_KEY} '{value}' on embedding '{}'", record.id);
+        }
+        threshold
+    })
+}
+
+/// Sets (or with `None` removes) the login threshold override of `user_name`.
+/// Returns false if there is no such user to set it for, or no override to remove.
+pub fn set_threshold(
+    storage: &mut dyn EmbeddingStorage,
+    overrides: &ThresholdOverrides,
+    user_name: &str,
+    threshold: Option<f32>,
+) -> Result<bool> {
+    let records = storage.get_user_embeddings(user_name)?;
+    match threshold {
+        Some(threshold) => {
+            validate_threshold(threshold)?;
+            if records.is_empty() {
+                return Ok(false);
+            }
+            overrides.set(user_name, Some(threshold))?;
+        }
+        None => {
+            let had_override = overrides.set(user_name, None)?;
+            if !had_override && legacy_threshold(&records).is_none() {
+                return Ok(false);
+            }
+        }
+    }
+
+    // Drop the copies older versions kept on the templates, which would otherwise
+    // come back as the override once the new one is removed
+    let legacy: Vec<EmbeddingRecord> = records
+        .into_iter()
+        .filter_map(|mut record| record.metadata.remove(THRESHOLD_KEY).map(|_| record))
+        .collect();
+    if !legacy.is_empty() {
+        // Storing under an existing ID replaces the record
+        storage.store_embeddings(legacy)?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::local_file_vector_storage::LocalFileVectorStorage;
+    use std::collections::HashMap;
+    use std::fs;
+
+    fn record(days_ago: i64, threshold: Option<&str>) -> EmbeddingRecord {
+        let mut metadata = HashMap::new();
+        if let Some(threshold) = threshold {
+            metadata.insert(THRESHOLD_KEY.to_string(), threshold.to_string());
+        }
+        EmbeddingRecord {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: "alice".to_string(),
+            embedding: vec![1., 0.],
+            created_at: chrono::Utc::now() - chrono::Duration::days(days_ago),
+            metadata,
+            model: None,
+        }
+    }
+
+    #[test]
+    fn legacy_overrides_use_the_most_recent_valid_template() {
+        assert_eq!(legacy_threshold(&[record(1, None)]), None);
+        assert_eq!(legacy_threshold(&[record(3, Some("0.8")), record(1, Some("0.65")), record(0, None)]), Some(0.65));
+        // Bad values are skipped rather than failing every login
+        assert_eq!(legacy_threshold(&[record(0, Some("high")), record(2, Some("0.75"))]), Some(0.75));
+        assert_eq!(legacy_threshold(&[record(0, Some("2"))]), None);
+    }
+
+    #[test]
+    fn overrides_are_kept_once_per_user_and_outlive_the_templates() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("face_auth_thresholds_{}.json", uuid::Uuid::new_v4()));
+        let embeddings = path.with_extension("embeddings.json");
+        let result = (|| {
+            let overrides = ThresholdOverrides::new(path.clone());
+            let mut storage = LocalFileVectorStorage::new(embeddings.to_string_lossy().into_owned(), false)?;
+            storage.store_embeddings(vec![record(1, Some("0.9")), record(0, None)])?;
+
+            // Setting the override moves it off the templates
+            assert!(set_threshold(&mut storage, &overrides, "alice", Some(0.8))?);
+            assert!(storage.get_all_embeddings()?.iter().all(|record| record.metadata.is_empty()));
+            assert!(!set_threshold(&mut storage, &overrides, "bob", Some(0.8))?);
+            assert!(set_threshold(&mut storage, &overrides, "alice", Some(2.)).is_err());
+
+            // Deleting and re-registering keeps it
+            delete_user(&mut storage, "alice")?;
+            storage.store_embedding(record(0, None))?;
+            assert_eq!(threshold_override(&overrides, "alice", &storage.get_user_embeddings("alice")?)?, Some(0.8));
+
+            // A bad hand-edited value only affects its own user
+            fs::write(&path, r#"{"users": {"alice": "high", "carol": 0.75}}"#)?;
+            assert_eq!(overrides.get("alice")?, None);
+            assert_eq!(overrides.get("carol")?, Some(0.75));
+
+            assert!(set_threshold(&mut storage, &overrides, "carol", None)?);
+            assert!(!set_threshold(&mut storage, &overrides, "carol", None)?);
+            Ok(())
+        })();
+        for file in [path.clone(), path.with_extension("json.lock"), embeddings.clone(), embeddings.with_extension("json.lock")] {
+            let _ = fs::remove_file(file);
+        }
+        result
+    }
+}